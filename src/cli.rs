@@ -0,0 +1,319 @@
+use crate::image::Format;
+use crate::raytracer::{Antialiasing, Shading};
+
+pub const USAGE: &str = "Usage: raytracer [OPTIONS] [SCENE]
+
+Renders SCENE, a scene description file (default scenes/demo.scene).
+
+Options:
+  -W, --width <PIXELS>        Image width (default 512)
+  -H, --height <PIXELS>       Image height (default 512)
+  -a, --antialiasing <N|off>  Render an N by N grid of rays per pixel, or one
+                              ray with off. Overrides the scene file.
+  -r, --reflections <N>       Maximum reflection depth, or bounces when path
+                              tracing. Overrides the scene file.
+  -s, --shading <NAME>        How to light the scene. Overrides the scene file.
+                                whitted    direct light and reflections
+                                path       path traced global illumination
+                                spectral   path tracing one wavelength at a
+                                           time, for dispersion
+                                occlusion  ambient occlusion
+                                normals    surface normals as colors
+                                depth      distance from the camera
+                                objects    a different color for each object
+  -n, --samples <N>           Samples per pixel when path tracing or rendering
+                              ambient occlusion (default the scene file's, or 64)
+  -o, --output <PATH>         Where to save the image (default output/output.png)
+  -f, --format <png|ppm>      Image format (default from the output extension)
+  -j, --threads <N>           Number of render threads (default one per CPU)
+  -c, --camera <mono|anaglyph>
+                              Render normally, or as a red/cyan anaglyph
+      --ipd <METERS>          Eye separation for anaglyph renders (default 0.065)
+  -h, --help                  Print this message
+";
+
+const DEFAULT_SAMPLES: u32 = 64;
+const DEFAULT_OCCLUSION_DISTANCE: f32 = 2.0;
+const DEFAULT_FAR: f32 = 50.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CameraMode {
+    Mono,
+    Anaglyph,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub scene: String,
+    pub width: usize,
+    pub height: usize,
+    pub antialiasing: Option<Antialiasing>,
+    pub reflections: Option<u32>,
+    pub shading: Option<Shading>,
+    pub samples: Option<u32>,
+    pub output: String,
+    pub format: Format,
+    pub threads: Option<usize>,
+    pub camera: CameraMode,
+    pub ipd: f32,
+    pub help: bool,
+}
+
+// Parses the arguments after the program name
+pub fn parse_args<I>(args: I) -> Result<Options, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut options = Options {
+        scene: "scenes/demo.scene".to_owned(),
+        width: 512,
+        height: 512,
+        antialiasing: None,
+        reflections: None,
+        shading: None,
+        samples: None,
+        output: "output/output.png".to_owned(),
+        format: Format::Png,
+        threads: None,
+        camera: CameraMode::Mono,
+        ipd: 0.065,
+        help: false,
+    };
+    let mut format = None;
+    let mut scene = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // Accept both --name value and --name=value
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => {
+                (name.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || -> Result<String, String> {
+            return match inline_value.clone().or_else(|| args.next()) {
+                Some(value) => Ok(value),
+                None => Err(format!("{} needs a value", name)),
+            };
+        };
+
+        match name.as_str() {
+            "-h" | "--help" => options.help = true,
+            "-W" | "--width" => options.width = parse_number(&name, &value()?)?,
+            "-H" | "--height" => options.height = parse_number(&name, &value()?)?,
+            "-a" | "--antialiasing" => {
+                let value = value()?;
+                options.antialiasing = Some(if value == "off" {
+                    Antialiasing::Off
+                } else {
+                    Antialiasing::Grid(parse_number(&name, &value)?)
+                });
+            }
+            "-r" | "--reflections" => options.reflections = Some(parse_number(&name, &value()?)?),
+            "-s" | "--shading" => {
+                options.shading = match value()?.as_str() {
+                    "whitted" => Some(Shading::Whitted),
+                    "path" => Some(Shading::PathTraced(DEFAULT_SAMPLES)),
+                    "spectral" => Some(Shading::Spectral(DEFAULT_SAMPLES)),
+                    "occlusion" => Some(Shading::AmbientOcclusion {
+                        samples: DEFAULT_SAMPLES,
+                        distance: DEFAULT_OCCLUSION_DISTANCE,
+                    }),
+                    "normals" => Some(Shading::Normals),
+                    "depth" => Some(Shading::Depth { far: DEFAULT_FAR }),
+                    "objects" => Some(Shading::Objects),
+                    other => return Err(format!("unknown shading '{}'", other)),
+                }
+            }
+            "-n" | "--samples" => options.samples = Some(parse_number(&name, &value()?)?),
+            "-o" | "--output" => options.output = value()?,
+            "-f" | "--format" => {
+                let value = value()?;
+                format = Some(
+                    Format::from_name(&value)
+                        .ok_or_else(|| format!("unknown image format '{}'", value))?,
+                );
+            }
+            "-j" | "--threads" => options.threads = Some(parse_number(&name, &value()?)?),
+            "-c" | "--camera" => {
+                options.camera = match value()?.as_str() {
+                    "mono" => CameraMode::Mono,
+                    "anaglyph" => CameraMode::Anaglyph,
+                    other => return Err(format!("unknown camera '{}'", other)),
+                }
+            }
+            "--ipd" => {
+                let value = value()?;
+                options.ipd = value
+                    .parse()
+                    .ok()
+                    .filter(|ipd: &f32| ipd.is_finite() && *ipd >= 0.0)
+                    .ok_or_else(|| format!("--ipd needs a distance, not '{}'", value))?;
+            }
+            _ if name.starts_with('-') && name != "-" => {
+                return Err(format!("unknown option '{}'", name));
+            }
+            _ => {
+                if scene.is_some() {
+                    return Err(format!("unexpected argument '{}'", arg));
+                }
+                scene = Some(arg);
+            }
+        }
+    }
+
+    if let Some(scene) = scene {
+        options.scene = scene;
+    }
+    options.format = format
+        .or_else(|| Format::from_path(&options.output))
+        .unwrap_or(Format::Png);
+    return Ok(options);
+}
+
+impl Options {
+    // The scene file's shading with any command line overrides applied
+    pub fn shading(&self, scene_shading: Shading) -> Shading {
+        // Switching to another sampled mode keeps the scene's sample count
+        let scene_samples = match scene_shading {
+            Shading::PathTraced(samples) | Shading::Spectral(samples) => Some(samples),
+            Shading::AmbientOcclusion { samples, .. } => Some(samples),
+            _ => None,
+        };
+        let shading = self.shading.unwrap_or(scene_shading);
+        return match (shading, self.samples.or(scene_samples)) {
+            (Shading::PathTraced(_), Some(samples)) => Shading::PathTraced(samples),
+            (Shading::Spectral(_), Some(samples)) => Shading::Spectral(samples),
+            (Shading::AmbientOcclusion { distance, .. }, Some(samples)) => {
+                Shading::AmbientOcclusion { samples, distance }
+            }
+            _ => shading,
+        };
+    }
+}
+
+// Positive whole numbers only, since none of the options make sense at zero
+fn parse_number<T: std::str::FromStr + PartialOrd + Default>(
+    name: &str,
+    value: &str,
+) -> Result<T, String> {
+    return match value.parse::<T>() {
+        Ok(number) if number > T::default() => Ok(number),
+        _ => Err(format!(
+            "{} needs a positive whole number, not '{}'",
+            name, value
+        )),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        return parse_args(args.iter().map(|arg| arg.to_string()));
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.scene, "scenes/demo.scene");
+        assert_eq!((options.width, options.height), (512, 512));
+        assert_eq!(options.antialiasing, None);
+        assert_eq!(options.format, Format::Png);
+        assert_eq!(options.camera, CameraMode::Mono);
+    }
+
+    #[test]
+    fn all_options() {
+        let options = parse(&[
+            "-W",
+            "1920",
+            "--height=1080",
+            "-a",
+            "4",
+            "--reflections",
+            "3",
+            "-o",
+            "render.ppm",
+            "-j",
+            "16",
+            "--camera",
+            "anaglyph",
+            "--ipd",
+            "0.1",
+            "room.scene",
+        ])
+        .unwrap();
+        assert_eq!(options.scene, "room.scene");
+        assert_eq!((options.width, options.height), (1920, 1080));
+        assert_eq!(options.antialiasing, Some(Antialiasing::Grid(4)));
+        assert_eq!(options.reflections, Some(3));
+        assert_eq!(options.output, "render.ppm");
+        assert_eq!(options.format, Format::Ppm);
+        assert_eq!(options.threads, Some(16));
+        assert_eq!(options.camera, CameraMode::Anaglyph);
+        assert_eq!(options.ipd, 0.1);
+
+        let options = parse(&["-a", "off", "-o", "render.ppm", "-f", "png"]).unwrap();
+        assert_eq!(options.antialiasing, Some(Antialiasing::Off));
+        assert_eq!(options.format, Format::Png);
+    }
+
+    #[test]
+    fn shading() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.shading(Shading::Whitted), Shading::Whitted);
+        assert_eq!(
+            options.shading(Shading::PathTraced(8)),
+            Shading::PathTraced(8)
+        );
+
+        let options = parse(&["--shading", "path"]).unwrap();
+        assert_eq!(
+            options.shading(Shading::Whitted),
+            Shading::PathTraced(DEFAULT_SAMPLES)
+        );
+
+        let options = parse(&["-n", "256"]).unwrap();
+        assert_eq!(options.shading(Shading::Whitted), Shading::Whitted);
+        assert_eq!(
+            options.shading(Shading::PathTraced(8)),
+            Shading::PathTraced(256)
+        );
+
+        let options = parse(&["-s", "whitted", "-n", "256"]).unwrap();
+        assert_eq!(options.shading(Shading::PathTraced(8)), Shading::Whitted);
+        let options = parse(&["-s", "occlusion", "-n", "4"]).unwrap();
+        assert_eq!(
+            options.shading(Shading::Whitted),
+            Shading::AmbientOcclusion {
+                samples: 4,
+                distance: DEFAULT_OCCLUSION_DISTANCE
+            }
+        );
+        let options = parse(&["-s", "spectral"]).unwrap();
+        assert_eq!(
+            options.shading(Shading::PathTraced(8)),
+            Shading::Spectral(8)
+        );
+        let options = parse(&["-s", "spectral", "-n", "16"]).unwrap();
+        assert_eq!(options.shading(Shading::Whitted), Shading::Spectral(16));
+        assert!(parse(&["--shading", "radiosity"]).is_err());
+        assert!(parse(&["--samples", "0"]).is_err());
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert!(parse(&["--width"]).is_err());
+        assert!(parse(&["--width", "0"]).is_err());
+        assert!(parse(&["--width", "-5"]).is_err());
+        assert!(parse(&["--format", "gif"]).is_err());
+        assert!(parse(&["--camera", "fisheye"]).is_err());
+        assert!(parse(&["--ipd", "-0.065"]).is_err());
+        assert!(parse(&["--ipd", "NaN"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+        assert!(parse(&["a.scene", "b.scene"]).is_err());
+    }
+}
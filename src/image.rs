@@ -1,5 +1,6 @@
 use std::fs::File;
-use std::io::BufWriter;
+use std::io;
+use std::io::{BufWriter, Write};
 use std::ops::{Add, Mul};
 use std::path::Path;
 
@@ -21,7 +22,7 @@ impl Color {
             a: a as f32 / 255.0,
         };
     }
-    pub fn to_hex(&self) -> String {
+    pub fn to_hex(self) -> String {
         return format!(
             "#{:02x}{:02x}{:02x}",
             (self.r * 255.0) as u8,
@@ -48,7 +49,7 @@ impl Color {
         };
     }
 
-    pub fn to_gray(&self) -> Color {
+    pub fn to_gray(self) -> Color {
         let avg = (self.r + self.g + self.b) / 3.0;
         return Color {
             r: avg,
@@ -115,6 +116,16 @@ impl Image {
         };
     }
 
+    // pixels holds 8 bit RGBA, row by row from the top
+    pub fn from_rgba(width: usize, height: usize, pixels: Vec<u8>) -> Image {
+        assert_eq!(pixels.len(), width * height * 4);
+        return Image {
+            width,
+            height,
+            pixels,
+        };
+    }
+
     pub fn new_like(image: &Image) -> Image {
         return Image::new(image.width, image.height);
     }
@@ -151,17 +162,28 @@ impl Image {
 
     pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
         let base = x * 4 + y * self.width * 4;
-        self.pixels[base + 0] = (color.r * 255.0) as u8;
+        self.pixels[base] = (color.r * 255.0) as u8;
         self.pixels[base + 1] = (color.g * 255.0) as u8;
         self.pixels[base + 2] = (color.b * 255.0) as u8;
         self.pixels[base + 3] = (color.a * 255.0) as u8;
         //        return self;
     }
 
-    pub fn save(&self, filename: &String) {
+    pub fn save(&self, filename: &String) -> io::Result<()> {
+        return self.save_as(filename, Format::from_path(filename).unwrap_or(Format::Png));
+    }
+
+    pub fn save_as(&self, filename: &String, format: Format) -> io::Result<()> {
         let path = Path::new(filename);
-        let file = File::create(path).unwrap();
-        let ref mut w = BufWriter::new(file);
+        let file = File::create(path)?;
+        let w = &mut BufWriter::new(file);
+        return match format {
+            Format::Png => self.write_png(w),
+            Format::Ppm => self.write_ppm(w),
+        };
+    }
+
+    fn write_png(&self, w: &mut BufWriter<File>) -> io::Result<()> {
         let mut encoder = png::Encoder::new(
             w,
             self.width.try_into().unwrap(),
@@ -169,9 +191,55 @@ impl Image {
         );
         encoder.set_color(png::ColorType::Rgba);
         encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
+        let mut writer = encoder.write_header()?;
+
+        writer.write_image_data(&self.pixels)?; // Save
+        return Ok(());
+    }
 
-        writer.write_image_data(&self.pixels).unwrap(); // Save
+    // Binary PPM. It has no alpha channel, so that gets dropped.
+    fn write_ppm(&self, w: &mut BufWriter<File>) -> io::Result<()> {
+        write!(w, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for pixel in self.pixels.chunks_exact(4) {
+            w.write_all(&pixel[..3])?;
+        }
+        return w.flush();
+    }
+}
+
+// An image with high dynamic range colors, which can be much brighter than
+// white. Rows go from the top down.
+pub struct HdrImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+}
+
+impl HdrImage {
+    pub fn get_pixel(&self, x: usize, y: usize) -> Color {
+        return self.pixels[x + y * self.width];
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    Ppm,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        return match name.to_ascii_lowercase().as_str() {
+            "png" => Some(Format::Png),
+            "ppm" => Some(Format::Ppm),
+            _ => None,
+        };
+    }
+
+    // Guesses the format from a file extension
+    pub fn from_path(filename: &str) -> Option<Format> {
+        let extension = Path::new(filename).extension()?.to_str()?;
+        return Format::from_name(extension);
     }
 }
 
@@ -187,6 +255,14 @@ mod tests {
         assert_eq!(c.to_hex(), "#000000");
     }
 
+    #[test]
+    fn formats() {
+        assert_eq!(Format::from_path("out/render.PPM"), Some(Format::Ppm));
+        assert_eq!(Format::from_path("render.png"), Some(Format::Png));
+        assert_eq!(Format::from_path("render"), None);
+        assert_eq!(Format::from_name("jpeg"), None);
+    }
+
     #[test]
     fn output_colors() {
         let size = 512;
@@ -197,6 +273,6 @@ mod tests {
                 img.set_pixel(x, y, c);
             }
         }
-        img.save(&"graident.png".to_owned());
+        img.save(&"graident.png".to_owned()).unwrap();
     }
 }
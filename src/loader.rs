@@ -0,0 +1,44 @@
+use std::fmt;
+use std::io;
+
+pub mod hdr;
+pub mod ies;
+pub mod obj;
+pub mod png;
+pub mod scene;
+pub mod stl;
+
+// Everything that can go wrong reading a model or scene from disk
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    // A problem with the contents of a text file, on a 1-based line number
+    Parse { line: usize, message: String },
+    // A problem with a binary file, or one that isn't tied to a line
+    Format(String),
+}
+
+impl LoadError {
+    pub fn parse(line: usize, message: impl Into<String>) -> LoadError {
+        return LoadError::Parse {
+            line,
+            message: message.into(),
+        };
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            LoadError::Io(error) => write!(formatter, "{}", error),
+            LoadError::Parse { line, message } => write!(formatter, "line {}: {}", line, message),
+            LoadError::Format(message) => write!(formatter, "{}", message),
+        };
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> LoadError {
+        return LoadError::Io(error);
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs;
+use std::path::Path;
+
+use crate::image::{Color, HdrImage};
+use crate::loader::LoadError;
+
+// Loads a Radiance RGBE image, the usual format for HDR environment maps.
+// Only the standard -Y H +X W orientation is supported.
+pub fn load_hdr(path: &Path) -> Result<HdrImage, LoadError> {
+    let bytes = fs::read(path)?;
+    return parse_hdr(&bytes);
+}
+
+fn format_error(message: impl Into<String>) -> LoadError {
+    return LoadError::Format(message.into());
+}
+
+// Each pixel shares one exponent between its three mantissas
+fn rgbe_to_color(rgbe: [u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::new(0, 0, 0, 255);
+    }
+    let scale = f32::powi(2.0, rgbe[3] as i32 - (128 + 8));
+    return Color {
+        r: (rgbe[0] as f32 + 0.5) * scale,
+        g: (rgbe[1] as f32 + 0.5) * scale,
+        b: (rgbe[2] as f32 + 0.5) * scale,
+        a: 1.0,
+    };
+}
+
+pub fn parse_hdr(bytes: &[u8]) -> Result<HdrImage, LoadError> {
+    // Text header lines, ending with a blank line and then the resolution
+    let mut position = 0;
+    let mut next_line = || -> Result<&str, LoadError> {
+        let length = bytes[position..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .ok_or_else(|| format_error("header never ends"))?;
+        let line = std::str::from_utf8(&bytes[position..position + length])
+            .map_err(|_| format_error("header isn't text"))?;
+        position += length + 1;
+        return Ok(line.trim_end_matches('\r'));
+    };
+    if !next_line()?.starts_with("#?") {
+        return Err(format_error("not a Radiance HDR file"));
+    }
+    loop {
+        let line = next_line()?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(format_error(format!("unsupported format {}", format)));
+            }
+        }
+    }
+    let resolution = next_line()?;
+    let (height, width) = match resolution.split_whitespace().collect::<Vec<&str>>()[..] {
+        ["-Y", height, "+X", width] => (height.parse::<usize>(), width.parse::<usize>()),
+        _ => {
+            return Err(format_error(format!(
+                "unsupported resolution '{}'",
+                resolution
+            )))
+        }
+    };
+    let (height, width) = match (height, width) {
+        (Ok(height), Ok(width)) if height > 0 && width > 0 => (height, width),
+        _ => return Err(format_error(format!("bad resolution '{}'", resolution))),
+    };
+
+    // The header can claim any size, so check it against the data before
+    // allocating. Every scanline starts with at least one four byte pixel.
+    let remaining = bytes.len() - position;
+    if height > remaining / 4 {
+        return Err(format_error(format!(
+            "header promises {} scanlines but there are only {} bytes of pixel data",
+            height, remaining
+        )));
+    }
+    let too_large = || format_error(format!("resolution '{}' is too large", resolution));
+    let size = width.checked_mul(height).ok_or_else(too_large)?;
+    let mut pixels = Vec::new();
+    pixels.try_reserve_exact(size).map_err(|_| too_large())?;
+    let mut scanline = Vec::new();
+    scanline.try_reserve_exact(width).map_err(|_| too_large())?;
+    scanline.resize(width, [0u8; 4]);
+
+    let mut data = Data { bytes, position };
+    for _ in 0..height {
+        data.scanline(&mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_color(rgbe)));
+    }
+    return Ok(HdrImage {
+        width,
+        height,
+        pixels,
+    });
+}
+
+// The pixel data after the header
+struct Data<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Data<'_> {
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or_else(|| format_error("pixel data ends early"))?;
+        self.position += 1;
+        return Ok(byte);
+    }
+
+    fn pixel(&mut self) -> Result<[u8; 4], LoadError> {
+        return Ok([self.byte()?, self.byte()?, self.byte()?, self.byte()?]);
+    }
+
+    // Newer files run length encode each channel of a scanline separately,
+    // marked by a pixel of 2 2 and the width. Anything else is either flat
+    // pixels or the old encoding, where 1 1 1 n repeats the last pixel.
+    fn scanline(&mut self, scanline: &mut [[u8; 4]]) -> Result<(), LoadError> {
+        let width = scanline.len();
+        let marker = self.bytes.get(self.position..self.position + 4);
+        let encoded = (8..0x8000).contains(&width)
+            && marker.is_some_and(|marker| {
+                marker[0] == 2
+                    && marker[1] == 2
+                    && marker[2] & 0x80 == 0
+                    && ((marker[2] as usize) << 8 | marker[3] as usize) == width
+            });
+        if encoded {
+            self.position += 4;
+            for channel in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let count = self.byte()? as usize;
+                    let (count, run) = if count > 128 {
+                        (count - 128, true)
+                    } else {
+                        (count, false)
+                    };
+                    if count == 0 || x + count > width {
+                        return Err(format_error("bad run length in pixel data"));
+                    }
+                    let value = if run { Some(self.byte()?) } else { None };
+                    for pixel in &mut scanline[x..x + count] {
+                        pixel[channel] = match value {
+                            Some(value) => value,
+                            None => self.byte()?,
+                        };
+                    }
+                    x += count;
+                }
+            }
+            return Ok(());
+        }
+
+        let mut x = 0;
+        let mut shift = 0;
+        while x < width {
+            let pixel = self.pixel()?;
+            if pixel[0] == 1 && pixel[1] == 1 && pixel[2] == 1 {
+                if x == 0 {
+                    return Err(format_error("repeat with no pixel before it"));
+                }
+                let count = (pixel[3] as usize) << shift;
+                if x + count > width {
+                    return Err(format_error("bad run length in pixel data"));
+                }
+                let last = scanline[x - 1];
+                scanline[x..x + count].fill(last);
+                x += count;
+                shift += 8;
+            } else {
+                scanline[x] = pixel;
+                x += 1;
+                shift = 0;
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(width: usize, height: usize) -> Vec<u8> {
+        return format!(
+            "#?RADIANCE\n# made for a test\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            height, width
+        )
+        .into_bytes();
+    }
+
+    #[test]
+    fn flat_pixels() {
+        let mut bytes = header(2, 2);
+        // 1 2 0.5 and black, then an old style repeat of 0.5 0.5 0.5
+        bytes.extend([
+            128, 255, 64, 129, 0, 0, 0, 0, 128, 128, 128, 128, 1, 1, 1, 1,
+        ]);
+        let image = parse_hdr(&bytes).unwrap();
+        assert_eq!((image.width, image.height), (2, 2));
+        let pixel = image.get_pixel(0, 0);
+        assert!((pixel.r - 1.0).abs() < 0.01 && (pixel.g - 2.0).abs() < 0.01);
+        assert!((pixel.b - 0.5).abs() < 0.01);
+        assert_eq!(image.get_pixel(1, 0).g, 0.0);
+        assert_eq!(image.get_pixel(1, 1).r, image.get_pixel(0, 1).r);
+    }
+
+    #[test]
+    fn run_length_encoded() {
+        let mut bytes = header(10, 1);
+        bytes.extend([2, 2, 0, 10]);
+        // Red is one run, green is ten literal values, and blue and the
+        // exponent are a run and a literal
+        bytes.extend([128 + 10, 128]);
+        bytes.push(10);
+        bytes.extend(0..10u8);
+        bytes.extend([128 + 9, 64, 1, 32]);
+        bytes.extend([128 + 10, 129]);
+        let image = parse_hdr(&bytes).unwrap();
+        assert!((image.get_pixel(3, 0).r - 1.0).abs() < 0.01);
+        assert!(image.get_pixel(3, 0).g > image.get_pixel(2, 0).g);
+        assert!((image.get_pixel(9, 0).b - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(parse_hdr(b"P6\n2 2\n255\n").is_err());
+        let mut bytes = header(2, 2);
+        bytes.extend([128, 128, 128, 129]);
+        assert!(parse_hdr(&bytes).is_err());
+        let text = String::from_utf8(header(2, 2)).unwrap();
+        assert!(parse_hdr(text.replace("-Y 2", "+Y 2").as_bytes()).is_err());
+        assert!(parse_hdr(text.replace("rgbe", "xyze").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn huge_resolution() {
+        // More scanlines than there is data for
+        let mut bytes = header(1, 1 << 40);
+        bytes.extend([128, 128, 128, 129]);
+        assert!(parse_hdr(&bytes).is_err());
+        // Too many pixels to count or allocate
+        let mut bytes = header(usize::MAX, 1);
+        bytes.extend([128, 128, 128, 129]);
+        assert!(parse_hdr(&bytes).is_err());
+        let mut bytes = header(1 << 60, 2);
+        bytes.extend([128, 128, 128, 129, 128, 128, 128, 129]);
+        assert!(parse_hdr(&bytes).is_err());
+    }
+}
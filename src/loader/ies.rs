@@ -0,0 +1,195 @@
+use std::fs;
+use std::path::Path;
+
+use crate::loader::LoadError;
+use crate::raytracer::light::IesProfile;
+
+// Loads an IESNA LM-63 photometric file. Only type C photometry, the kind
+// almost every fixture uses, is supported.
+pub fn load_ies(path: &Path) -> Result<IesProfile, LoadError> {
+    let text = fs::read_to_string(path)?;
+    return parse_ies(&text);
+}
+
+// The numbers after the keyword lines, which can be split over lines and
+// separated by spaces or commas however the file likes
+struct Numbers<'a> {
+    words: Vec<(usize, &'a str)>,
+    next: usize,
+}
+
+impl Numbers<'_> {
+    fn number(&mut self, what: &str) -> Result<f32, LoadError> {
+        let (line, word) = match self.words.get(self.next) {
+            Some(&entry) => entry,
+            None => {
+                let line = self.words.last().map_or(1, |&(line, _)| line);
+                return Err(LoadError::parse(line, format!("expected {}", what)));
+            }
+        };
+        self.next += 1;
+        return word
+            .parse::<f32>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .ok_or_else(|| LoadError::parse(line, format!("bad number '{}' for {}", word, what)));
+    }
+
+    fn count(&mut self, what: &str) -> Result<usize, LoadError> {
+        let line = self.words.get(self.next).map_or(1, |&(line, _)| line);
+        let value = self.number(what)?;
+        if value < 1.0 || value.fract() != 0.0 {
+            return Err(LoadError::parse(
+                line,
+                format!("bad count {} for {}", value, what),
+            ));
+        }
+        return Ok(value as usize);
+    }
+
+    fn angles(&mut self, count: usize, what: &str) -> Result<Vec<f32>, LoadError> {
+        let line = self.words.get(self.next).map_or(1, |&(line, _)| line);
+        let angles = (0..count)
+            .map(|_| self.number(what))
+            .collect::<Result<Vec<f32>, LoadError>>()?;
+        if angles.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(LoadError::parse(line, format!("{} must increase", what)));
+        }
+        return Ok(angles);
+    }
+}
+
+pub fn parse_ies(text: &str) -> Result<IesProfile, LoadError> {
+    // Keyword lines like [MANUFAC] come first, up to the TILT line
+    let mut lines = text.lines().enumerate();
+    let (tilt_line, tilt) = loop {
+        match lines.next() {
+            Some((index, line)) => {
+                if let Some(tilt) = line.trim().strip_prefix("TILT=") {
+                    break (index + 1, tilt.trim());
+                }
+            }
+            None => return Err(LoadError::Format("no TILT line".to_owned())),
+        }
+    };
+    let mut numbers = Numbers {
+        words: lines
+            .flat_map(|(index, line)| {
+                line.split(|c: char| c.is_whitespace() || c == ',')
+                    .filter(|word| !word.is_empty())
+                    .map(move |word| (index + 1, word))
+            })
+            .collect(),
+        next: 0,
+    };
+
+    match tilt {
+        "NONE" => {}
+        // Lamp to luminaire geometry, then pairs of angles and multipliers.
+        // Tilting the lamp isn't supported, so they are skipped.
+        "INCLUDE" => {
+            numbers.number("lamp to luminaire geometry")?;
+            let pairs = numbers.count("number of tilt angles")?;
+            for _ in 0..pairs * 2 {
+                numbers.number("tilt angles and multipliers")?;
+            }
+        }
+        _ => return Err(LoadError::parse(tilt_line, "TILT files aren't supported")),
+    }
+
+    numbers.number("number of lamps")?;
+    numbers.number("lumens per lamp")?;
+    let multiplier = numbers.number("candela multiplier")?;
+    let vertical_count = numbers.count("number of vertical angles")?;
+    let horizontal_count = numbers.count("number of horizontal angles")?;
+    let photometric_type = numbers.number("photometric type")?;
+    if photometric_type != 1.0 {
+        return Err(LoadError::Format(format!(
+            "only type C photometry is supported, not type {}",
+            photometric_type
+        )));
+    }
+    // Units, the luminous opening's size, ballast factor, a reserved
+    // number and input watts
+    for what in [
+        "units type",
+        "width",
+        "length",
+        "height",
+        "ballast factor",
+        "ballast lamp factor",
+        "input watts",
+    ] {
+        numbers.number(what)?;
+    }
+
+    let vertical_angles = numbers.angles(vertical_count, "vertical angles")?;
+    let horizontal_angles = numbers.angles(horizontal_count, "horizontal angles")?;
+    let mut candela = Vec::with_capacity(horizontal_count);
+    for _ in 0..horizontal_count {
+        let row = (0..vertical_count)
+            .map(|_| Ok(numbers.number("candela value")? * multiplier))
+            .collect::<Result<Vec<f32>, LoadError>>()?;
+        candela.push(row);
+    }
+    return Ok(IesProfile {
+        vertical_angles,
+        horizontal_angles,
+        candela,
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IES: &str = "IESNA:LM-63-2002
+[TEST] A downlight
+[MANUFAC] Nobody
+TILT=NONE
+1 1000 2 3 1 1 2 0.1 0.1 0.0
+1.0 1.0 10
+0 45 90
+0
+200, 100,
+0
+";
+
+    #[test]
+    fn downlight() {
+        let profile = parse_ies(IES).unwrap();
+        assert_eq!(profile.vertical_angles, vec![0.0, 45.0, 90.0]);
+        assert_eq!(profile.horizontal_angles, vec![0.0]);
+        // Values are scaled by the candela multiplier
+        assert_eq!(profile.candela, vec![vec![400.0, 200.0, 0.0]]);
+        assert_eq!(profile.candela(22.5, 123.0), 300.0);
+    }
+
+    #[test]
+    fn tilt() {
+        let included = IES.replace("TILT=NONE\n", "TILT=INCLUDE\n1\n2\n0 90\n1.0 0.5\n");
+        assert_eq!(parse_ies(&included).unwrap().candela[0][0], 400.0);
+
+        let file = IES.replace("TILT=NONE", "TILT=lamp.tlt");
+        assert!(matches!(
+            parse_ies(&file),
+            Err(LoadError::Parse { line: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(matches!(
+            parse_ies("IESNA:LM-63-2002\n1 2 3\n"),
+            Err(LoadError::Format(_))
+        ));
+        // Missing the last candela value
+        assert!(matches!(
+            parse_ies(IES.trim_end().strip_suffix('0').unwrap()),
+            Err(LoadError::Parse { line: 9, .. })
+        ));
+        // Type A photometry
+        assert!(parse_ies(&IES.replace("1 1 2 0.1", "1 3 2 0.1")).is_err());
+        assert!(parse_ies(&IES.replace("0 45 90", "0 90 45")).is_err());
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::image::Color;
+use crate::loader::png::load_png;
+use crate::loader::LoadError;
+use crate::matrix::vector::{Point3D, Vector2D, Vector3D};
+use crate::raytracer::geometry::material::Material;
+use crate::raytracer::geometry::mesh::TriangleMesh;
+use crate::raytracer::texture::{ImageTexture, Texture};
+
+// A material from an MTL library, before it is turned into a Material
+#[derive(Clone)]
+pub struct MtlMaterial {
+    pub name: String,
+    pub diffuse_color: [f32; 3],     // Kd
+    pub specular_color: [f32; 3],    // Ks
+    pub specular_exponent: f32,      // Ns
+    pub dissolve: f32,               // d, or 1 - Tr
+    pub optical_density: f32,        // Ni, the index of refraction
+    pub emissive_color: [f32; 3],    // Ke
+    pub illumination: u32,           // illum
+    pub diffuse_map: Option<String>, // map_Kd, relative to the MTL file
+}
+
+impl MtlMaterial {
+    fn new(name: &str) -> MtlMaterial {
+        return MtlMaterial {
+            name: name.to_owned(),
+            diffuse_color: [1.0, 1.0, 1.0],
+            specular_color: [0.0, 0.0, 0.0],
+            specular_exponent: 0.0,
+            dissolve: 1.0,
+            optical_density: 1.0,
+            emissive_color: [0.0, 0.0, 0.0],
+            illumination: 2,
+            diffuse_map: None,
+        };
+    }
+
+    pub fn to_material(&self) -> Material {
+        let [r, g, b] = self.diffuse_color;
+        let specular =
+            (self.specular_color[0] + self.specular_color[1] + self.specular_color[2]) / 3.0;
+        // Illumination models 3 and up turn on ray traced reflections
+        let reflectivity = if self.illumination >= 3 {
+            specular
+        } else {
+            0.0
+        };
+        let mut material = Material::new(
+            Color {
+                r,
+                g,
+                b,
+                a: self.dissolve,
+            },
+            1.0,
+            specular,
+            self.specular_exponent.round() as i32,
+            reflectivity,
+        );
+        material.ior = self.optical_density;
+        let [r, g, b] = self.emissive_color;
+        material.emission = Color { r, g, b, a: 1.0 };
+        return material;
+    }
+}
+
+// The meshes in an OBJ file, and whatever had to be left out of them
+pub struct ObjModel {
+    pub meshes: Vec<TriangleMesh>,
+    // Like a usemtl naming a material no library defines
+    pub warnings: Vec<String>,
+}
+
+// Loads an OBJ file and the MTL libraries it references. Every group or
+// object becomes its own mesh, split again wherever the material changes.
+// Faces before any usemtl, or using an unknown material, get default_material.
+pub fn load_obj(path: &Path, default_material: Arc<Material>) -> Result<ObjModel, LoadError> {
+    let text = fs::read_to_string(path)?;
+    let directory = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut read_mtl = |name: &str| -> Result<String, LoadError> {
+        let mtl_path: PathBuf = directory.join(name);
+        return fs::read_to_string(&mtl_path)
+            .map_err(|error| LoadError::Format(format!("{}: {}", mtl_path.display(), error)));
+    };
+    let mut load_texture = |name: &str| -> Result<Arc<dyn Texture>, LoadError> {
+        let image = load_png(&directory.join(name))?;
+        return Ok(Arc::new(ImageTexture::new(image)));
+    };
+    return parse_obj(&text, default_material, &mut read_mtl, &mut load_texture);
+}
+
+pub fn parse_mtl(text: &str) -> Result<Vec<MtlMaterial>, LoadError> {
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = strip_comment(line);
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            let name = rest_of_line(line, keyword)
+                .ok_or_else(|| LoadError::parse(line_number, "newmtl needs a name"))?;
+            materials.push(MtlMaterial::new(name));
+            continue;
+        }
+        let material = match materials.last_mut() {
+            Some(material) => material,
+            None => {
+                return Err(LoadError::parse(
+                    line_number,
+                    format!("{} before newmtl", keyword),
+                ))
+            }
+        };
+        match keyword {
+            "Kd" => material.diffuse_color = parse_color(&mut words, line_number)?,
+            "Ks" => material.specular_color = parse_color(&mut words, line_number)?,
+            "Ns" => material.specular_exponent = parse_numbers::<1>(&mut words, line_number)?[0],
+            "d" => material.dissolve = parse_numbers::<1>(&mut words, line_number)?[0],
+            "Ni" => material.optical_density = parse_numbers::<1>(&mut words, line_number)?[0],
+            "Ke" => material.emissive_color = parse_color(&mut words, line_number)?,
+            "Tr" => material.dissolve = 1.0 - parse_numbers::<1>(&mut words, line_number)?[0],
+            "illum" => {
+                material.illumination = words
+                    .next()
+                    .and_then(|word| word.parse().ok())
+                    .ok_or_else(|| LoadError::parse(line_number, "illum needs a whole number"))?
+            }
+            // Texture options come before the file name, which is always last
+            "map_Kd" => {
+                let name = line
+                    .split_whitespace()
+                    .last()
+                    .filter(|name| *name != keyword);
+                material.diffuse_map = Some(
+                    name.ok_or_else(|| LoadError::parse(line_number, "map_Kd needs a file"))?
+                        .to_owned(),
+                );
+            }
+            // Everything else (Ka, other maps...) has nowhere to go yet
+            _ => {}
+        }
+    }
+    return Ok(materials);
+}
+
+// Faces gathered for one mesh. Vertices are shared when a face corner has the
+// same position, texture and normal index as an earlier one.
+struct MeshBuilder {
+    material: Arc<Material>,
+    corners: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+    positions: Vec<Point3D>,
+    normals: Vec<Vector3D>,
+    uvs: Vec<Vector2D>,
+    faces: Vec<[u32; 3]>,
+    missing_normals: bool,
+    has_uvs: bool,
+}
+
+impl MeshBuilder {
+    fn new(material: Arc<Material>) -> MeshBuilder {
+        return MeshBuilder {
+            material,
+            corners: HashMap::new(),
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            faces: Vec::new(),
+            missing_normals: false,
+            has_uvs: false,
+        };
+    }
+
+    fn corner(&mut self, corner: (usize, Option<usize>, Option<usize>), obj: &ObjBuffers) -> u32 {
+        if let Some(&index) = self.corners.get(&corner) {
+            return index;
+        }
+        let (position, uv, normal) = corner;
+        let index = self.positions.len() as u32;
+        self.positions.push(obj.positions[position]);
+        self.uvs.push(uv.map_or(Vector2D::zero(), |uv| obj.uvs[uv]));
+        self.normals
+            .push(normal.map_or(Vector3D::zero(), |normal| obj.normals[normal]));
+        self.has_uvs |= uv.is_some();
+        self.missing_normals |= normal.is_none();
+        self.corners.insert(corner, index);
+        return index;
+    }
+
+    fn build(self) -> TriangleMesh {
+        // A mesh only keeps file normals if every corner has one
+        let normals = if self.missing_normals {
+            None
+        } else {
+            Some(self.normals)
+        };
+        // Corners without texture coordinates get (0, 0)
+        let uvs = if self.has_uvs { self.uvs } else { Vec::new() };
+        return TriangleMesh::new(self.positions, normals, uvs, self.faces, self.material);
+    }
+}
+
+struct ObjBuffers {
+    positions: Vec<Point3D>,
+    normals: Vec<Vector3D>,
+    uvs: Vec<Vector2D>,
+}
+
+// Loads the texture at a path, for map_Kd
+pub type TextureLoader<'a> = dyn FnMut(&str) -> Result<Arc<dyn Texture>, LoadError> + 'a;
+
+// read_mtl returns the contents of the MTL library with the given file name,
+// and load_texture loads the image at a path relative to the OBJ file.
+// Unknown materials and textures that fail to load are warnings, not errors.
+pub fn parse_obj(
+    text: &str,
+    default_material: Arc<Material>,
+    read_mtl: &mut dyn FnMut(&str) -> Result<String, LoadError>,
+    load_texture: &mut TextureLoader,
+) -> Result<ObjModel, LoadError> {
+    let mut obj = ObjBuffers {
+        positions: Vec::new(),
+        normals: Vec::new(),
+        uvs: Vec::new(),
+    };
+    let mut materials: HashMap<String, Arc<Material>> = HashMap::new();
+    // Textures shared between materials are only loaded once
+    let mut textures: HashMap<String, Option<Arc<dyn Texture>>> = HashMap::new();
+    let mut meshes = Vec::new();
+    let mut warnings = Vec::new();
+    let mut current = MeshBuilder::new(Arc::clone(&default_material));
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = strip_comment(line);
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        match keyword {
+            "v" => obj
+                .positions
+                .push(Point3D::new(parse_numbers::<3>(&mut words, line_number)?)),
+            "vn" => obj
+                .normals
+                .push(Vector3D::new(parse_numbers::<3>(&mut words, line_number)?)),
+            "vt" => {
+                // The third texture coordinate is optional and unused
+                let uv = parse_numbers::<2>(&mut words, line_number)?;
+                obj.uvs.push(Vector2D::new(uv));
+            }
+            "f" => {
+                let mut corners = Vec::new();
+                for word in words {
+                    let corner = parse_corner(word, &obj, line_number)?;
+                    corners.push(current.corner(corner, &obj));
+                }
+                if corners.len() < 3 {
+                    return Err(LoadError::parse(
+                        line_number,
+                        "face needs at least 3 vertices",
+                    ));
+                }
+                // Fan out polygons into triangles. Files wind counterclockwise
+                // seen from outside, where our triangles face the other way.
+                for i in 1..corners.len() - 1 {
+                    current.faces.push([corners[0], corners[i + 1], corners[i]]);
+                }
+            }
+            "g" | "o" => {
+                let material = Arc::clone(&current.material);
+                finish_mesh(
+                    &mut meshes,
+                    std::mem::replace(&mut current, MeshBuilder::new(material)),
+                );
+            }
+            "usemtl" => {
+                let name = rest_of_line(line, keyword)
+                    .ok_or_else(|| LoadError::parse(line_number, "usemtl needs a name"))?;
+                let material = match materials.get(name) {
+                    Some(material) => Arc::clone(material),
+                    None => {
+                        warnings.push(format!(
+                            "line {}: unknown material '{}', using the default",
+                            line_number, name
+                        ));
+                        Arc::clone(&default_material)
+                    }
+                };
+                finish_mesh(
+                    &mut meshes,
+                    std::mem::replace(&mut current, MeshBuilder::new(material)),
+                );
+            }
+            "mtllib" => {
+                for name in words {
+                    let mtl = read_mtl(name)?;
+                    let library = parse_mtl(&mtl)
+                        .map_err(|error| LoadError::Format(format!("{}: {}", name, error)))?;
+                    for material in library {
+                        let mut converted = material.to_material();
+                        if let Some(map) = &material.diffuse_map {
+                            // Maps are relative to the library they're in
+                            let path = Path::new(name).with_file_name(map);
+                            let path = path.to_string_lossy().into_owned();
+                            converted.color_texture = textures
+                                .entry(path.clone())
+                                .or_insert_with(|| match load_texture(&path) {
+                                    Ok(texture) => Some(texture),
+                                    Err(error) => {
+                                        warnings.push(format!(
+                                            "line {}: {}: {}, leaving it untextured",
+                                            line_number, path, error
+                                        ));
+                                        None
+                                    }
+                                })
+                                .clone();
+                        }
+                        materials.insert(material.name.clone(), Arc::new(converted));
+                    }
+                }
+            }
+            // Smoothing groups, lines, points and curves aren't supported
+            _ => {}
+        }
+    }
+    finish_mesh(&mut meshes, current);
+    return Ok(ObjModel { meshes, warnings });
+}
+
+fn finish_mesh(meshes: &mut Vec<TriangleMesh>, builder: MeshBuilder) {
+    if !builder.faces.is_empty() {
+        meshes.push(builder.build());
+    }
+}
+
+// Parses v, v/vt, v//vn or v/vt/vn. Indices start at 1, and negative ones
+// count back from the most recent vertex.
+fn parse_corner(
+    word: &str,
+    obj: &ObjBuffers,
+    line_number: usize,
+) -> Result<(usize, Option<usize>, Option<usize>), LoadError> {
+    let mut parts = word.split('/');
+    let resolve =
+        |part: Option<&str>, count: usize, what: &str| -> Result<Option<usize>, LoadError> {
+            let part = match part {
+                Some(part) if !part.is_empty() => part,
+                _ => return Ok(None),
+            };
+            let index: i64 = part.parse().map_err(|_| {
+                LoadError::parse(line_number, format!("bad {} index '{}'", what, part))
+            })?;
+            let resolved = if index < 0 {
+                count as i64 + index
+            } else {
+                index - 1
+            };
+            if resolved < 0 || resolved >= count as i64 {
+                return Err(LoadError::parse(
+                    line_number,
+                    format!("{} index {} out of range", what, index),
+                ));
+            }
+            return Ok(Some(resolved as usize));
+        };
+    let position = resolve(parts.next(), obj.positions.len(), "vertex")?
+        .ok_or_else(|| LoadError::parse(line_number, format!("bad face vertex '{}'", word)))?;
+    let uv = resolve(parts.next(), obj.uvs.len(), "texture")?;
+    let normal = resolve(parts.next(), obj.normals.len(), "normal")?;
+    if parts.next().is_some() {
+        return Err(LoadError::parse(
+            line_number,
+            format!("bad face vertex '{}'", word),
+        ));
+    }
+    return Ok((position, uv, normal));
+}
+
+fn strip_comment(line: &str) -> &str {
+    return match line.find('#') {
+        Some(start) => &line[..start],
+        None => line,
+    };
+}
+
+// Names may contain spaces, so take everything after the keyword
+fn rest_of_line<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let rest = line.trim_start()[keyword.len()..].trim();
+    return if rest.is_empty() { None } else { Some(rest) };
+}
+
+// Reads N numbers, ignoring any extra ones after them
+fn parse_numbers<'a, const N: usize>(
+    words: &mut impl Iterator<Item = &'a str>,
+    line_number: usize,
+) -> Result<[f32; N], LoadError> {
+    let mut numbers = [0.0; N];
+    for number in numbers.iter_mut() {
+        let word = words
+            .next()
+            .ok_or_else(|| LoadError::parse(line_number, format!("expected {} numbers", N)))?;
+        *number = word
+            .parse::<f32>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .ok_or_else(|| LoadError::parse(line_number, format!("bad number '{}'", word)))?;
+    }
+    return Ok(numbers);
+}
+
+// Colors may be given as one gray value or as r g b
+fn parse_color<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+    line_number: usize,
+) -> Result<[f32; 3], LoadError> {
+    let values: Vec<&str> = words.collect();
+    let mut color = match values.len() {
+        1 => {
+            let gray = parse_numbers::<1>(&mut values.into_iter(), line_number)?[0];
+            [gray, gray, gray]
+        }
+        3 => parse_numbers::<3>(&mut values.into_iter(), line_number)?,
+        _ => return Err(LoadError::parse(line_number, "expected 1 or 3 numbers")),
+    };
+    for channel in color.iter_mut() {
+        *channel = channel.clamp(0.0, 1.0);
+    }
+    return Ok(color);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::Image;
+    use crate::raytracer::geometry::material::plain_material;
+    use crate::raytracer::geometry::Geometry;
+    use crate::raytracer::geometry::Ray;
+    use crate::raytracer::texture::TexturePoint;
+
+    const MTL: &str = "# Two materials
+newmtl red plastic
+Kd 1 0 0
+Ks 0.5
+Ns 50
+d 0.75
+Ni 1.45
+map_Kd -s 2 2 2 textures/red.png
+
+newmtl mirror
+Kd 0 0 0
+Ks 1 1 1
+Ke 0 0 0.5
+illum 3
+";
+
+    // A quad and a triangle in two groups
+    const OBJ: &str = "mtllib scene.mtl
+v 0 0 5
+v 1 0 5
+v 1 1 5
+v 0 1 5
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 -1
+g quad
+usemtl red plastic
+f 1/1/1 2/2/1 3/3/1 4/4/1
+g triangle
+usemtl mirror
+f -4//1 -3//1 -2//1
+";
+
+    fn parse(obj: &str) -> Result<ObjModel, LoadError> {
+        let mut read_mtl = |name: &str| -> Result<String, LoadError> {
+            assert_eq!(name, "scene.mtl");
+            return Ok(MTL.to_owned());
+        };
+        // A single gray pixel
+        let mut load_texture = |name: &str| -> Result<Arc<dyn Texture>, LoadError> {
+            assert_eq!(name, "textures/red.png");
+            let image = Image::from_rgba(1, 1, vec![51, 51, 51, 255]);
+            return Ok(Arc::new(ImageTexture::new(image)));
+        };
+        return parse_obj(obj, plain_material(), &mut read_mtl, &mut load_texture);
+    }
+
+    #[test]
+    fn mtl() {
+        let materials = parse_mtl(MTL).unwrap();
+        assert_eq!(materials.len(), 2);
+        assert_eq!(materials[0].name, "red plastic");
+        assert_eq!(
+            materials[0].diffuse_map.as_deref(),
+            Some("textures/red.png")
+        );
+
+        let red = materials[0].to_material();
+        assert_eq!((red.color.r, red.color.g, red.color.a), (1.0, 0.0, 0.75));
+        assert_eq!(
+            (red.specular, red.specular_n, red.reflectivity),
+            (0.5, 50, 0.0)
+        );
+        assert_eq!(red.ior, 1.45);
+        assert!(!red.is_emissive());
+        let mirror = materials[1].to_material();
+        assert_eq!(mirror.reflectivity, 1.0);
+        assert_eq!(mirror.emission.b, 0.5);
+    }
+
+    #[test]
+    fn groups_and_materials() {
+        let model = parse(OBJ).unwrap();
+        assert!(model.warnings.is_empty());
+        let meshes = model.meshes;
+        assert_eq!(meshes.len(), 2);
+
+        let quad = meshes[0].buffers();
+        assert_eq!(quad.faces.len(), 2);
+        assert_eq!(quad.positions.len(), 4);
+        assert_eq!(quad.material.specular_n, 50);
+        // map_Kd tints the diffuse color
+        let textured = quad.material.at(&TexturePoint::new(
+            Vector2D::new([0.5, 0.5]),
+            Point3D::zero(),
+        ));
+        assert!((textured.color.r - 0.2).abs() < 1e-6 && textured.color.g == 0.0);
+        let triangle = meshes[1].buffers();
+        assert_eq!(triangle.faces.len(), 1);
+        assert_eq!(triangle.material.reflectivity, 1.0);
+        assert!(triangle.material.color_texture.is_none());
+        assert!(triangle.uvs.is_empty());
+
+        let ray = Ray {
+            origin: Point3D::new([0.25, 0.75, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let mut meshes = meshes.into_iter();
+        let hit = Arc::new(meshes.next().unwrap())
+            .intersect(&ray, f32::INFINITY)
+            .unwrap();
+        assert_eq!(hit.dist, 5.0);
+        assert_eq!(hit.normal, Vector3D::new([0.0, 0.0, -1.0]));
+        assert!((hit.uv - Vector2D::new([0.25, 0.75])).norm() < 1e-6);
+    }
+
+    #[test]
+    fn winding_faces_outward() {
+        // Counterclockwise from -z, so the computed normal should point at -z
+        let meshes = parse("v 0 0 5\nv 0 1 5\nv 1 0 5\nf 1 2 3\n")
+            .unwrap()
+            .meshes;
+        assert_eq!(
+            meshes[0].buffers().normals[0],
+            Vector3D::new([0.0, 0.0, -1.0])
+        );
+    }
+
+    #[test]
+    fn warnings() {
+        let mut read_mtl = |_: &str| -> Result<String, LoadError> { Ok(MTL.to_owned()) };
+        let mut load_texture = |_: &str| -> Result<Arc<dyn Texture>, LoadError> {
+            Err(LoadError::Format("no such file".to_owned()))
+        };
+        let obj = "mtllib scene.mtl\nusemtl glass\nv 0 0 5\nv 0 1 5\nv 1 0 5\nf 1 2 3\n";
+        let model = parse_obj(obj, plain_material(), &mut read_mtl, &mut load_texture).unwrap();
+        // Neither stops the mesh loading
+        assert_eq!(model.meshes.len(), 1);
+        assert_eq!(model.warnings.len(), 2);
+        assert!(model.warnings[0].starts_with("line 1: textures/red.png"));
+        assert!(model.warnings[1].contains("unknown material 'glass'"));
+    }
+
+    #[test]
+    fn malformed() {
+        match parse("v 0 0 0\nv 1 0 0\nf 1 2 3\n") {
+            Err(LoadError::Parse { line: 3, .. }) => {}
+            _ => panic!("expected an out of range error on line 3"),
+        }
+        match parse("v 0 0\n") {
+            Err(LoadError::Parse { line: 1, .. }) => {}
+            _ => panic!("expected a missing number error on line 1"),
+        }
+        match parse("v 0 0 0\nf 1 1\n") {
+            Err(LoadError::Parse { line: 2, .. }) => {}
+            _ => panic!("expected a short face error on line 2"),
+        }
+        assert!(parse_mtl("Kd 1 1 1\n").is_err());
+    }
+}
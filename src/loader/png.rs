@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::image::Image;
+use crate::loader::LoadError;
+
+// Loads a PNG file of any color type or bit depth as 8 bit RGBA
+pub fn load_png(path: &Path) -> Result<Image, LoadError> {
+    let file = File::open(path)?;
+    return decode_png(BufReader::new(file));
+}
+
+pub fn decode_png(reader: impl Read) -> Result<Image, LoadError> {
+    let format_error = |error: ::png::DecodingError| LoadError::Format(error.to_string());
+    let mut decoder = ::png::Decoder::new(reader);
+    // Palettes and low bit depths are expanded and 16 bit channels cut down,
+    // which leaves 8 bit gray, gray and alpha, RGB or RGBA
+    decoder.set_transformations(::png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(format_error)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(format_error)?;
+    let bytes = &buffer[..frame.buffer_size()];
+
+    let to_rgba = |pixel: &[u8]| -> [u8; 4] {
+        return match *pixel {
+            [gray] => [gray, gray, gray, 255],
+            [gray, alpha] => [gray, gray, gray, alpha],
+            [r, g, b] => [r, g, b, 255],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!(),
+        };
+    };
+    let channels = frame.color_type.samples();
+    let mut pixels = Vec::with_capacity(frame.width as usize * frame.height as usize * 4);
+    // Rows can be padded out past the last pixel
+    for row in bytes.chunks_exact(frame.line_size) {
+        for pixel in row[..frame.width as usize * channels].chunks_exact(channels) {
+            pixels.extend_from_slice(&to_rgba(pixel));
+        }
+    }
+    return Ok(Image::from_rgba(
+        frame.width as usize,
+        frame.height as usize,
+        pixels,
+    ));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Encodes a small image with the png crate, so decoding can be checked
+    // against it
+    fn encode(width: u32, height: u32, color: ::png::ColorType, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = ::png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(::png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        return bytes;
+    }
+
+    #[test]
+    fn color_types() {
+        let rgb = encode(2, 1, ::png::ColorType::Rgb, &[255, 0, 0, 0, 0, 255]);
+        let image = decode_png(&rgb[..]).unwrap();
+        assert_eq!((image.get_width(), image.get_height()), (2, 1));
+        let blue = image.get_pixel(1, 0);
+        assert_eq!((blue.r, blue.b, blue.a), (0.0, 1.0, 1.0));
+
+        let gray_alpha = encode(1, 2, ::png::ColorType::GrayscaleAlpha, &[51, 255, 102, 0]);
+        let image = decode_png(&gray_alpha[..]).unwrap();
+        let clear = image.get_pixel(0, 1);
+        assert_eq!((clear.r, clear.g, clear.a), (0.4, 0.4, 0.0));
+    }
+
+    #[test]
+    fn not_a_png() {
+        assert!(matches!(
+            decode_png(&b"GIF89a"[..]),
+            Err(LoadError::Format(_))
+        ));
+    }
+}
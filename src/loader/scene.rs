@@ -0,0 +1,1243 @@
+// Scene description files. Each line is one statement: a keyword followed by
+// properties, which are a name followed by their values. Anything after a #
+// is a comment. scenes/README describes every keyword, and the other files
+// in scenes/ are worked examples.
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::image::Color;
+use crate::loader::hdr::load_hdr;
+use crate::loader::ies::load_ies;
+use crate::loader::obj::load_obj;
+use crate::loader::png::load_png;
+use crate::loader::stl::load_stl;
+use crate::loader::LoadError;
+use crate::matrix::matrix::Matrix;
+use crate::matrix::vector::{Point3D, Vector3D};
+use crate::raytracer::bsdf::BsdfModel;
+use crate::raytracer::environment::{Environment, EnvironmentMap, UniformEnvironment};
+use crate::raytracer::geometry::material::Material;
+use crate::raytracer::geometry::transformed::Transformed;
+use crate::raytracer::geometry::{Geometry, Sphere, Triangle};
+use crate::raytracer::light::{
+    cone_solid_angle, DirectionalLight, IesLight, LightSource, Lights, PointLight, SpotLight,
+    LUMENS_PER_WATT,
+};
+use crate::raytracer::scene::Scene;
+use crate::raytracer::sky::{self, Sky};
+use crate::raytracer::spectrum::{self, Dispersion, D_LINE};
+use crate::raytracer::texture::procedural::{Pattern, Procedural};
+use crate::raytracer::texture::{ImageTexture, Texture};
+use crate::raytracer::{Antialiasing, Camera, Shading};
+
+// Everything needed to render a scene file
+pub struct SceneDescription {
+    pub camera: Camera,
+    pub scene: Scene,
+    pub lights: Lights,
+    pub antialiasing: Antialiasing,
+    pub reflections: u32,
+    pub shading: Shading,
+    // Problems that didn't stop the scene loading, like a mesh using a
+    // material its library doesn't have
+    pub warnings: Vec<String>,
+}
+
+pub fn load_scene(path: &Path) -> Result<SceneDescription, LoadError> {
+    let text = fs::read_to_string(path)?;
+    let directory = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    return parse_scene(&text, &directory);
+}
+
+// The words of one line, read front to back
+struct Words<'a> {
+    words: std::iter::Peekable<std::str::SplitWhitespace<'a>>,
+    line: usize,
+}
+
+impl<'a> Words<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        return self.words.next();
+    }
+
+    fn word(&mut self, what: &str) -> Result<&'a str, LoadError> {
+        return self
+            .words
+            .next()
+            .ok_or_else(|| LoadError::parse(self.line, format!("expected {}", what)));
+    }
+
+    fn number(&mut self, what: &str) -> Result<f32, LoadError> {
+        let word = self.word(what)?;
+        return word
+            .parse::<f32>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .ok_or_else(|| {
+                LoadError::parse(self.line, format!("bad number '{}' for {}", word, what))
+            });
+    }
+
+    fn whole_number(&mut self, what: &str) -> Result<u32, LoadError> {
+        let word = self.word(what)?;
+        return word.parse::<u32>().map_err(|_| {
+            LoadError::parse(
+                self.line,
+                format!("bad whole number '{}' for {}", word, what),
+            )
+        });
+    }
+
+    fn vector(&mut self, what: &str) -> Result<Vector3D, LoadError> {
+        return Ok(Vector3D::new([
+            self.number(what)?,
+            self.number(what)?,
+            self.number(what)?,
+        ]));
+    }
+
+    // r g b, and optionally a, from 0 to 255
+    fn color(&mut self, what: &str) -> Result<Color, LoadError> {
+        let mut channels = [255; 4];
+        for i in 0..4 {
+            if i == 3
+                && self
+                    .words
+                    .peek()
+                    .is_none_or(|word| word.parse::<u8>().is_err())
+            {
+                break;
+            }
+            let word = self.word(what)?;
+            channels[i] = word.parse::<u8>().map_err(|_| {
+                LoadError::parse(
+                    self.line,
+                    format!("bad color channel '{}' for {}", word, what),
+                )
+            })?;
+        }
+        return Ok(Color::new(
+            channels[0],
+            channels[1],
+            channels[2],
+            channels[3],
+        ));
+    }
+
+    fn unknown(&self, property: &str, keyword: &str) -> LoadError {
+        return LoadError::parse(
+            self.line,
+            format!("unknown property '{}' for {}", property, keyword),
+        );
+    }
+}
+
+struct SceneParser {
+    directory: PathBuf,
+    camera: Camera,
+    antialiasing: Antialiasing,
+    reflections: u32,
+    shading: Shading,
+    materials: HashMap<String, Arc<Material>>,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    objects: Vec<Arc<dyn Geometry>>,
+    lights: Vec<Box<dyn LightSource>>,
+    environment: Box<dyn Environment>,
+    warnings: Vec<String>,
+}
+
+// directory is where mesh files are looked up
+pub fn parse_scene(text: &str, directory: &Path) -> Result<SceneDescription, LoadError> {
+    let mut parser = SceneParser {
+        directory: directory.to_path_buf(),
+        camera: Camera {
+            position: Vector3D::new([0.0, 0.0, 0.0]),
+            look: Vector3D::new([0.0, 0.0, 1.0]),
+            up: Vector3D::new([0.0, 1.0, 0.0]),
+            fov: 90.0,
+            exposure: 1.0,
+        },
+        antialiasing: Antialiasing::Off,
+        reflections: 20,
+        shading: Shading::Whitted,
+        materials: HashMap::new(),
+        textures: HashMap::new(),
+        objects: Vec::new(),
+        lights: Vec::new(),
+        environment: Box::new(UniformEnvironment {
+            color: Color::new(0, 0, 0, 255),
+        }),
+        warnings: Vec::new(),
+    };
+    parser.materials.insert(
+        "default".to_owned(),
+        Arc::new(Material::new(
+            Color::new(255, 255, 255, 255),
+            1.0,
+            0.0,
+            0,
+            0.0,
+        )),
+    );
+
+    for (index, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(start) => &line[..start],
+            None => line,
+        };
+        let mut words = Words {
+            words: line.split_whitespace().peekable(),
+            line: index + 1,
+        };
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        match keyword {
+            "camera" => parser.camera(&mut words)?,
+            "antialiasing" => parser.antialiasing(&mut words)?,
+            "reflections" => parser.reflections = words.whole_number("reflections")?,
+            "shading" => parser.shading(&mut words)?,
+            "texture" => parser.texture(&mut words)?,
+            "material" => parser.material(&mut words)?,
+            "sphere" => parser.sphere(&mut words)?,
+            "triangle" => parser.triangle(&mut words)?,
+            "mesh" => parser.mesh(&mut words)?,
+            "light" => parser.light(&mut words)?,
+            "background" => parser.background(&mut words)?,
+            "sky" => parser.sky(&mut words)?,
+            _ => {
+                return Err(LoadError::parse(
+                    words.line,
+                    format!("unknown statement '{}'", keyword),
+                ))
+            }
+        }
+        if let Some(extra) = words.next() {
+            return Err(LoadError::parse(
+                words.line,
+                format!("unexpected '{}'", extra),
+            ));
+        }
+    }
+
+    if parser.camera.look.norm() == 0.0 || parser.camera.look.cross(&parser.camera.up).norm() == 0.0
+    {
+        return Err(LoadError::Format(
+            "camera look must be non-zero and not parallel to up".to_owned(),
+        ));
+    }
+
+    return Ok(SceneDescription {
+        camera: parser.camera,
+        scene: Scene::with_environment(parser.objects, parser.environment),
+        lights: Lights::new(parser.lights),
+        antialiasing: parser.antialiasing,
+        reflections: parser.reflections,
+        shading: parser.shading,
+        warnings: parser.warnings,
+    });
+}
+
+impl SceneParser {
+    fn camera(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        while let Some(property) = words.next() {
+            match property {
+                "position" => self.camera.position = words.vector(property)?,
+                "look" => self.camera.look = words.vector(property)?,
+                "up" => self.camera.up = words.vector(property)?,
+                "fov" => self.camera.fov = words.number(property)?,
+                "exposure" => {
+                    self.camera.exposure = words.number(property)?;
+                    if self.camera.exposure < 0.0 {
+                        return Err(LoadError::parse(words.line, "exposure can't be negative"));
+                    }
+                }
+                _ => return Err(words.unknown(property, "camera")),
+            }
+        }
+        return Ok(());
+    }
+
+    fn antialiasing(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        self.antialiasing = match words.word("off or grid")? {
+            "off" => Antialiasing::Off,
+            "grid" => {
+                let size = words.whole_number("grid size")?;
+                if size == 0 {
+                    return Err(LoadError::parse(words.line, "grid size must be at least 1"));
+                }
+                Antialiasing::Grid(size)
+            }
+            other => {
+                return Err(LoadError::parse(
+                    words.line,
+                    format!("unknown antialiasing '{}'", other),
+                ))
+            }
+        };
+        return Ok(());
+    }
+
+    // One of whitted, path <samples>, spectral <samples>,
+    // occlusion <samples> <distance>, normals, depth <far> or objects
+    fn shading(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let samples = |words: &mut Words| -> Result<u32, LoadError> {
+            let samples = words.whole_number("samples per pixel")?;
+            if samples == 0 {
+                return Err(LoadError::parse(
+                    words.line,
+                    "samples per pixel must be at least 1",
+                ));
+            }
+            return Ok(samples);
+        };
+        self.shading = match words.word("shading")? {
+            "whitted" => Shading::Whitted,
+            "path" => Shading::PathTraced(samples(words)?),
+            "spectral" => Shading::Spectral(samples(words)?),
+            "occlusion" => Shading::AmbientOcclusion {
+                samples: samples(words)?,
+                distance: words.number("occlusion distance")?,
+            },
+            "normals" => Shading::Normals,
+            "depth" => Shading::Depth {
+                far: words.number("far distance")?,
+            },
+            "objects" => Shading::Objects,
+            other => {
+                return Err(LoadError::parse(
+                    words.line,
+                    format!("unknown shading '{}'", other),
+                ))
+            }
+        };
+        return Ok(());
+    }
+
+    fn material(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let name = words.word("material name")?;
+        let mut color = Color::new(255, 255, 255, 255);
+        let mut diffuse = 1.0;
+        let mut specular = 0.0;
+        let mut specular_n = 0;
+        let mut reflectivity = 0.0;
+        let mut ior = None;
+        let mut dispersion = None;
+        let mut emission = Color::new(0, 0, 0, 0);
+        let mut emission_strength = 1.0;
+        let mut model = None;
+        let mut roughness = 0.0;
+        let mut color_texture = None;
+        let mut specular_texture = None;
+        let mut roughness_texture = None;
+        let mut normal_map = None;
+        let mut bump_map = None;
+        let mut bump_height = 0.01;
+        while let Some(property) = words.next() {
+            match property {
+                "color" => color = words.color(property)?,
+                "diffuse" => diffuse = words.number(property)?,
+                "specular" => specular = words.number(property)?,
+                "specular_n" => {
+                    let value = words.whole_number(property)?;
+                    specular_n = i32::try_from(value).map_err(|_| {
+                        LoadError::parse(words.line, format!("specular_n {} is too large", value))
+                    })?;
+                }
+                "reflectivity" => reflectivity = words.number(property)?,
+                "ior" => {
+                    let value = words.number(property)?;
+                    if value <= 0.0 {
+                        return Err(LoadError::parse(words.line, "ior must be positive"));
+                    }
+                    ior = Some(value);
+                }
+                // Coefficients for wavelengths in micrometres
+                "cauchy" => {
+                    dispersion = Some(Dispersion::Cauchy {
+                        a: words.number(property)?,
+                        b: words.number(property)?,
+                    })
+                }
+                "sellmeier" => {
+                    let mut b = [0.0; 3];
+                    let mut c = [0.0; 3];
+                    for value in b.iter_mut().chain(c.iter_mut()) {
+                        *value = words.number(property)?;
+                    }
+                    dispersion = Some(Dispersion::Sellmeier { b, c });
+                }
+                // Anything with an emissive material becomes an area light
+                "emission" => emission = words.color(property)?,
+                "emission_strength" => emission_strength = words.number(property)?,
+                "bsdf" => {
+                    let name = words.word(property)?;
+                    model = Some(BsdfModel::from_name(name).ok_or_else(|| {
+                        LoadError::parse(words.line, format!("unknown bsdf '{}'", name))
+                    })?);
+                }
+                "roughness" => {
+                    roughness = words.number(property)?;
+                    if !(0.0..=1.0).contains(&roughness) {
+                        return Err(LoadError::parse(
+                            words.line,
+                            "roughness must be between 0 and 1",
+                        ));
+                    }
+                }
+                "texture" => color_texture = Some(self.find_texture(words)?),
+                "specular_texture" => specular_texture = Some(self.find_texture(words)?),
+                "roughness_texture" => roughness_texture = Some(self.find_texture(words)?),
+                "normal_map" => normal_map = Some(self.find_texture(words)?),
+                "bump_map" => bump_map = Some(self.find_texture(words)?),
+                "bump_height" => bump_height = words.number(property)?,
+                _ => return Err(words.unknown(property, "material")),
+            }
+        }
+        let mut material = Material::new(color, diffuse, specular, specular_n, reflectivity);
+        material.dispersion = dispersion;
+        material.emission = emission * emission_strength;
+        material.model = model;
+        material.roughness = roughness;
+        material.color_texture = color_texture;
+        material.specular_texture = specular_texture;
+        material.roughness_texture = roughness_texture;
+        material.normal_map = normal_map;
+        material.bump_map = bump_map;
+        material.bump_height = bump_height;
+        // Without an ior of their own, dispersive materials use theirs at
+        // the middle of the visible range outside of spectral rendering
+        material.ior = match (ior, dispersion) {
+            (Some(ior), _) => ior,
+            (None, Some(dispersion)) => dispersion.ior(D_LINE),
+            (None, None) => 1.0,
+        };
+        self.materials.insert(name.to_owned(), Arc::new(material));
+        return Ok(());
+    }
+
+    // A named texture of some kind, set up by the properties after it
+    fn texture(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let name = words.word("texture name")?;
+        let kind = words.word("texture kind")?;
+        // Everything but images is procedural
+        let mut pattern = match kind {
+            "image" => None,
+            _ => Some(Pattern::from_name(kind).ok_or_else(|| {
+                LoadError::parse(words.line, format!("unknown texture kind '{}'", kind))
+            })?),
+        };
+        let mut file = None;
+        let mut color1 = Color::new(0, 0, 0, 255);
+        let mut color2 = Color::new(255, 255, 255, 255);
+        let mut scale = 1.0;
+        while let Some(property) = words.next() {
+            match (property, &mut pattern) {
+                ("file", None) => file = Some(self.directory.join(words.word(property)?)),
+                ("color1", Some(_)) => color1 = words.color(property)?,
+                ("color2", Some(_)) => color2 = words.color(property)?,
+                ("scale", Some(pattern)) if !matches!(pattern, Pattern::Gradient { .. }) => {
+                    scale = words.number(property)?;
+                    if scale <= 0.0 {
+                        return Err(LoadError::parse(
+                            words.line,
+                            "texture scale must be positive",
+                        ));
+                    }
+                }
+                (
+                    "octaves",
+                    Some(
+                        Pattern::Fbm { octaves }
+                        | Pattern::Turbulence { octaves }
+                        | Pattern::Marble { octaves },
+                    ),
+                ) => *octaves = words.whole_number(property)?,
+                ("start", Some(Pattern::Gradient { start, .. })) => {
+                    *start = words.vector(property)?
+                }
+                ("end", Some(Pattern::Gradient { end, .. })) => *end = words.vector(property)?,
+                _ => return Err(words.unknown(property, &format!("{} texture", kind))),
+            }
+        }
+        let texture: Arc<dyn Texture> = match pattern {
+            Some(pattern) => Arc::new(Procedural::new(pattern, color1, color2, scale)),
+            None => {
+                let file =
+                    file.ok_or_else(|| LoadError::parse(words.line, "image texture needs a file"))?;
+                let image = load_png(&file).map_err(|error| {
+                    LoadError::parse(words.line, format!("{}: {}", file.display(), error))
+                })?;
+                Arc::new(ImageTexture::new(image))
+            }
+        };
+        self.textures.insert(name.to_owned(), texture);
+        return Ok(());
+    }
+
+    fn find_texture(&self, words: &mut Words) -> Result<Arc<dyn Texture>, LoadError> {
+        let name = words.word("texture name")?;
+        return match self.textures.get(name) {
+            Some(texture) => Ok(Arc::clone(texture)),
+            None => Err(LoadError::parse(
+                words.line,
+                format!("unknown texture '{}'", name),
+            )),
+        };
+    }
+
+    fn find_material(&self, words: &mut Words) -> Result<Arc<Material>, LoadError> {
+        let name = words.word("material name")?;
+        return match self.materials.get(name) {
+            Some(material) => Ok(Arc::clone(material)),
+            None => Err(LoadError::parse(
+                words.line,
+                format!("unknown material '{}'", name),
+            )),
+        };
+    }
+
+    // Reads a translate, rotate or scale property onto transform. Returns
+    // false if property is something else.
+    fn transform(
+        words: &mut Words,
+        property: &str,
+        transform: &mut Option<Matrix<f32, 4, 4>>,
+    ) -> Result<bool, LoadError> {
+        let step = match property {
+            "translate" => Matrix::translation(&words.vector(property)?),
+            "rotate" => {
+                let axis = words.vector(property)?;
+                if axis.norm() == 0.0 {
+                    return Err(LoadError::parse(words.line, "rotate axis can't be zero"));
+                }
+                Matrix::rotation(&axis, words.number(property)?)
+            }
+            "scale" => Matrix::scaling(&words.vector(property)?),
+            _ => return Ok(false),
+        };
+        // Later steps apply after earlier ones
+        *transform = Some(match transform {
+            Some(transform) => step * *transform,
+            None => step,
+        });
+        return Ok(true);
+    }
+
+    fn add_object(
+        &mut self,
+        object: Arc<dyn Geometry>,
+        transform: Option<Matrix<f32, 4, 4>>,
+        line: usize,
+    ) -> Result<(), LoadError> {
+        let object: Arc<dyn Geometry> = match transform {
+            Some(transform) => Arc::new(
+                Transformed::new(object, transform)
+                    .ok_or_else(|| LoadError::parse(line, "transform can't be undone"))?,
+            ),
+            None => object,
+        };
+        self.objects.push(object);
+        return Ok(());
+    }
+
+    fn sphere(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let material = self.find_material(words)?;
+        let mut origin = Point3D::zero();
+        let mut radius = 1.0;
+        let mut transform = None;
+        while let Some(property) = words.next() {
+            match property {
+                "origin" => origin = words.vector(property)?,
+                "radius" => radius = words.number(property)?,
+                _ => {
+                    if !SceneParser::transform(words, property, &mut transform)? {
+                        return Err(words.unknown(property, "sphere"));
+                    }
+                }
+            }
+        }
+        let sphere = Arc::new(Sphere {
+            origin,
+            radius,
+            material,
+        });
+        return self.add_object(sphere, transform, words.line);
+    }
+
+    fn triangle(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let material = self.find_material(words)?;
+        let mut corners = [None; 3];
+        let mut transform = None;
+        while let Some(property) = words.next() {
+            match property {
+                "a" => corners[0] = Some(words.vector(property)?),
+                "b" => corners[1] = Some(words.vector(property)?),
+                "c" => corners[2] = Some(words.vector(property)?),
+                _ => {
+                    if !SceneParser::transform(words, property, &mut transform)? {
+                        return Err(words.unknown(property, "triangle"));
+                    }
+                }
+            }
+        }
+        let [a, b, c] = corners;
+        let (a, b, c) = match (a, b, c) {
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+            _ => return Err(LoadError::parse(words.line, "triangle needs a, b and c")),
+        };
+        let triangle = Arc::new(Triangle { a, b, c, material });
+        return self.add_object(triangle, transform, words.line);
+    }
+
+    fn mesh(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let material = self.find_material(words)?;
+        let mut file = None;
+        let mut transform = None;
+        while let Some(property) = words.next() {
+            match property {
+                "file" => file = Some(self.directory.join(words.word(property)?)),
+                _ => {
+                    if !SceneParser::transform(words, property, &mut transform)? {
+                        return Err(words.unknown(property, "mesh"));
+                    }
+                }
+            }
+        }
+        let file = file.ok_or_else(|| LoadError::parse(words.line, "mesh needs a file"))?;
+        let line = words.line;
+        let error =
+            |error: LoadError| LoadError::parse(line, format!("{}: {}", file.display(), error));
+
+        let extension = file
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            // STL meshes are transformed as they load, so they don't need wrapping
+            Some("stl") => {
+                let mesh = load_stl(&file, material, transform.as_ref()).map_err(error)?;
+                self.objects.push(Arc::new(mesh));
+            }
+            // The material is only used for faces without one of their own
+            Some("obj") => {
+                let model = load_obj(&file, material).map_err(error)?;
+                for warning in model.warnings {
+                    self.warnings
+                        .push(format!("line {}: {}: {}", line, file.display(), warning));
+                }
+                for mesh in model.meshes {
+                    self.add_object(Arc::new(mesh), transform, line)?;
+                }
+            }
+            _ => {
+                return Err(LoadError::parse(
+                    line,
+                    format!(
+                        "{}: only .stl and .obj meshes are supported",
+                        file.display()
+                    ),
+                ))
+            }
+        }
+        return Ok(());
+    }
+
+    fn light(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let kind = match words.words.peek() {
+            Some(&kind @ ("point" | "directional" | "spot" | "ies")) => {
+                words.next();
+                kind
+            }
+            _ => "point",
+        };
+        let mut position = Point3D::zero();
+        let mut direction = Vector3D::new([0.0, -1.0, 0.0]);
+        let mut color = Color::new(255, 255, 255, 255);
+        // How bright the light is, as a unit and an amount
+        let mut brightness = None;
+        let mut angle = 30.0;
+        let mut softness = 0.0;
+        let mut file = None;
+        while let Some(property) = words.next() {
+            match property {
+                "position" if kind != "directional" => position = words.vector(property)?,
+                "direction" if kind != "point" => direction = words.vector(property)?,
+                "color" => color = words.color(property)?,
+                "temperature" => {
+                    let kelvin = words.number(property)?;
+                    if kelvin <= 0.0 {
+                        return Err(LoadError::parse(words.line, "temperature must be positive"));
+                    }
+                    color = spectrum::blackbody(kelvin);
+                }
+                "power" | "lumens" | "candela" | "intensity" if kind != "directional" => {
+                    brightness = Some((property, words.number(property)?))
+                }
+                "irradiance" | "lux" if kind == "directional" => {
+                    brightness = Some((property, words.number(property)?))
+                }
+                "angle" if kind == "spot" => angle = words.number(property)?,
+                "softness" if kind == "spot" => softness = words.number(property)?,
+                "file" if kind == "ies" => file = Some(self.directory.join(words.word(property)?)),
+                _ => return Err(words.unknown(property, "light")),
+            }
+        }
+        if direction.norm() == 0.0 {
+            return Err(LoadError::parse(
+                words.line,
+                "light direction must be non-zero",
+            ));
+        }
+        if brightness.is_some_and(|(_, amount)| amount < 0.0) {
+            return Err(LoadError::parse(words.line, "light can't be negative"));
+        }
+        if !(angle > 0.0 && angle <= 180.0) {
+            return Err(LoadError::parse(
+                words.line,
+                "spot angle must be more than 0 and at most 180 degrees",
+            ));
+        }
+        if softness < 0.0 {
+            return Err(LoadError::parse(
+                words.line,
+                "spot softness can't be negative",
+            ));
+        }
+        // Lights given by their total output spread it over every direction
+        // they shine in
+        let watts = |solid_angle: f32| match brightness {
+            Some(("power", watts)) => watts / solid_angle,
+            Some(("lumens", lumens)) => lumens / LUMENS_PER_WATT / solid_angle,
+            Some(("candela", candela)) => candela / LUMENS_PER_WATT,
+            Some((_, intensity)) => intensity,
+            None => 1.0,
+        };
+
+        let light: Box<dyn LightSource> = match kind {
+            "directional" => Box::new(DirectionalLight {
+                direction,
+                color,
+                irradiance: match brightness {
+                    Some(("lux", lux)) => lux / LUMENS_PER_WATT,
+                    Some((_, irradiance)) => irradiance,
+                    None => 1.0,
+                },
+            }),
+            "spot" => Box::new(SpotLight {
+                position,
+                direction,
+                angle,
+                softness,
+                color,
+                intensity: watts(cone_solid_angle(angle)),
+            }),
+            "ies" => {
+                let file =
+                    file.ok_or_else(|| LoadError::parse(words.line, "ies light needs a file"))?;
+                let profile = load_ies(&file).map_err(|error| {
+                    LoadError::parse(words.line, format!("{}: {}", file.display(), error))
+                })?;
+                // Profiles are used as measured unless told otherwise. The
+                // profile's total output or peak candela are scaled to match.
+                let scale = match brightness {
+                    Some(("power" | "lumens", _)) => watts(1.0) / profile.lumens(),
+                    Some(_) => watts(1.0) / profile.max_candela(),
+                    None => 1.0 / LUMENS_PER_WATT,
+                };
+                if !scale.is_finite() {
+                    return Err(LoadError::parse(words.line, "ies profile gives no light"));
+                }
+                Box::new(IesLight {
+                    position,
+                    direction,
+                    profile,
+                    color,
+                    scale,
+                })
+            }
+            _ => Box::new(PointLight {
+                position,
+                color,
+                intensity: watts(4.0 * PI),
+            }),
+        };
+        self.lights.push(light);
+        return Ok(());
+    }
+
+    // Rays that miss everything see the environment, which is a flat color
+    // or an HDR image wrapped around the scene
+    fn background(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let mut color = Color::new(0, 0, 0, 255);
+        let mut file = None;
+        let mut strength = 1.0;
+        let mut rotation = 0.0;
+        while let Some(property) = words.next() {
+            match property {
+                "color" => color = words.color(property)?,
+                "file" => file = Some(self.directory.join(words.word(property)?)),
+                "strength" => strength = words.number(property)?,
+                "rotate" => rotation = words.number(property)?,
+                _ => return Err(words.unknown(property, "background")),
+            }
+        }
+        if strength < 0.0 {
+            return Err(LoadError::parse(
+                words.line,
+                "background strength can't be negative",
+            ));
+        }
+        self.environment = match file {
+            Some(file) => {
+                let image = load_hdr(&file).map_err(|error| {
+                    LoadError::parse(words.line, format!("{}: {}", file.display(), error))
+                })?;
+                Box::new(EnvironmentMap::new(image, strength, rotation))
+            }
+            None => Box::new(UniformEnvironment {
+                color: color * strength,
+            }),
+        };
+        return Ok(());
+    }
+
+    // A Preetham sky with its sun, placed directly or from a date, time and
+    // place
+    fn sky(&mut self, words: &mut Words) -> Result<(), LoadError> {
+        let line = words.line;
+        let mut turbidity = 3.0;
+        let mut strength = 1.0;
+        let mut elevation = None;
+        let mut azimuth = None;
+        let mut date = None;
+        let mut time = None;
+        let (mut latitude, mut longitude, mut timezone) = (0.0, 0.0, 0.0);
+        let mut place_given = false;
+        while let Some(property) = words.next() {
+            match property {
+                "turbidity" => turbidity = words.number(property)?,
+                "strength" => strength = words.number(property)?,
+                "elevation" => elevation = Some(words.number(property)?),
+                "azimuth" => azimuth = Some(words.number(property)?),
+                "date" => date = Some(parse_date(words.word(property)?, line)?),
+                "time" => time = Some(parse_time(words.word(property)?, line)?),
+                "latitude" | "longitude" | "timezone" => {
+                    let value = words.number(property)?;
+                    match property {
+                        "latitude" => latitude = value,
+                        "longitude" => longitude = value,
+                        _ => timezone = value,
+                    }
+                    place_given = true;
+                }
+                _ => return Err(words.unknown(property, "sky")),
+            }
+        }
+        if turbidity < 1.0 {
+            return Err(LoadError::parse(line, "sky turbidity must be at least 1"));
+        }
+        if strength < 0.0 {
+            return Err(LoadError::parse(line, "sky strength can't be negative"));
+        }
+        let placed = date.is_some() || time.is_some() || place_given;
+        if placed && (elevation.is_some() || azimuth.is_some()) {
+            return Err(LoadError::parse(
+                line,
+                "sky takes either elevation and azimuth or a date, time and place",
+            ));
+        }
+        let (elevation, azimuth) = match (date, time) {
+            (Some(day), Some(hours)) => {
+                sky::sun_position(day, hours, latitude, longitude, timezone)
+            }
+            (None, None) if !place_given => (elevation.unwrap_or(45.0), azimuth.unwrap_or(180.0)),
+            _ => return Err(LoadError::parse(line, "sky needs both a date and a time")),
+        };
+
+        let sky = Sky::new(elevation, azimuth, turbidity, strength);
+        if let Some(sun) = sky.sun_light() {
+            self.lights.push(Box::new(sun));
+        }
+        self.environment = Box::new(sky);
+        return Ok(());
+    }
+}
+
+// YYYY-MM-DD as a day of the year
+fn parse_date(word: &str, line: usize) -> Result<u32, LoadError> {
+    let bad = || LoadError::parse(line, format!("bad date '{}', expected YYYY-MM-DD", word));
+    let parts: Vec<&str> = word.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return Err(bad());
+    };
+    let year = year.parse::<i32>().map_err(|_| bad())?;
+    let month = month.parse::<u32>().map_err(|_| bad())?;
+    let day = day.parse::<u32>().map_err(|_| bad())?;
+    return sky::day_of_year(year, month, day).ok_or_else(bad);
+}
+
+// HH:MM or HH:MM:SS as hours since midnight
+fn parse_time(word: &str, line: usize) -> Result<f32, LoadError> {
+    let bad = || LoadError::parse(line, format!("bad time '{}', expected HH:MM", word));
+    let parts = word
+        .split(':')
+        .map(|part| part.parse::<u32>().map_err(|_| bad()))
+        .collect::<Result<Vec<u32>, LoadError>>()?;
+    let (hours, minutes, seconds) = match parts[..] {
+        [hours, minutes] => (hours, minutes, 0),
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        _ => return Err(bad()),
+    };
+    if hours > 24 || minutes > 59 || seconds > 59 {
+        return Err(bad());
+    }
+    return Ok(hours as f32 + minutes as f32 / 60.0 + seconds as f32 / 3600.0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::Image;
+    use crate::matrix::vector::Vector2D;
+    use crate::raytracer::geometry::Ray;
+    use crate::raytracer::texture::TexturePoint;
+
+    fn parse(text: &str) -> Result<SceneDescription, LoadError> {
+        return parse_scene(text, Path::new("."));
+    }
+
+    fn error_line(text: &str) -> usize {
+        return match parse(text) {
+            Err(LoadError::Parse { line, .. }) => line,
+            Err(error) => panic!("expected a parse error, got {}", error),
+            Ok(_) => panic!("expected a parse error"),
+        };
+    }
+
+    #[test]
+    fn full_scene() {
+        let description = parse(
+            "# A small scene
+camera position 1 2 3 look 0 0 2 up 0 1 0 fov 60 exposure 2
+antialiasing grid 4
+reflections 5
+shading path 16
+
+material red color 255 0 0 diffuse 0.5 specular 0.25 specular_n 10
+material glass color 255 255 255 128 reflectivity 0.5 ior 1.5
+sphere red origin 0 0 16 radius 2
+triangle glass a 0 0 0 b 1 0 0 c 0 1 0 translate 0 0 10
+light position 3 5 15 intensity 5   # key light
+light position -3 5 15 color 255 255 0
+background color 0 0 255 strength 2
+",
+        )
+        .unwrap();
+
+        assert_eq!(description.camera.position, Vector3D::new([1.0, 2.0, 3.0]));
+        assert_eq!(description.camera.fov, 60.0);
+        assert_eq!(description.camera.exposure, 2.0);
+        assert!(matches!(description.antialiasing, Antialiasing::Grid(4)));
+        assert_eq!(description.reflections, 5);
+        assert_eq!(description.shading, Shading::PathTraced(16));
+        assert_eq!(description.scene.len(), 2);
+        let background = description
+            .scene
+            .background(&Vector3D::new([0.0, 1.0, 0.0]));
+        assert_eq!((background.r, background.b), (0.0, 2.0));
+        assert_eq!(description.lights.sources.len(), 2);
+        let light = description.lights.sources[0].illuminate(&Point3D::new([3.0, 4.0, 15.0]));
+        assert_eq!(light.unwrap().irradiance, 5.0);
+        let light = description.lights.sources[1].illuminate(&Point3D::zero());
+        assert_eq!(light.unwrap().color.b, 0.0);
+    }
+
+    #[test]
+    fn light_types() {
+        let description = parse(
+            "light directional direction 0 -2 0 irradiance 2
+light spot position 0 4 0 angle 20 softness 5
+light point position 1 0 0
+",
+        )
+        .unwrap();
+        let sources = &description.lights.sources;
+        let below = Point3D::zero();
+        let sun = sources[0].illuminate(&below).unwrap();
+        assert_eq!(sun.direction, Vector3D::new([0.0, 1.0, 0.0]));
+        assert_eq!(sun.irradiance, 2.0);
+        // Spots point down by default
+        assert_eq!(sources[1].illuminate(&below).unwrap().dist, 4.0);
+        assert!(sources[1]
+            .illuminate(&Point3D::new([4.0, 0.0, 0.0]))
+            .is_none());
+        assert_eq!(sources[2].illuminate(&below).unwrap().dist, 1.0);
+    }
+
+    #[test]
+    fn light_units() {
+        let description = parse(
+            "light position 0 1 0 power 4
+light position 0 1 0 lumens 683
+light position 0 1 0 candela 683
+light spot position 0 1 0 angle 60 power 2
+light directional lux 683 temperature 3000
+",
+        )
+        .unwrap();
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-5 * b;
+        let sources = &description.lights.sources;
+        let at = |index: usize| sources[index].illuminate(&Point3D::zero()).unwrap();
+        // A point light spreads its power over the whole sphere
+        assert!(close(at(0).irradiance, 1.0 / PI));
+        assert!(close(at(1).irradiance, 1.0 / (4.0 * PI)));
+        assert!(close(at(2).irradiance, 1.0));
+        // and a spot over its cone, which is pi steradians at 60 degrees
+        assert!(close(at(3).irradiance, 2.0 / PI));
+        let sun = at(4);
+        assert!(close(sun.irradiance, 1.0));
+        // A warm white
+        assert!(sun.color.r > sun.color.b);
+
+        assert_eq!(error_line("light power -1"), 1);
+        assert_eq!(error_line("light directional power 1"), 1);
+        assert_eq!(error_line("light lux 1"), 1);
+        assert_eq!(error_line("light temperature 0"), 1);
+        assert_eq!(error_line("camera exposure -1"), 1);
+    }
+
+    #[test]
+    fn shading() {
+        let shading = |text: &str| parse(text).unwrap().shading;
+        assert_eq!(shading(""), Shading::Whitted);
+        assert_eq!(
+            shading("shading occlusion 8 2.5"),
+            Shading::AmbientOcclusion {
+                samples: 8,
+                distance: 2.5
+            }
+        );
+        assert_eq!(shading("shading normals"), Shading::Normals);
+        assert_eq!(shading("shading depth 30"), Shading::Depth { far: 30.0 });
+        assert_eq!(shading("shading objects"), Shading::Objects);
+        assert_eq!(shading("shading spectral 32"), Shading::Spectral(32));
+    }
+
+    #[test]
+    fn dispersion() {
+        let material = |text: &str| {
+            let description =
+                parse(&format!("{}\nsphere glass origin 0 0 5 radius 1", text)).unwrap();
+            let ray = Ray {
+                origin: Point3D::zero(),
+                direction: Vector3D::new([0.0, 0.0, 1.0]),
+            };
+            return description
+                .scene
+                .intersect(&ray, f32::INFINITY, None)
+                .unwrap()
+                .material;
+        };
+
+        let flint = material("material glass cauchy 1.67 0.0074");
+        assert_eq!(flint.ior, flint.ior_at(Some(D_LINE)));
+        assert!(flint.ior_at(Some(450.0)) > flint.ior_at(Some(650.0)));
+        let bk7 =
+            material("material glass ior 1.5 sellmeier 1.0396 0.2318 1.0105 0.0060 0.0200 103.56");
+        assert_eq!(bk7.ior, 1.5);
+        assert!((bk7.ior_at(Some(D_LINE)) - 1.5168).abs() < 1e-3);
+        assert!(material("material glass ior 1.5").dispersion.is_none());
+    }
+
+    #[test]
+    fn bsdf_materials() {
+        let description = parse(
+            "material steel color 200 200 210 bsdf conductor roughness 0.3
+material chalk diffuse 0.5
+sphere steel origin 0 0 5 radius 1
+",
+        )
+        .unwrap();
+        let ray = Ray {
+            origin: Point3D::zero(),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let hit = description
+            .scene
+            .intersect(&ray, f32::INFINITY, None)
+            .unwrap();
+        assert_eq!(hit.material.model, Some(BsdfModel::Conductor));
+        assert_eq!(hit.material.roughness, 0.3);
+        assert!(hit.material.bsdf(hit.material.color, 1.0).is_some());
+
+        assert_eq!(error_line("material a bsdf phong"), 1);
+        assert_eq!(error_line("material a bsdf dielectric roughness 2"), 1);
+    }
+
+    #[test]
+    fn textures() {
+        // Red on the left and blue on the right
+        let directory = std::env::temp_dir();
+        let mut image = Image::new(2, 1);
+        image.set_pixel(0, 0, Color::new(255, 0, 0, 255));
+        image.set_pixel(1, 0, Color::new(0, 0, 255, 255));
+        image
+            .save(&directory.join("scene_texture.png").display().to_string())
+            .unwrap();
+        let description = parse_scene(
+            "texture flag image file scene_texture.png
+material flag texture flag roughness_texture flag bsdf conductor
+sphere flag origin 0 0 5 radius 1",
+            &directory,
+        )
+        .unwrap();
+        let ray = Ray {
+            origin: Point3D::zero(),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let hit = description
+            .scene
+            .intersect(&ray, f32::INFINITY, None)
+            .unwrap();
+        let uv = |u: f32, v: f32| TexturePoint::new(Vector2D::new([u, v]), hit.local);
+        let left = hit.material.at(&uv(0.25, 0.5));
+        assert_eq!((left.color.r, left.color.b), (1.0, 0.0));
+        assert!((left.roughness - 1.0 / 3.0).abs() < 1e-6);
+        let right = hit.material.at(&uv(0.75, 0.5));
+        assert_eq!((right.color.r, right.color.b), (0.0, 1.0));
+
+        assert_eq!(error_line("\nmaterial red texture missing"), 2);
+        assert_eq!(error_line("texture flag image"), 1);
+        assert_eq!(error_line("texture flag image file missing.png"), 1);
+        assert_eq!(error_line("texture flag bricks"), 1);
+    }
+
+    #[test]
+    fn procedural_textures() {
+        let description = parse(
+            "texture tiles checker color1 255 0 0 color2 0 0 255 scale 2
+texture veins marble octaves 3 scale 0.5
+texture ramp gradient start 0 0 4 end 0 0 6
+material floor texture tiles specular_texture veins roughness_texture ramp bsdf conductor \
+bump_map veins bump_height 0.02
+sphere floor origin 0 0 5 radius 1",
+        )
+        .unwrap();
+        let ray = Ray {
+            origin: Point3D::new([0.5, 0.5, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let hit = description
+            .scene
+            .intersect(&ray, f32::INFINITY, None)
+            .unwrap();
+        // Looked up at the hit's own position, in an even checker
+        let material = hit.material.at(&TexturePoint::of(&hit));
+        assert_eq!((material.color.r, material.color.b), (1.0, 0.0));
+        assert!((0.0..=1.0).contains(&material.specular));
+        assert!((material.roughness - (hit.pos.z() - 4.0) / 2.0).abs() < 1e-5);
+        assert!(material.bump_map.is_some() && material.normal_map.is_none());
+        assert_eq!(material.bump_height, 0.02);
+
+        assert_eq!(error_line("texture tiles bricks"), 1);
+        assert_eq!(error_line("material bumpy bump_map missing"), 1);
+        assert_eq!(error_line("texture tiles checker octaves 3"), 1);
+        assert_eq!(error_line("texture tiles gradient scale 3"), 1);
+        assert_eq!(error_line("texture tiles wood scale 0"), 1);
+        assert_eq!(error_line("texture tiles perlin file noise.png"), 1);
+    }
+
+    #[test]
+    fn area_lights() {
+        let description = parse(
+            "material bulb emission 255 0 255 emission_strength 2
+sphere bulb origin 0 0 5 radius 1
+sphere default origin 0 0 10 radius 1
+",
+        )
+        .unwrap();
+        assert_eq!(description.scene.emitters().len(), 1);
+        let emission = description.scene.emitters()[0]
+            .sample_surface(0.5, 0.5)
+            .unwrap()
+            .material
+            .emission;
+        assert_eq!((emission.r, emission.g, emission.b), (2.0, 0.0, 2.0));
+    }
+
+    #[test]
+    fn sky() {
+        let description =
+            parse("sky turbidity 4 date 2023-06-21 time 13:00 latitude 51.5 timezone 1").unwrap();
+        // The sky is blue and the sun shines down from it, from the south
+        let zenith = description
+            .scene
+            .background(&Vector3D::new([0.0, 1.0, 0.0]));
+        assert!(zenith.b > zenith.r);
+        assert_eq!(description.lights.sources.len(), 1);
+        let sun = description.lights.sources[0]
+            .illuminate(&Point3D::zero())
+            .unwrap();
+        assert!(sun.direction.y() > 0.8 && sun.direction.z() < 0.0);
+
+        // Nothing but the sky glow once the sun has set
+        let night = parse("sky elevation -3 azimuth 270").unwrap();
+        assert_eq!(night.lights.sources.len(), 0);
+
+        assert_eq!(error_line("sky turbidity 0.5"), 1);
+        assert_eq!(error_line("sky date 2023-02-30 time 12:00"), 1);
+        assert_eq!(error_line("sky date 2023-06-21"), 1);
+        assert_eq!(error_line("sky time 25:00 date 2023-06-21"), 1);
+        assert_eq!(error_line("sky elevation 30 latitude 10"), 1);
+        assert_eq!(error_line("sky date 21/06/2023 time 12:00"), 1);
+    }
+
+    #[test]
+    fn environment_map() {
+        let description = load_scene(Path::new("scenes/studio.scene")).unwrap();
+        let environment = description.scene.environment();
+        // The studio's lights are much brighter than white
+        let sample = environment.sample(0.5, 0.5).unwrap();
+        assert!(sample.radiance.g > 1.0);
+        assert!(description.scene.background(&sample.direction).g > 1.0);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        assert_eq!(error_line("\n\nfrobnicate\n"), 3);
+        assert_eq!(error_line("camera fov\n"), 1);
+        assert_eq!(error_line("camera fov wide\n"), 1);
+        assert_eq!(error_line("\nsphere missing radius 1\n"), 2);
+        assert_eq!(error_line("material red color 300 0 0\n"), 1);
+        assert_eq!(error_line("material red specular_n 4294967295\n"), 1);
+        assert_eq!(error_line("triangle default a 0 0 0 b 1 0 0\n"), 1);
+        assert_eq!(error_line("sphere default radius 1 scale 0 1 1\n"), 1);
+        assert_eq!(error_line("reflections 5 6\n"), 1);
+        assert_eq!(error_line("\nshading path 0\n"), 2);
+        assert_eq!(error_line("shading radiosity\n"), 1);
+        assert_eq!(error_line("shading occlusion 16\n"), 1);
+        assert_eq!(error_line("shading depth\n"), 1);
+        assert_eq!(error_line("material glass ior 0\n"), 1);
+        assert_eq!(error_line("material glass cauchy 1.5\n"), 1);
+        assert_eq!(error_line("material glass sellmeier 1 2 3 4 5\n"), 1);
+        assert_eq!(error_line("material bulb emission 255 255\n"), 1);
+        assert_eq!(error_line("light point direction 0 1 0\n"), 1);
+        assert_eq!(error_line("light directional position 0 1 0\n"), 1);
+        assert_eq!(error_line("\nlight spot direction 0 0 0\n"), 2);
+        assert_eq!(error_line("light spot angle 0\n"), 1);
+        assert_eq!(error_line("light spot angle 190\n"), 1);
+        assert_eq!(error_line("light spot softness -5\n"), 1);
+        assert_eq!(error_line("light ies position 0 1 0\n"), 1);
+        assert_eq!(error_line("\nlight ies file missing.ies\n"), 2);
+        assert_eq!(error_line("\n\n\nmesh default file missing.stl\n"), 4);
+        assert_eq!(error_line("background void\n"), 1);
+        assert_eq!(error_line("background color 255 255 255 strength -1\n"), 1);
+        assert_eq!(error_line("\nbackground file missing.hdr\n"), 2);
+    }
+}
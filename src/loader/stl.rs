@@ -0,0 +1,367 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::loader::LoadError;
+use crate::matrix::matrix::Matrix;
+use crate::matrix::vector::{Point3D, Vector3D};
+use crate::raytracer::geometry::material::Material;
+use crate::raytracer::geometry::mesh::TriangleMesh;
+
+// Binary STL: 80 byte header, a u32 triangle count, then 50 bytes per triangle
+const HEADER_SIZE: usize = 84;
+const FACET_SIZE: usize = 50;
+
+// One triangle as stored in the file
+struct Facet {
+    normal: Vector3D,
+    vertices: [Point3D; 3],
+}
+
+// Loads an ASCII or binary STL file as a flat shaded mesh, optionally
+// moving it into place with transform.
+pub fn load_stl(
+    path: &Path,
+    material: Arc<Material>,
+    transform: Option<&Matrix<f32, 4, 4>>,
+) -> Result<TriangleMesh, LoadError> {
+    let bytes = fs::read(path)?;
+    return parse_stl(&bytes, material, transform);
+}
+
+pub fn parse_stl(
+    bytes: &[u8],
+    material: Arc<Material>,
+    transform: Option<&Matrix<f32, 4, 4>>,
+) -> Result<TriangleMesh, LoadError> {
+    // Binary files may also start with "solid", so go by whether the size
+    // in the header adds up instead
+    let binary_size = binary_size(bytes);
+    let text = std::str::from_utf8(bytes).ok();
+    let facets = if binary_size == Some(bytes.len()) {
+        parse_binary(bytes)?
+    } else if let Some(text) = text.filter(|_| bytes.starts_with(b"solid")) {
+        parse_ascii(text)?
+    } else if let Some(size) = binary_size.filter(|&size| size > bytes.len()) {
+        return Err(LoadError::Format(format!(
+            "truncated binary STL: the header promises {} bytes but there are only {}",
+            size,
+            bytes.len()
+        )));
+    } else if bytes.starts_with(b"solid") {
+        return Err(LoadError::Format("ASCII STL is not valid UTF-8".to_owned()));
+    } else {
+        return Err(LoadError::Format(format!(
+            "not an STL file: {} bytes doesn't match the triangle count in the header",
+            bytes.len()
+        )));
+    };
+    return Ok(build_mesh(facets, material, transform));
+}
+
+// How long a binary file with the triangle count in its header should be
+fn binary_size(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < HEADER_SIZE {
+        return None;
+    }
+    let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+    return count.checked_mul(FACET_SIZE)?.checked_add(HEADER_SIZE);
+}
+
+fn parse_binary(bytes: &[u8]) -> Result<Vec<Facet>, LoadError> {
+    let read_vector = |offset: usize| -> Vector3D {
+        let mut data = [0.0; 3];
+        for i in 0..3 {
+            let start = offset + i * 4;
+            let mut float = [0; 4];
+            float.copy_from_slice(&bytes[start..start + 4]);
+            data[i] = f32::from_le_bytes(float);
+        }
+        return Vector3D::new(data);
+    };
+
+    let mut facets = Vec::new();
+    for (index, offset) in (HEADER_SIZE..bytes.len()).step_by(FACET_SIZE).enumerate() {
+        let facet = Facet {
+            normal: read_vector(offset),
+            vertices: [
+                read_vector(offset + 12),
+                read_vector(offset + 24),
+                read_vector(offset + 36),
+            ],
+        };
+        check_finite(&facet)
+            .map_err(|message| LoadError::Format(format!("triangle {}: {}", index, message)))?;
+        facets.push(facet);
+    }
+    return Ok(facets);
+}
+
+fn parse_ascii(text: &str) -> Result<Vec<Facet>, LoadError> {
+    let mut facets = Vec::new();
+    let mut normal: Option<Vector3D> = None;
+    let mut vertices: Vec<Point3D> = Vec::new();
+    let mut facet_line = 0;
+    let mut ended = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if ended {
+            return Err(LoadError::parse(line_number, "text after endsolid"));
+        }
+        match keyword {
+            "solid" if line_number == 1 => {}
+            "facet" => {
+                if normal.is_some() {
+                    return Err(LoadError::parse(line_number, "facet inside another facet"));
+                }
+                if words.next() != Some("normal") {
+                    return Err(LoadError::parse(line_number, "expected 'facet normal'"));
+                }
+                normal = Some(parse_vector(&mut words, line_number)?);
+                vertices.clear();
+                facet_line = line_number;
+            }
+            "outer" => {
+                if normal.is_none() || words.next() != Some("loop") {
+                    return Err(LoadError::parse(line_number, "unexpected 'outer'"));
+                }
+            }
+            "vertex" => {
+                if normal.is_none() {
+                    return Err(LoadError::parse(line_number, "vertex outside of a facet"));
+                }
+                if vertices.len() == 3 {
+                    return Err(LoadError::parse(
+                        line_number,
+                        "facet has more than 3 vertices",
+                    ));
+                }
+                vertices.push(parse_vector(&mut words, line_number)?);
+            }
+            "endloop" => {}
+            "endfacet" => {
+                let facet_normal = match normal.take() {
+                    Some(facet_normal) => facet_normal,
+                    None => return Err(LoadError::parse(line_number, "endfacet without facet")),
+                };
+                if vertices.len() != 3 {
+                    return Err(LoadError::parse(
+                        facet_line,
+                        format!("facet has {} vertices, expected 3", vertices.len()),
+                    ));
+                }
+                facets.push(Facet {
+                    normal: facet_normal,
+                    vertices: [vertices[0], vertices[1], vertices[2]],
+                });
+            }
+            "endsolid" => {
+                if normal.is_some() {
+                    return Err(LoadError::parse(line_number, "endsolid inside a facet"));
+                }
+                ended = true;
+            }
+            _ => {
+                return Err(LoadError::parse(
+                    line_number,
+                    format!("unexpected '{}'", keyword),
+                ))
+            }
+        }
+    }
+
+    if !ended {
+        return Err(LoadError::Format(
+            "ASCII STL is missing endsolid".to_owned(),
+        ));
+    }
+    return Ok(facets);
+}
+
+// Reads exactly three finite numbers from the rest of the line
+fn parse_vector<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+    line_number: usize,
+) -> Result<Vector3D, LoadError> {
+    let mut data = [0.0; 3];
+    for i in 0..3 {
+        let word = words
+            .next()
+            .ok_or_else(|| LoadError::parse(line_number, "expected 3 numbers"))?;
+        data[i] = word
+            .parse::<f32>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .ok_or_else(|| LoadError::parse(line_number, format!("bad number '{}'", word)))?;
+    }
+    if words.next().is_some() {
+        return Err(LoadError::parse(line_number, "expected 3 numbers"));
+    }
+    return Ok(Vector3D::new(data));
+}
+
+fn check_finite(facet: &Facet) -> Result<(), String> {
+    let finite = |vector: &Vector3D| (0..3).all(|i| vector[i].is_finite());
+    if !finite(&facet.normal) || !facet.vertices.iter().all(finite) {
+        return Err("contains a NaN or infinite number".to_owned());
+    }
+    return Ok(());
+}
+
+// STL facets don't share vertices, so each one gets its own three. That
+// keeps the hard edges CAD parts are made of.
+fn build_mesh(
+    facets: Vec<Facet>,
+    material: Arc<Material>,
+    transform: Option<&Matrix<f32, 4, 4>>,
+) -> TriangleMesh {
+    // A mirroring transform turns the winding inside out
+    let mirrored = match transform {
+        Some(transform) => {
+            let x = transform.transform_vector(&Vector3D::new([1.0, 0.0, 0.0]));
+            let y = transform.transform_vector(&Vector3D::new([0.0, 1.0, 0.0]));
+            let z = transform.transform_vector(&Vector3D::new([0.0, 0.0, 1.0]));
+            x.cross(&y) * z < 0.0
+        }
+        None => false,
+    };
+
+    let mut positions = Vec::with_capacity(facets.len() * 3);
+    let mut faces = Vec::with_capacity(facets.len());
+    for facet in facets {
+        let [a, b, c] = facet.vertices;
+        // Files wind counterclockwise seen from outside, where our triangles
+        // face the other way, so b and c swap. Trust the stored normal over
+        // the winding when it disagrees.
+        let outward = (b - a).cross(&(c - a));
+        let mut vertices = if outward * facet.normal < 0.0 {
+            [a, b, c]
+        } else {
+            [a, c, b]
+        };
+        if mirrored {
+            vertices.swap(1, 2);
+        }
+        if let Some(transform) = transform {
+            vertices = vertices.map(|vertex| transform.transform_point(&vertex));
+        }
+
+        let first = positions.len() as u32;
+        positions.extend_from_slice(&vertices);
+        faces.push([first, first + 1, first + 2]);
+    }
+
+    // Every vertex belongs to one face, so these are just the face normals
+    return TriangleMesh::new(positions, None, Vec::new(), faces, material);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raytracer::geometry::material::plain_material;
+    use crate::raytracer::geometry::Geometry;
+    use crate::raytracer::geometry::Ray;
+
+    const ASCII: &str = "solid test
+  facet normal 0 0 -1
+    outer loop
+      vertex 0 0 5
+      vertex 0 1 5
+      vertex 1 0 5
+    endloop
+  endfacet
+endsolid test
+";
+
+    fn binary(facets: &[[f32; 12]]) -> Vec<u8> {
+        let mut bytes = b"solid but actually binary".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend_from_slice(&(facets.len() as u32).to_le_bytes());
+        for facet in facets {
+            for value in facet {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes.extend_from_slice(&[0, 0]);
+        }
+        return bytes;
+    }
+
+    fn hit_normal(mesh: TriangleMesh) -> Option<Vector3D> {
+        let ray = Ray {
+            origin: Point3D::new([0.25, 0.25, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        return Arc::new(mesh)
+            .intersect(&ray, f32::INFINITY)
+            .map(|hit| hit.normal);
+    }
+
+    #[test]
+    fn ascii() {
+        let mesh = parse_stl(ASCII.as_bytes(), plain_material(), None).unwrap();
+        assert_eq!(mesh.buffers().faces.len(), 1);
+        assert_eq!(hit_normal(mesh), Some(Vector3D::new([0.0, 0.0, -1.0])));
+    }
+
+    #[test]
+    fn binary_with_solid_header() {
+        let bytes = binary(&[[0.0, 0.0, -1.0, 0.0, 0.0, 5.0, 0.0, 1.0, 5.0, 1.0, 0.0, 5.0]]);
+        let mesh = parse_stl(&bytes, plain_material(), None).unwrap();
+        assert_eq!(mesh.buffers().faces.len(), 1);
+        assert_eq!(hit_normal(mesh), Some(Vector3D::new([0.0, 0.0, -1.0])));
+    }
+
+    #[test]
+    fn truncated_binary() {
+        let mut bytes = binary(&[[0.0, 0.0, -1.0, 0.0, 0.0, 5.0, 0.0, 1.0, 5.0, 1.0, 0.0, 5.0]]);
+        bytes.truncate(bytes.len() - 10);
+        match parse_stl(&bytes, plain_material(), None) {
+            Err(LoadError::Format(message)) => assert!(message.contains("truncated")),
+            _ => panic!("expected a truncation error"),
+        }
+    }
+
+    #[test]
+    fn transform() {
+        let transform = Matrix::<f32, 4, 4>::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 5.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let mesh = parse_stl(ASCII.as_bytes(), plain_material(), Some(&transform)).unwrap();
+        let positions = &mesh.buffers().positions;
+        assert!(positions.iter().all(|position| position.z() == 10.0));
+    }
+
+    #[test]
+    fn malformed() {
+        let missing_vertex = ASCII.replace("      vertex 1 0 5\n", "");
+        match parse_stl(missing_vertex.as_bytes(), plain_material(), None) {
+            Err(LoadError::Parse { line: 2, .. }) => {}
+            _ => panic!("expected an error on line 2"),
+        }
+
+        let bad_number = ASCII.replace("vertex 0 1 5", "vertex 0 one 5");
+        match parse_stl(bad_number.as_bytes(), plain_material(), None) {
+            Err(LoadError::Parse { line: 5, .. }) => {}
+            _ => panic!("expected an error on line 5"),
+        }
+
+        let truncated = ASCII.replace("endsolid test\n", "");
+        assert!(parse_stl(truncated.as_bytes(), plain_material(), None).is_err());
+
+        let mut short = binary(&[[0.0; 12]]);
+        short.pop();
+        assert!(parse_stl(&short, plain_material(), None).is_err());
+
+        let nan = binary(&[[f32::NAN; 12]]);
+        assert!(parse_stl(&nan, plain_material(), None).is_err());
+    }
+}
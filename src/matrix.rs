@@ -1,2 +1,3 @@
+#[allow(clippy::module_inception)]
 pub mod matrix;
 pub mod vector;
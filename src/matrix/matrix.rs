@@ -1,16 +1,22 @@
 extern crate num_traits;
 use num_traits::Float;
-//use std::fmt;
-//use std::ops;
 
-#[derive(Debug, PartialEq)]
+use crate::matrix::vector::{Point3D, Vector, Vector3D};
+use std::ops::{Add, Index, IndexMut, Mul, Sub};
+
+// Stored row by row, so data[row][col]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Matrix<T: Float, const ROWS: usize, const COLS: usize> {
-    data: [[T; ROWS]; COLS],
+    data: [[T; COLS]; ROWS],
 }
 
 impl<T: Float, const ROWS: usize, const COLS: usize> Matrix<T, ROWS, COLS> {
+    pub fn new(data: [[T; COLS]; ROWS]) -> Matrix<T, ROWS, COLS> {
+        return Matrix::<T, ROWS, COLS> { data };
+    }
+
     pub fn add(&self, other: &Matrix<T, ROWS, COLS>) -> Matrix<T, ROWS, COLS> {
-        let mut result = [[T::zero(); ROWS]; COLS];
+        let mut result = [[T::zero(); COLS]; ROWS];
         for y in 0..ROWS {
             for x in 0..COLS {
                 result[y][x] = self.data[y][x] + other.data[y][x];
@@ -20,7 +26,7 @@ impl<T: Float, const ROWS: usize, const COLS: usize> Matrix<T, ROWS, COLS> {
     }
 
     pub fn sub(&self, other: &Matrix<T, ROWS, COLS>) -> Matrix<T, ROWS, COLS> {
-        let mut result = [[T::zero(); ROWS]; COLS];
+        let mut result = [[T::zero(); COLS]; ROWS];
         for y in 0..ROWS {
             for x in 0..COLS {
                 result[y][x] = self.data[y][x] - other.data[y][x];
@@ -30,7 +36,7 @@ impl<T: Float, const ROWS: usize, const COLS: usize> Matrix<T, ROWS, COLS> {
     }
 
     pub fn scale(&self, scalar: T) -> Matrix<T, ROWS, COLS> {
-        let mut result = [[T::zero(); ROWS]; COLS];
+        let mut result = [[T::zero(); COLS]; ROWS];
         for y in 0..ROWS {
             for x in 0..COLS {
                 result[y][x] = self.data[y][x] * scalar;
@@ -39,8 +45,14 @@ impl<T: Float, const ROWS: usize, const COLS: usize> Matrix<T, ROWS, COLS> {
         return Matrix::<T, ROWS, COLS> { data: result };
     }
 
+    pub fn zero() -> Matrix<T, ROWS, COLS> {
+        return Matrix::<T, ROWS, COLS> {
+            data: [[T::zero(); COLS]; ROWS],
+        };
+    }
+
     pub fn transpose(&self) -> Matrix<T, COLS, ROWS> {
-        let mut result = [[T::zero(); COLS]; ROWS];
+        let mut result = [[T::zero(); ROWS]; COLS];
         for y in 0..ROWS {
             for x in 0..COLS {
                 result[x][y] = self.data[y][x];
@@ -51,20 +63,29 @@ impl<T: Float, const ROWS: usize, const COLS: usize> Matrix<T, ROWS, COLS> {
 }
 
 // A M by N matrix times a N by K matrix results in a M by K product
-// impl<T: Float, const M: usize, const N: usize, const K: usize> Matrix<T, M, N> {
 impl<T: Float, const M: usize, const N: usize> Matrix<T, M, N> {
     pub fn multiply<const K: usize>(&self, other: Matrix<T, N, K>) -> Matrix<T, M, K> {
-        let mut result = [[T::zero(); M]; K];
+        let mut result = [[T::zero(); K]; M];
 
         for i in 0..M {
             for j in 0..N {
                 for k in 0..K {
-                    result[i][j] = result[i][j] + self.data[i][k] * other.data[k][j];
+                    result[i][k] = result[i][k] + self.data[i][j] * other.data[j][k];
                 }
             }
         }
         return Matrix::<T, M, K> { data: result };
     }
+
+    pub fn multiply_vector(&self, vector: &Vector<T, N>) -> Vector<T, M> {
+        let mut result = [T::zero(); M];
+        for i in 0..M {
+            for j in 0..N {
+                result[i] = result[i] + self.data[i][j] * vector[j];
+            }
+        }
+        return Vector::new(result);
+    }
 }
 
 // Square matrix specific stuff
@@ -76,6 +97,249 @@ impl<T: Float, const DIM: usize> Matrix<T, DIM, DIM> {
         }
         return Matrix::<T, DIM, DIM> { data: result };
     }
+
+    // Whether a pivot is too small next to the matrix's biggest entry to be
+    // told apart from rounding error. determinant and inverse share this, so
+    // a matrix without an inverse always has a determinant of zero.
+    fn negligible_pivot(&self, pivot: T) -> bool {
+        let mut largest = T::zero();
+        for row in 0..DIM {
+            for col in 0..DIM {
+                largest = largest.max(self.data[row][col].abs());
+            }
+        }
+        return pivot.abs() <= largest * T::epsilon() * T::from(DIM).unwrap();
+    }
+
+    // Gaussian elimination with partial pivoting. Each row swap flips the sign.
+    #[allow(dead_code)]
+    pub fn determinant(&self) -> T {
+        let mut rows = self.data;
+        let mut determinant = T::one();
+        for col in 0..DIM {
+            let mut pivot = col;
+            for row in col + 1..DIM {
+                if rows[row][col].abs() > rows[pivot][col].abs() {
+                    pivot = row;
+                }
+            }
+            if self.negligible_pivot(rows[pivot][col]) {
+                return T::zero();
+            }
+            if pivot != col {
+                rows.swap(col, pivot);
+                determinant = -determinant;
+            }
+            determinant = determinant * rows[col][col];
+            for row in col + 1..DIM {
+                let factor = rows[row][col] / rows[col][col];
+                for x in col..DIM {
+                    rows[row][x] = rows[row][x] - factor * rows[col][x];
+                }
+            }
+        }
+        return determinant;
+    }
+
+    // Gauss-Jordan elimination with partial pivoting. Returns None if the
+    // matrix is singular.
+    pub fn inverse(&self) -> Option<Matrix<T, DIM, DIM>> {
+        let mut left = self.data;
+        let mut right = Matrix::<T, DIM, DIM>::identity().data;
+        for col in 0..DIM {
+            // Swap the row with the biggest value in this column into place
+            let mut pivot = col;
+            for row in col + 1..DIM {
+                if left[row][col].abs() > left[pivot][col].abs() {
+                    pivot = row;
+                }
+            }
+            if self.negligible_pivot(left[pivot][col]) {
+                return None;
+            }
+            left.swap(col, pivot);
+            right.swap(col, pivot);
+
+            let scale = T::one() / left[col][col];
+            for x in 0..DIM {
+                left[col][x] = left[col][x] * scale;
+                right[col][x] = right[col][x] * scale;
+            }
+            for row in 0..DIM {
+                if row == col {
+                    continue;
+                }
+                let factor = left[row][col];
+                for x in 0..DIM {
+                    left[row][x] = left[row][x] - factor * left[col][x];
+                    right[row][x] = right[row][x] - factor * right[col][x];
+                }
+            }
+        }
+        return Some(Matrix::<T, DIM, DIM> { data: right });
+    }
+}
+
+// 4x4 matrices act on 3D points and directions as affine transforms
+impl Matrix<f32, 4, 4> {
+    pub fn translation(offset: &Vector3D) -> Matrix<f32, 4, 4> {
+        return Matrix::new([
+            [1.0, 0.0, 0.0, offset.x()],
+            [0.0, 1.0, 0.0, offset.y()],
+            [0.0, 0.0, 1.0, offset.z()],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+    }
+
+    pub fn scaling(scale: &Vector3D) -> Matrix<f32, 4, 4> {
+        return Matrix::new([
+            [scale.x(), 0.0, 0.0, 0.0],
+            [0.0, scale.y(), 0.0, 0.0],
+            [0.0, 0.0, scale.z(), 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+    }
+
+    // Rotates by angle degrees around axis, turning x towards y for the z axis
+    pub fn rotation(axis: &Vector3D, angle: f32) -> Matrix<f32, 4, 4> {
+        let axis = axis.normalized();
+        let (x, y, z) = (axis.x(), axis.y(), axis.z());
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let t = 1.0 - cos;
+        return Matrix::new([
+            [
+                t * x * x + cos,
+                t * x * y - sin * z,
+                t * x * z + sin * y,
+                0.0,
+            ],
+            [
+                t * x * y + sin * z,
+                t * y * y + cos,
+                t * y * z - sin * x,
+                0.0,
+            ],
+            [
+                t * x * z - sin * y,
+                t * y * z + sin * x,
+                t * z * z + cos,
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+    }
+
+    // Places an object at eye with its +z axis pointing at target and its +y
+    // axis as close to up as possible, the same way the camera is oriented.
+    #[allow(dead_code)]
+    pub fn look_at(eye: &Point3D, target: &Point3D, up: &Vector3D) -> Matrix<f32, 4, 4> {
+        let forward = (*target - *eye).normalized();
+        let right = up.cross(&forward).normalized();
+        let up = forward.cross(&right);
+        return Matrix::new([
+            [right.x(), up.x(), forward.x(), eye.x()],
+            [right.y(), up.y(), forward.y(), eye.y()],
+            [right.z(), up.z(), forward.z(), eye.z()],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+    }
+
+    pub fn transform_point(&self, point: &Point3D) -> Point3D {
+        let result = *self * Vector::new([point.x(), point.y(), point.z(), 1.0]);
+        return Point3D::new([result[0], result[1], result[2]]);
+    }
+
+    // Directions ignore the translation part of the transform
+    pub fn transform_vector(&self, vector: &Vector3D) -> Vector3D {
+        let result = *self * Vector::new([vector.x(), vector.y(), vector.z(), 0.0]);
+        return Vector3D::new([result[0], result[1], result[2]]);
+    }
+}
+
+impl<T: Float, const ROWS: usize, const COLS: usize> Index<(usize, usize)>
+    for Matrix<T, ROWS, COLS>
+{
+    type Output = T;
+
+    fn index(&self, (row, col): (usize, usize)) -> &T {
+        return &self.data[row][col];
+    }
+}
+
+impl<T: Float, const ROWS: usize, const COLS: usize> IndexMut<(usize, usize)>
+    for Matrix<T, ROWS, COLS>
+{
+    fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut T {
+        return &mut self.data[row][col];
+    }
+}
+
+// Matrix Addition
+impl<T: Float, const ROWS: usize, const COLS: usize> Add for Matrix<T, ROWS, COLS> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        return Matrix::add(&self, &other);
+    }
+}
+
+// Matrix Subtraction
+impl<T: Float, const ROWS: usize, const COLS: usize> Sub for Matrix<T, ROWS, COLS> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        return Matrix::sub(&self, &other);
+    }
+}
+
+// Matrix Multiplication
+impl<T: Float, const M: usize, const N: usize, const K: usize> Mul<Matrix<T, N, K>>
+    for Matrix<T, M, N>
+{
+    type Output = Matrix<T, M, K>;
+
+    fn mul(self, other: Matrix<T, N, K>) -> Matrix<T, M, K> {
+        return self.multiply(other);
+    }
+}
+
+// Matrix times column vector
+impl<T: Float, const M: usize, const N: usize> Mul<Vector<T, N>> for Matrix<T, M, N> {
+    type Output = Vector<T, M>;
+
+    fn mul(self, other: Vector<T, N>) -> Vector<T, M> {
+        return self.multiply_vector(&other);
+    }
+}
+
+// Scalar Multiplication
+impl<T: Float, const ROWS: usize, const COLS: usize> Mul<T> for Matrix<T, ROWS, COLS> {
+    type Output = Self;
+
+    fn mul(self, other: T) -> Self {
+        return self.scale(other);
+    }
+}
+
+// Vectors convert to and from single column matrices
+impl<T: Float, const DIM: usize> From<Vector<T, DIM>> for Matrix<T, DIM, 1> {
+    fn from(vector: Vector<T, DIM>) -> Self {
+        let mut result = [[T::zero(); 1]; DIM];
+        for i in 0..DIM {
+            result[i][0] = vector[i];
+        }
+        return Matrix::<T, DIM, 1> { data: result };
+    }
+}
+
+impl<T: Float, const DIM: usize> From<Matrix<T, DIM, 1>> for Vector<T, DIM> {
+    fn from(matrix: Matrix<T, DIM, 1>) -> Self {
+        let mut result = [T::zero(); DIM];
+        for i in 0..DIM {
+            result[i] = matrix.data[i][0];
+        }
+        return Vector::new(result);
+    }
 }
 
 #[cfg(test)]
@@ -103,4 +367,152 @@ mod test {
             }
         );
     }
+
+    fn assert_close(a: &Vector3D, b: &Vector3D) {
+        assert!((*a - *b).norm() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn multiply() {
+        let a = Matrix::<f32, 2, 2>::new([[1.0, 2.0], [3.0, 4.0]]);
+        let b = Matrix::<f32, 2, 2>::new([[5.0, 6.0], [7.0, 8.0]]);
+        assert_eq!(
+            a.multiply(b),
+            Matrix::<f32, 2, 2>::new([[19.0, 22.0], [43.0, 50.0]])
+        );
+
+        // A 2 by 3 times a 3 by 2 gives a 2 by 2
+        let a = Matrix::<f32, 2, 3>::new([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]);
+        let b = Matrix::<f32, 3, 2>::new([[7.0, 8.0], [9.0, 10.0], [11.0, 12.0]]);
+        assert_eq!(
+            a * b,
+            Matrix::<f32, 2, 2>::new([[58.0, 64.0], [139.0, 154.0]])
+        );
+        assert_eq!(b * a * 2.0, (b * a).scale(2.0));
+    }
+
+    #[test]
+    fn add_sub_transpose() {
+        let a = Matrix::<f32, 2, 3>::new([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]);
+        let b = Matrix::<f32, 2, 3>::new([[6.0, 5.0, 4.0], [3.0, 2.0, 1.0]]);
+        assert_eq!(a + b, Matrix::<f32, 2, 3>::new([[7.0; 3]; 2]));
+        assert_eq!(a - a, Matrix::<f32, 2, 3>::zero());
+        assert_eq!(
+            a.transpose(),
+            Matrix::<f32, 3, 2>::new([[1.0, 4.0], [2.0, 5.0], [3.0, 6.0]])
+        );
+        assert_eq!(a[(1, 2)], 6.0);
+    }
+
+    #[test]
+    fn vectors() {
+        let a = Matrix::<f32, 2, 3>::new([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]);
+        let vec = Vector3D::new([1.0, 0.0, -1.0]);
+        assert_eq!(a * vec, Vector::<f32, 2>::new([-2.0, -2.0]));
+
+        let column = Matrix::<f32, 3, 1>::from(vec);
+        assert_eq!(
+            column.transpose(),
+            Matrix::<f32, 1, 3>::new([[1.0, 0.0, -1.0]])
+        );
+        assert_eq!(Vector3D::from(column), vec);
+        assert_eq!(Vector::<f32, 2>::from(a * column), a * vec);
+    }
+
+    #[test]
+    fn determinant() {
+        assert_eq!(Matrix::<f32, 3, 3>::identity().determinant(), 1.0);
+        let a = Matrix::<f64, 3, 3>::new([[2.0, 0.0, 1.0], [1.0, 1.0, 0.0], [0.0, 3.0, 1.0]]);
+        assert!((a.determinant() - 5.0).abs() < 1e-12);
+        let swapped = Matrix::<f64, 2, 2>::new([[0.0, 1.0], [1.0, 0.0]]);
+        assert_eq!(swapped.determinant(), -1.0);
+        let singular = Matrix::<f32, 2, 2>::new([[1.0, 2.0], [2.0, 4.0]]);
+        assert_eq!(singular.determinant(), 0.0);
+        // Elimination leaves rounding error behind instead of an exact zero
+        let nearly = Matrix::<f32, 3, 3>::new([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0], [7.0, 8.0, 9.0]]);
+        assert_eq!(nearly.determinant(), 0.0);
+        assert_eq!(nearly.inverse(), None);
+    }
+
+    #[test]
+    fn inverse() {
+        let a = Matrix::<f32, 3, 3>::new([[2.0, 0.0, 1.0], [1.0, 1.0, 0.0], [0.0, 3.0, 1.0]]);
+        let inverse = a.inverse().unwrap();
+        let product = a.multiply(inverse);
+        for row in 0..3 {
+            for col in 0..3 {
+                let expected = if row == col { 1.0 } else { 0.0 };
+                assert!((product.data[row][col] - expected).abs() < 1e-6);
+            }
+        }
+        let singular = Matrix::<f32, 2, 2>::new([[1.0, 2.0], [2.0, 4.0]]);
+        assert_eq!(singular.inverse(), None);
+        // Tiny matrices are only singular if they are for their size
+        let tiny = Matrix::<f32, 2, 2>::new([[1e-9, 0.0], [0.0, 1e-9]]);
+        assert!(tiny.inverse().is_some());
+        assert!(tiny.determinant() > 0.0);
+
+        let transform = Matrix::translation(&Vector3D::new([1.0, 2.0, 3.0]))
+            * Matrix::rotation(&Vector3D::new([1.0, 1.0, 0.0]), 30.0)
+            * Matrix::scaling(&Vector3D::new([2.0, 3.0, 4.0]));
+        let point = Point3D::new([-1.0, 5.0, 2.0]);
+        let round_trip = transform
+            .inverse()
+            .unwrap()
+            .transform_point(&transform.transform_point(&point));
+        assert_close(&round_trip, &point);
+        assert!((transform.determinant() - 24.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn transforms() {
+        let point = Point3D::new([1.0, 0.0, 0.0]);
+        let rotation = Matrix::rotation(&Vector3D::new([0.0, 0.0, 1.0]), 90.0);
+        assert_close(
+            &rotation.transform_point(&point),
+            &Point3D::new([0.0, 1.0, 0.0]),
+        );
+
+        let moved = Matrix::translation(&Vector3D::new([0.0, 0.0, 5.0]))
+            .multiply(Matrix::scaling(&Vector3D::new([2.0, 2.0, 2.0])));
+        assert_close(
+            &moved.transform_point(&point),
+            &Point3D::new([2.0, 0.0, 5.0]),
+        );
+        assert_close(
+            &moved.transform_vector(&point),
+            &Vector3D::new([2.0, 0.0, 0.0]),
+        );
+
+        let eye = Point3D::new([1.0, 2.0, 3.0]);
+        let look = Matrix::look_at(
+            &eye,
+            &Point3D::new([1.0, 2.0, 10.0]),
+            &Vector3D::new([0.0, 1.0, 0.0]),
+        );
+        assert_close(&look.transform_point(&Point3D::zero()), &eye);
+        assert_close(
+            &look.transform_vector(&point),
+            &Vector3D::new([1.0, 0.0, 0.0]),
+        );
+    }
+
+    #[test]
+    fn transform_point() {
+        let transform = Matrix::<f32, 4, 4>::new([
+            [2.0, 0.0, 0.0, 1.0],
+            [0.0, 0.0, -1.0, 2.0],
+            [0.0, 1.0, 0.0, 3.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let vec = Vector3D::new([1.0, 2.0, 3.0]);
+        assert_eq!(
+            transform.transform_point(&vec),
+            Point3D::new([3.0, -1.0, 5.0])
+        );
+        assert_eq!(
+            transform.transform_vector(&vec),
+            Vector3D::new([2.0, -3.0, 2.0])
+        );
+    }
 }
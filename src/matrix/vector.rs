@@ -1,10 +1,10 @@
 extern crate num_traits;
 use num_traits::Float;
 use std::fmt;
-use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::ops::{Add, Div, Index, Mul, Neg, Sub};
 
 pub type Vector3D = Vector<f32, 3>;
-// pub type Vector2D = Vector<f32, 2>;
+pub type Vector2D = Vector<f32, 2>;
 pub type Point3D = Vector3D;
 // pub type Point2D = Vector2D;
 
@@ -97,6 +97,32 @@ impl<T: Float, const DIM: usize> Vector<T, DIM> {
         }
         return Self::new(result);
     }
+
+    // Component-wise minimum
+    pub fn min(&self, other: &Self) -> Vector<T, DIM> {
+        let mut result = [T::zero(); DIM];
+        for i in 0..DIM {
+            result[i] = self.data[i].min(other.data[i]);
+        }
+        return Self::new(result);
+    }
+
+    // Component-wise maximum
+    pub fn max(&self, other: &Self) -> Vector<T, DIM> {
+        let mut result = [T::zero(); DIM];
+        for i in 0..DIM {
+            result[i] = self.data[i].max(other.data[i]);
+        }
+        return Self::new(result);
+    }
+}
+
+impl<T: Float, const DIM: usize> Index<usize> for Vector<T, DIM> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        return &self.data[index];
+    }
 }
 
 // Vector Addition
@@ -252,6 +278,15 @@ mod test {
         );
     }
 
+    #[test]
+    fn min_max() {
+        let vec1 = Vector3D::new([1.0, 5.0, -3.0]);
+        let vec2 = Vector3D::new([4.0, 2.0, -6.0]);
+        assert_eq!(vec1.min(&vec2), Vector3D::new([1.0, 2.0, -6.0]));
+        assert_eq!(vec1.max(&vec2), Vector3D::new([4.0, 5.0, -3.0]));
+        assert_eq!(vec1[1], 5.0);
+    }
+
     #[test]
     fn dot() {
         let vec1 = Vector3D {
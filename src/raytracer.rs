@@ -1,33 +1,65 @@
-// extern crate num_cpus;
-// extern crate rayon;
+extern crate num_cpus;
+extern crate rayon;
 
-use std::rc::Rc;
-// use std::thread;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
 
 use crate::image::Color;
+use crate::image::Format;
 use crate::image::Image;
 use crate::matrix::vector::Point3D;
 use crate::matrix::vector::Vector3D;
 
-use crate::raytracer::geometry::Lights;
-use geometry::Geometry;
+use crate::raytracer::light::Lights;
+use differential::RayDifferential;
 use geometry::Ray;
-use geometry::Rayhit;
-
+use integrator::Integrator;
+use sampling::Rng;
+use scene::Scene;
+use tile::Tile;
+
+pub mod bsdf;
+pub mod differential;
+pub mod environment;
 pub mod geometry;
+pub mod integrator;
+pub mod light;
+pub mod refraction;
+pub mod sampling;
+pub mod scene;
+pub mod sky;
+pub mod spectrum;
+pub mod texture;
+pub mod tile;
 
 // Some coordinate ground rules:
 // x is east/west, y is up/down, z is north/south
 
-const AMBIENT: f32 = 0.2;
+const TILE_SIZE: u32 = 32;
 
-#[allow(dead_code)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Antialiasing {
     Off,
     Grid(u32),
 }
 
+// Which integrator to render with, and its settings
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Shading {
+    // Direct light, perfect reflections and a flat ambient term
+    Whitted,
+    // Monte Carlo path tracing with this many samples per pixel
+    PathTraced(u32),
+    // Path tracing where each path carries one wavelength of light
+    Spectral(u32),
+    AmbientOcclusion { samples: u32, distance: f32 },
+    // Debug views
+    Normals,
+    Depth { far: f32 },
+    Objects,
+}
+
 pub struct Raytracer {
     origin: Point3D,
     center: Point3D,
@@ -37,6 +69,8 @@ pub struct Raytracer {
     plane_height: f32,
     img: Image,
     aa: Antialiasing,
+    exposure: f32,
+    threads: usize,
 }
 
 pub struct Camera {
@@ -44,16 +78,11 @@ pub struct Camera {
     pub look: Vector3D,     // The direction to look
     pub up: Vector3D,       // Which direction is up on the screen. Must be orthagonal to look
     pub fov: f32,           // FOV of the resulting image.
+    pub exposure: f32,      // Scales the light reaching the camera into pixel values
 }
 
 pub fn clamp(input: f32) -> f32 {
-    return if input < 0.0 {
-        0.0
-    } else if input > 1.0 {
-        1.0
-    } else {
-        input
-    };
+    return input.clamp(0.0, 1.0);
 }
 
 impl Raytracer {
@@ -73,148 +102,13 @@ impl Raytracer {
             plane_height,
             img,
             aa,
+            exposure: cam.exposure,
+            threads: num_cpus::get(),
         };
     }
 
-    pub fn shade(
-        ray: &Ray,
-        hit: &Rayhit,
-        scene: &Vec<Rc<dyn Geometry>>,
-        lights: &Lights,
-        reflections: u32,
-    ) -> (Color, u32) {
-        let mut color = Color::new(0, 0, 0, 0);
-        let mut ray_count = 1;
-
-        let reflect = ray.direction - hit.normal * (ray.direction * hit.normal) * 2.0;
-        for light_source in &lights.sources {
-            let to_light = light_source.source.clone() - hit.pos;
-            let dist_to_light = to_light.norm();
-            let to_light = to_light * (1.0 / dist_to_light);
-            let ray_to_light = Ray {
-                direction: to_light,
-                origin: hit.pos,
-            };
-
-            let half_angle = (to_light - ray.direction).normalized();
-
-            let mut light_amount = 1.0;
-            // How much of the light reaches the hit position?
-            if hit.dist.is_finite() {
-                for object in scene.iter() {
-                    // Don't let an object cast a shadow on itself
-                    if (&*hit.obj as *const dyn Geometry as *const ())
-                        == (&**object as *const dyn Geometry as *const ())
-                    {
-                        continue;
-                    }
-                    match Rc::clone(object).intersect(&ray_to_light, dist_to_light) {
-                        Some(shadow_hit) => {
-                            // Some light passes through transparent objects
-                            light_amount *= 1.0 - shadow_hit.material.color.a;
-                            if light_amount <= 0.0 {
-                                light_amount = 0.0;
-                                break;
-                            }
-                        }
-                        None => {}
-                    }
-                }
-            } else {
-                color = hit.material.color;
-                continue;
-            }
-
-            let mixed_color = light_source.color * hit.material.color;
-
-            // TODO: Make this section look less gross
-            let light_ambient = mixed_color * AMBIENT; //TODO: Make a parameter for the raytracer.
-            let light_diffuse =
-                mixed_color * (clamp(hit.normal * to_light) * light_amount * hit.material.diffuse);
-            let light_specular = light_source.color
-                * (f32::powi(clamp(hit.normal * half_angle), hit.material.specular_n)
-                    * light_amount
-                    * hit.material.specular);
-
-            color =
-                color + ((light_ambient + light_diffuse + light_specular) * light_source.intensity)
-        }
-
-        assert_ne!(reflections, 0);
-        let light_reflected = if reflections > 0 && hit.material.reflectivity > 0.0 {
-            let (reflected_color, reflected_rays) = Raytracer::trace(
-                &Ray {
-                    direction: reflect,
-                    origin: hit.pos,
-                },
-                scene,
-                lights,
-                reflections - 1,
-                Some(Rc::clone(&hit.obj)),
-            );
-            ray_count += reflected_rays;
-            reflected_color
-        } else {
-            hit.material.color
-        } * hit.material.reflectivity;
-        let light_transparent = if hit.material.color.a < 1.0 {
-            let (passthrough_color, passthrough_rays) = Raytracer::trace(
-                &Ray {
-                    direction: ray.direction,
-                    origin: hit.pos,
-                },
-                scene,
-                lights,
-                reflections,
-                Some(Rc::clone(&hit.obj)),
-            );
-            ray_count += passthrough_rays;
-            passthrough_color
-        } else {
-            Color::new(0, 0, 0, 0)
-        } * (1.0 - hit.material.color.a);
-        // color = color.overlay(passthrough_color);
-        // println!("light intensity: {}", total_intensity);
-
-        return (
-            color * (1.0 / lights.total_intensity) + light_reflected + light_transparent,
-            ray_count,
-        );
-    }
-
-    pub fn trace(
-        ray: &Ray,
-        scene: &Vec<Rc<dyn Geometry>>,
-        lights: &Lights,
-        reflections: u32,
-        ignore: Option<Rc<dyn Geometry>>,
-    ) -> (Color, u32) {
-        let mut closest_hit: Option<Rayhit> = None;
-        let mut closest_dist = f32::INFINITY;
-        for object in scene.iter() {
-            match &ignore {
-                Some(ignore) => {
-                    if (&**ignore as *const dyn Geometry as *const ())
-                        == (&**object as *const dyn Geometry as *const ())
-                    {
-                        continue;
-                    }
-                }
-                None => {}
-            }
-            match Rc::clone(object).intersect(ray, closest_dist) {
-                Some(hit) => {
-                    closest_dist = hit.dist;
-                    closest_hit = Some(hit);
-                }
-                None => {}
-            }
-        }
-
-        return match closest_hit {
-            Some(hit) => Raytracer::shade(ray, &hit, scene, lights, reflections),
-            None => (Color::new(0, 0, 0, 0), 1),
-        };
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
     }
 
     pub fn get_ray(&self, x: f32, y: f32) -> Ray {
@@ -226,28 +120,67 @@ impl Raytracer {
         };
     }
 
-    pub fn render(&mut self, scene: &Vec<Rc<dyn Geometry>>, lights: &Lights, reflections: u32) {
-        println!("Rendering Scene...");
+    // The rays spacing pixels to the right of and below get_ray(x, y), for
+    // measuring how much of a surface each of its samples covers
+    pub fn get_differential(&self, x: f32, y: f32, spacing: f32) -> RayDifferential {
+        let right = self.get_ray(x + spacing, y);
+        let below = self.get_ray(x, y + spacing);
+        return RayDifferential {
+            x_origin: right.origin,
+            x_direction: right.direction.normalized(),
+            y_origin: below.origin,
+            y_direction: below.direction.normalized(),
+        };
+    }
+
+    pub fn render(&mut self, scene: &Scene, lights: &Lights, integrator: &dyn Integrator) {
+        println!("Rendering Scene with {} objects...", scene.len());
         use std::time::Instant;
         let now = Instant::now();
 
-        // let num_threads = num_cpus::get();
-        // let thread_pool = rayon::ThreadPoolBuilder::new()
-        //     .num_threads(num_threads)
-        //     .build()
-        //     .unwrap();
-
-        // for (x,y) in (0..self.img.get_width()).flat_map(move |a| (0..self.img.get_height()).map(move |b| (a, b))) {
-        //     self.render_pixel(x, y, scene, light, reflections);
-        // }
+        let num_threads = self.threads;
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
 
+        let tiles = tile::hilbert_tiles(self.img.get_width(), self.img.get_height(), TILE_SIZE);
+        let next_tile = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        let mut img = Image::new_like(&self.img);
         let mut ray_count = 0;
 
-        for y in 0..self.img.get_height() {
-            for x in 0..self.img.get_width() {
-                ray_count = ray_count + self.render_pixel(x, y, scene, lights, reflections);
+        let raytracer = &*self;
+        thread_pool.in_place_scope(|scope| {
+            // Each worker grabs the next tile along the curve until there are none left
+            for _ in 0..num_threads {
+                let sender = sender.clone();
+                let tiles = &tiles;
+                let next_tile = &next_tile;
+                scope.spawn(move |_| loop {
+                    let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                    if index >= tiles.len() {
+                        break;
+                    }
+                    let tile = tiles[index];
+                    let result = raytracer.render_tile(&tile, scene, lights, integrator);
+                    sender.send((tile, result)).unwrap();
+                });
             }
-        }
+            drop(sender);
+
+            // Copy finished tiles into the image as they come in
+            for (tile, (pixels, rays)) in receiver {
+                for y in 0..tile.height {
+                    for x in 0..tile.width {
+                        let color = pixels[(x + y * tile.width) as usize];
+                        img.set_pixelu32(tile.x + x, tile.y + y, color);
+                    }
+                }
+                ray_count += rays;
+            }
+        });
+        self.img = img;
 
         let elapsed = now.elapsed();
         println!(
@@ -256,22 +189,85 @@ impl Raytracer {
         );
     }
 
+    pub fn render_tile(
+        &self,
+        tile: &Tile,
+        scene: &Scene,
+        lights: &Lights,
+        integrator: &dyn Integrator,
+    ) -> (Vec<Color>, u32) {
+        let mut pixels = Vec::with_capacity((tile.width * tile.height) as usize);
+        let mut ray_count = 0;
+        for y in tile.y..tile.y + tile.height {
+            for x in tile.x..tile.x + tile.width {
+                let (color, rays) = self.render_pixel(x, y, scene, lights, integrator);
+                pixels.push(color);
+                ray_count += rays;
+            }
+        }
+        return (pixels, ray_count);
+    }
+
     pub fn render_pixel(
-        &mut self,
+        &self,
         x: u32,
         y: u32,
-        scene: &Vec<Rc<dyn Geometry>>,
+        scene: &Scene,
         lights: &Lights,
-        reflections: u32,
-    ) -> u32 {
-        let (color, ray_count) = match &self.aa {
-            Antialiasing::Off => Raytracer::trace(
-                &self.get_ray(x as f32, y as f32),
-                scene,
-                lights,
-                reflections,
-                None,
-            ),
+        integrator: &dyn Integrator,
+    ) -> (Color, u32) {
+        let (color, ray_count) = self.sample_pixel(x, y, scene, lights, integrator);
+        if integrator.measures_light() {
+            return (color * self.exposure, ray_count);
+        }
+        return (color, ray_count);
+    }
+
+    fn sample_pixel(
+        &self,
+        x: u32,
+        y: u32,
+        scene: &Scene,
+        lights: &Lights,
+        integrator: &dyn Integrator,
+    ) -> (Color, u32) {
+        // Seeded by the pixel so renders come out the same every time
+        let mut rng = Rng::new((y * self.img.get_width() + x) as u64, 0);
+
+        // Random samples antialias the pixel as well, so the grid isn't used
+        if let Some(samples) = integrator.samples_per_pixel() {
+            // Each sample only has to cover its share of the pixel, but
+            // textures blur less than that so a few samples still look sharp
+            let spacing = (1.0 / (samples as f32).sqrt()).max(0.125);
+            let mut color = Color::new(0, 0, 0, 0);
+            let mut ray_count = 0;
+            for _ in 0..samples {
+                let sample_x = x as f32 + rng.next_f32() - 0.5;
+                let sample_y = y as f32 + rng.next_f32() - 0.5;
+                let ray = self.get_ray(sample_x, sample_y);
+                let differential = self.get_differential(sample_x, sample_y, spacing);
+                let (sample, rays) =
+                    integrator.trace(&ray, Some(&differential), scene, lights, &mut rng);
+                color = color + sample;
+                ray_count += rays;
+            }
+            let mut color = color * (1.0 / samples as f32);
+            color.a = 1.0;
+            return (color, ray_count);
+        }
+
+        return match &self.aa {
+            Antialiasing::Off => {
+                let (x, y) = (x as f32, y as f32);
+                let differential = self.get_differential(x, y, 1.0);
+                integrator.trace(
+                    &self.get_ray(x, y),
+                    Some(&differential),
+                    scene,
+                    lights,
+                    &mut rng,
+                )
+            }
 
             Antialiasing::Grid(size) => {
                 let sub_step = 1.0 / *size as f32;
@@ -280,15 +276,15 @@ impl Raytracer {
                 let mut ray_count = 0;
                 for sub_x in 0..*size {
                     for sub_y in 0..*size {
-                        let (sample, rays) = Raytracer::trace(
-                            &self.get_ray(
-                                x as f32 + offset + sub_step * sub_x as f32,
-                                y as f32 + offset + sub_step * sub_y as f32,
-                            ),
+                        let sample_x = x as f32 + offset + sub_step * sub_x as f32;
+                        let sample_y = y as f32 + offset + sub_step * sub_y as f32;
+                        let differential = self.get_differential(sample_x, sample_y, sub_step);
+                        let (sample, rays) = integrator.trace(
+                            &self.get_ray(sample_x, sample_y),
+                            Some(&differential),
                             scene,
                             lights,
-                            reflections,
-                            None,
+                            &mut rng,
                         );
                         color = color + sample;
                         ray_count += rays;
@@ -297,12 +293,10 @@ impl Raytracer {
                 (color * (1.0 / (size * size) as f32), ray_count)
             }
         };
-        self.img.set_pixelu32(x, y, color);
-        return ray_count;
     }
 
-    pub fn save(&self, str: &String) {
-        self.img.save(str);
+    pub fn save(&self, str: &String, format: Format) -> io::Result<()> {
+        return self.img.save_as(str, format);
     }
 }
 
@@ -312,7 +306,6 @@ pub struct Anaglyph {
     img: Image,
 }
 
-#[allow(dead_code)]
 impl Anaglyph {
     pub fn new(cam: &Camera, img: Image, aa: Antialiasing, ipd: f32) -> Anaglyph {
         let right_vector = cam.look.cross(&cam.up).scale(-1.0).normalized();
@@ -321,21 +314,28 @@ impl Anaglyph {
             look: cam.look,
             up: cam.up,
             fov: cam.fov,
+            exposure: cam.exposure,
         };
         let right_eye = Camera {
             position: cam.position + (right_vector * (ipd / 2.0)),
             look: cam.look,
             up: cam.up,
             fov: cam.fov,
+            exposure: cam.exposure,
         };
         let left = Raytracer::new(&left_eye, Image::new_like(&img), aa);
         let right = Raytracer::new(&right_eye, Image::new_like(&img), aa);
         return Anaglyph { left, right, img };
     }
 
-    pub fn render(&mut self, scene: &Vec<Rc<dyn Geometry>>, lights: &Lights, reflections: u32) {
-        self.left.render(scene, lights, reflections);
-        self.right.render(scene, lights, reflections);
+    pub fn set_threads(&mut self, threads: usize) {
+        self.left.set_threads(threads);
+        self.right.set_threads(threads);
+    }
+
+    pub fn render(&mut self, scene: &Scene, lights: &Lights, integrator: &dyn Integrator) {
+        self.left.render(scene, lights, integrator);
+        self.right.render(scene, lights, integrator);
         let left_filter = Color::new(0, 255, 255, 255);
         let right_filter = Color::new(255, 0, 0, 255);
         for y in 0..self.img.get_height() {
@@ -347,7 +347,7 @@ impl Anaglyph {
         }
     }
 
-    pub fn save(&self, str: &String) {
-        self.img.save(str);
+    pub fn save(&self, str: &String, format: Format) -> io::Result<()> {
+        return self.img.save_as(str, format);
     }
 }
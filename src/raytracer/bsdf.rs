@@ -0,0 +1,532 @@
+use std::f32::consts::PI;
+
+use crate::image::Color;
+use crate::matrix::vector::Vector3D;
+use crate::raytracer::clamp;
+use crate::raytracer::refraction::{fresnel, refract};
+use crate::raytracer::sampling;
+use crate::raytracer::sampling::Rng;
+
+// Below this GGX roughness microfacet lobes are too sharp to evaluate, so
+// surfaces are treated as perfectly smooth
+const MIN_ALPHA: f32 = 1e-3;
+
+// The kinds of surface a material can have in place of the Phong mix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BsdfModel {
+    Lambertian,
+    Conductor,
+    Dielectric,
+    Mirror,
+}
+
+impl BsdfModel {
+    pub fn from_name(name: &str) -> Option<BsdfModel> {
+        return match name {
+            "lambertian" => Some(BsdfModel::Lambertian),
+            "conductor" => Some(BsdfModel::Conductor),
+            "dielectric" => Some(BsdfModel::Dielectric),
+            "mirror" => Some(BsdfModel::Mirror),
+            _ => None,
+        };
+    }
+
+    // color tints the surface, roughness goes from 0 for polished to 1, and
+    // eta is the index of refraction on the far side of the surface over
+    // the one on the near side
+    pub fn bsdf(self, color: Color, roughness: f32, eta: f32) -> Box<dyn Bsdf> {
+        // Roughness squared looks evenly spaced from smooth to rough
+        let alpha = roughness * roughness;
+        return match self {
+            BsdfModel::Lambertian => Box::new(Lambertian { color }),
+            BsdfModel::Conductor if alpha < MIN_ALPHA => Box::new(Mirror { color }),
+            BsdfModel::Conductor => Box::new(Conductor { color, alpha }),
+            BsdfModel::Dielectric => Box::new(Dielectric { color, alpha, eta }),
+            BsdfModel::Mirror => Box::new(Mirror { color }),
+        };
+    }
+}
+
+// A direction picked by a bsdf
+pub struct BsdfSample {
+    pub direction: Vector3D,
+    // The bsdf times the cosine to the normal over the pdf, which is what
+    // light arriving from direction gets multiplied by
+    pub weight: Color,
+    // Per steradian, or for specular samples just the chance of picking
+    // this direction over the others the surface could send light
+    pub pdf: f32,
+    // Whether the surface only sends light this one way, like a mirror.
+    // evaluate and pdf can never find these directions.
+    pub specular: bool,
+}
+
+// How a surface scatters light. Directions are unit vectors pointing away
+// from the surface: wo towards where the light goes, back along the ray
+// that hit it, and wi towards where it came from. normal faces the side
+// wo is on. Both sides of a surface act the same unless light can cross it.
+pub trait Bsdf {
+    // How much of the light arriving from wi leaves towards wo, per
+    // steradian
+    fn evaluate(&self, wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> Color;
+
+    // Picks a direction for wi, favouring the ones that send most light
+    // towards wo. Returns None if the surface absorbs the light.
+    fn sample(&self, wo: &Vector3D, normal: &Vector3D, rng: &mut Rng) -> Option<BsdfSample>;
+
+    // The chance of sample picking wi, per steradian
+    fn pdf(&self, wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> f32;
+
+    // Every direction a perfectly smooth surface sends light towards wo
+    // from, with how much of it. Whitted shading follows all of them
+    // rather than picking one.
+    fn specular_directions(&self, _wo: &Vector3D, _normal: &Vector3D) -> Vec<(Vector3D, Color)> {
+        return Vec::new();
+    }
+}
+
+fn white() -> Color {
+    return Color::new(255, 255, 255, 255);
+}
+
+fn reflect(wo: &Vector3D, normal: &Vector3D) -> Vector3D {
+    return *normal * (2.0 * (*wo * *normal)) - *wo;
+}
+
+// Schlick's approximation of how much a metal reflects, from its color
+// head on. Everything reflects white at grazing angles.
+fn schlick(color: Color, cos_theta: f32) -> Color {
+    let grazing = f32::powi(1.0 - clamp(cos_theta), 5);
+    return color * (1.0 - grazing) + white() * grazing;
+}
+
+// The GGX, or Trowbridge-Reitz, distribution of microfacet normals, with
+// Smith's masking function for how they hide each other
+struct Ggx {
+    alpha: f32,
+}
+
+impl Ggx {
+    // How many microfacets face a direction at cos_h to the normal, per
+    // steradian
+    fn distribution(&self, cos_h: f32) -> f32 {
+        if cos_h <= 0.0 {
+            return 0.0;
+        }
+        let alpha2 = self.alpha * self.alpha;
+        let denominator = cos_h * cos_h * (alpha2 - 1.0) + 1.0;
+        return alpha2 / (PI * denominator * denominator);
+    }
+
+    // How much of the surface seen from cos_theta to the normal isn't
+    // hidden behind other microfacets
+    fn masking(&self, cos_theta: f32) -> f32 {
+        let cos_theta = cos_theta.abs();
+        let alpha2 = self.alpha * self.alpha;
+        return 2.0 * cos_theta
+            / (cos_theta + f32::sqrt(alpha2 + (1.0 - alpha2) * cos_theta * cos_theta));
+    }
+
+    fn shadowing(&self, cos_o: f32, cos_i: f32) -> f32 {
+        return self.masking(cos_o) * self.masking(cos_i);
+    }
+
+    // The chance of sampling::ggx_normal picking a microfacet normal
+    fn pdf(&self, cos_h: f32) -> f32 {
+        return self.distribution(cos_h) * cos_h;
+    }
+}
+
+// A perfectly matte surface, which looks the same from every direction
+pub struct Lambertian {
+    pub color: Color,
+}
+
+impl Bsdf for Lambertian {
+    fn evaluate(&self, _wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> Color {
+        if *wi * *normal <= 0.0 {
+            return Color::new(0, 0, 0, 0);
+        }
+        return self.color * (1.0 / PI);
+    }
+
+    fn sample(&self, _wo: &Vector3D, normal: &Vector3D, rng: &mut Rng) -> Option<BsdfSample> {
+        let direction = sampling::cosine_hemisphere(normal, rng.next_f32(), rng.next_f32());
+        let cos_theta = direction * *normal;
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        return Some(BsdfSample {
+            direction,
+            weight: self.color,
+            pdf: cos_theta / PI,
+            specular: false,
+        });
+    }
+
+    fn pdf(&self, _wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> f32 {
+        return clamp(*wi * *normal) / PI;
+    }
+}
+
+// A perfect mirror, tinted by color
+pub struct Mirror {
+    pub color: Color,
+}
+
+impl Bsdf for Mirror {
+    fn evaluate(&self, _wo: &Vector3D, _wi: &Vector3D, _normal: &Vector3D) -> Color {
+        return Color::new(0, 0, 0, 0);
+    }
+
+    fn sample(&self, wo: &Vector3D, normal: &Vector3D, _rng: &mut Rng) -> Option<BsdfSample> {
+        return Some(BsdfSample {
+            direction: reflect(wo, normal),
+            weight: self.color,
+            pdf: 1.0,
+            specular: true,
+        });
+    }
+
+    fn pdf(&self, _wo: &Vector3D, _wi: &Vector3D, _normal: &Vector3D) -> f32 {
+        return 0.0;
+    }
+
+    fn specular_directions(&self, wo: &Vector3D, normal: &Vector3D) -> Vec<(Vector3D, Color)> {
+        return vec![(reflect(wo, normal), self.color)];
+    }
+}
+
+// A rough metal made of tiny mirrors, whose color is what it reflects head
+// on. alpha is the GGX roughness.
+pub struct Conductor {
+    pub color: Color,
+    pub alpha: f32,
+}
+
+impl Bsdf for Conductor {
+    fn evaluate(&self, wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> Color {
+        let (cos_o, cos_i) = (*wo * *normal, *wi * *normal);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return Color::new(0, 0, 0, 0);
+        }
+        let half = (*wo + *wi).normalized();
+        let ggx = Ggx { alpha: self.alpha };
+        let amount =
+            ggx.distribution(half * *normal) * ggx.shadowing(cos_o, cos_i) / (4.0 * cos_o * cos_i);
+        return schlick(self.color, *wi * half) * amount;
+    }
+
+    fn sample(&self, wo: &Vector3D, normal: &Vector3D, rng: &mut Rng) -> Option<BsdfSample> {
+        let half = sampling::ggx_normal(normal, self.alpha, rng.next_f32(), rng.next_f32());
+        let direction = reflect(wo, &half);
+        let cos_i = direction * *normal;
+        let pdf = self.pdf(wo, &direction, normal);
+        if cos_i <= 0.0 || pdf <= 0.0 {
+            return None;
+        }
+        return Some(BsdfSample {
+            direction,
+            weight: self.evaluate(wo, &direction, normal) * (cos_i / pdf),
+            pdf,
+            specular: false,
+        });
+    }
+
+    fn pdf(&self, wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> f32 {
+        if *wo * *normal <= 0.0 || *wi * *normal <= 0.0 {
+            return 0.0;
+        }
+        // Reflecting off a microfacet squeezes the directions by 4 cos
+        let half = (*wo + *wi).normalized();
+        let ggx = Ggx { alpha: self.alpha };
+        return ggx.pdf(half * *normal) / (4.0 * (*wo * half));
+    }
+}
+
+// Glass, water and anything else clear, which reflects some light and lets
+// the rest through, by the Fresnel equations. Light going through is tinted
+// by color. alpha is the GGX roughness, and below MIN_ALPHA the surface is
+// smooth. eta is the index of refraction on the far side over the near.
+pub struct Dielectric {
+    pub color: Color,
+    pub alpha: f32,
+    pub eta: f32,
+}
+
+impl Dielectric {
+    fn is_smooth(&self) -> bool {
+        return self.alpha < MIN_ALPHA;
+    }
+
+    // The microfacet normal that refracts wo into wi, facing the near side
+    fn refracting_normal(&self, wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> Vector3D {
+        let half = (*wo + *wi * self.eta).normalized();
+        return if half * *normal < 0.0 { -half } else { half };
+    }
+}
+
+impl Bsdf for Dielectric {
+    fn evaluate(&self, wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> Color {
+        let (cos_o, cos_i) = (*wo * *normal, *wi * *normal);
+        if self.is_smooth() || cos_o <= 0.0 || cos_i == 0.0 {
+            return Color::new(0, 0, 0, 0);
+        }
+        let ggx = Ggx { alpha: self.alpha };
+        if cos_i > 0.0 {
+            let half = (*wo + *wi).normalized();
+            let reflectance = fresnel(*wo * half, 1.0, self.eta);
+            let amount = ggx.distribution(half * *normal) * ggx.shadowing(cos_o, cos_i)
+                / (4.0 * cos_o * cos_i);
+            return white() * (reflectance * amount);
+        }
+        // Walter et al.'s microfacet refraction, without the change in
+        // radiance from the light being squeezed into a smaller angle
+        let half = self.refracting_normal(wo, wi, normal);
+        let (cos_oh, cos_ih) = (*wo * half, *wi * half);
+        if cos_oh <= 0.0 || cos_ih >= 0.0 {
+            return Color::new(0, 0, 0, 0);
+        }
+        let transmittance = 1.0 - fresnel(cos_oh, 1.0, self.eta);
+        let denominator = cos_oh + self.eta * cos_ih;
+        let amount = ggx.distribution(half * *normal)
+            * ggx.shadowing(cos_o, cos_i)
+            * transmittance
+            * self.eta
+            * self.eta
+            * (cos_oh * -cos_ih)
+            / (cos_o * -cos_i * denominator * denominator);
+        return self.color * amount;
+    }
+
+    fn sample(&self, wo: &Vector3D, normal: &Vector3D, rng: &mut Rng) -> Option<BsdfSample> {
+        let half = if self.is_smooth() {
+            *normal
+        } else {
+            sampling::ggx_normal(normal, self.alpha, rng.next_f32(), rng.next_f32())
+        };
+        let cos_oh = *wo * half;
+        if cos_oh <= 0.0 {
+            return None;
+        }
+        // Reflect or refract in proportion to the Fresnel reflectance
+        let reflectance = fresnel(cos_oh, 1.0, self.eta);
+        let reflected = rng.next_f32() < reflectance;
+        let direction = if reflected {
+            reflect(wo, &half)
+        } else {
+            refract(&-*wo, &half, 1.0 / self.eta)?
+        };
+        let cos_i = direction * *normal;
+        if (cos_i > 0.0) != reflected || cos_i == 0.0 {
+            return None;
+        }
+        if self.is_smooth() {
+            let (weight, pdf) = if reflected {
+                (white(), reflectance)
+            } else {
+                (self.color, 1.0 - reflectance)
+            };
+            return Some(BsdfSample {
+                direction,
+                weight,
+                pdf,
+                specular: true,
+            });
+        }
+        let pdf = self.pdf(wo, &direction, normal);
+        if pdf <= 0.0 {
+            return None;
+        }
+        return Some(BsdfSample {
+            direction,
+            weight: self.evaluate(wo, &direction, normal) * (cos_i.abs() / pdf),
+            pdf,
+            specular: false,
+        });
+    }
+
+    fn pdf(&self, wo: &Vector3D, wi: &Vector3D, normal: &Vector3D) -> f32 {
+        let (cos_o, cos_i) = (*wo * *normal, *wi * *normal);
+        if self.is_smooth() || cos_o <= 0.0 || cos_i == 0.0 {
+            return 0.0;
+        }
+        let ggx = Ggx { alpha: self.alpha };
+        if cos_i > 0.0 {
+            let half = (*wo + *wi).normalized();
+            let reflectance = fresnel(*wo * half, 1.0, self.eta);
+            return reflectance * ggx.pdf(half * *normal) / (4.0 * (*wo * half));
+        }
+        let half = self.refracting_normal(wo, wi, normal);
+        let (cos_oh, cos_ih) = (*wo * half, *wi * half);
+        if cos_oh <= 0.0 || cos_ih >= 0.0 {
+            return 0.0;
+        }
+        // Refraction squeezes or stretches the directions depending on eta
+        let transmittance = 1.0 - fresnel(cos_oh, 1.0, self.eta);
+        let denominator = cos_oh + self.eta * cos_ih;
+        let stretch = self.eta * self.eta * -cos_ih / (denominator * denominator);
+        return transmittance * ggx.pdf(half * *normal) * stretch;
+    }
+
+    fn specular_directions(&self, wo: &Vector3D, normal: &Vector3D) -> Vec<(Vector3D, Color)> {
+        if !self.is_smooth() {
+            return Vec::new();
+        }
+        let reflectance = fresnel(*wo * *normal, 1.0, self.eta);
+        let mut directions = vec![(reflect(wo, normal), white() * reflectance)];
+        if let Some(refracted) = refract(&-*wo, normal, 1.0 / self.eta) {
+            directions.push((refracted, self.color * (1.0 - reflectance)));
+        }
+        return directions;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gray(value: f32) -> Color {
+        return Color {
+            r: value,
+            g: value,
+            b: value,
+            a: 1.0,
+        };
+    }
+
+    // The average sample weight, which is how much of the light arriving
+    // from every direction leaves towards wo. Also checks each sample
+    // agrees with evaluate and pdf.
+    fn albedo(bsdf: &dyn Bsdf, wo: &Vector3D, normal: &Vector3D) -> f32 {
+        let mut rng = Rng::new(9, 0);
+        let count = 50000;
+        let mut total = 0.0;
+        for _ in 0..count {
+            let sample = match bsdf.sample(wo, normal, &mut rng) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            assert!((sample.direction.norm() - 1.0).abs() < 1e-4);
+            if !sample.specular {
+                let pdf = bsdf.pdf(wo, &sample.direction, normal);
+                assert!(
+                    (pdf - sample.pdf).abs() <= 1e-3 * pdf,
+                    "{} {}",
+                    pdf,
+                    sample.pdf
+                );
+                let cos_i = (sample.direction * *normal).abs();
+                let weight = bsdf.evaluate(wo, &sample.direction, normal).g * cos_i / pdf;
+                assert!((weight - sample.weight.g).abs() <= 1e-3 * weight.max(1.0));
+            }
+            total += sample.weight.g;
+        }
+        return total / count as f32;
+    }
+
+    fn directions() -> (Vector3D, Vector3D) {
+        let normal = Vector3D::new([0.0, 1.0, 0.0]);
+        let wo = Vector3D::new([0.5, 1.0, 0.0]).normalized();
+        return (wo, normal);
+    }
+
+    #[test]
+    fn lambertian() {
+        let (wo, normal) = directions();
+        let bsdf = Lambertian { color: gray(0.5) };
+        assert!((albedo(&bsdf, &wo, &normal) - 0.5).abs() < 1e-5);
+        let below = Vector3D::new([0.0, -1.0, 0.0]);
+        assert_eq!(bsdf.evaluate(&wo, &below, &normal).g, 0.0);
+        assert_eq!(bsdf.pdf(&wo, &below, &normal), 0.0);
+    }
+
+    #[test]
+    fn mirror() {
+        let (wo, normal) = directions();
+        let bsdf = Mirror { color: gray(0.9) };
+        let sample = bsdf.sample(&wo, &normal, &mut Rng::new(0, 0)).unwrap();
+        assert!(sample.specular);
+        assert!((sample.direction - Vector3D::new([-0.5, 1.0, 0.0]).normalized()).norm() < 1e-6);
+        assert_eq!(sample.weight.g, 0.9);
+    }
+
+    #[test]
+    fn conductor() {
+        let (wo, normal) = directions();
+        // A smooth white metal keeps nearly all the light. Rough ones lose
+        // some to microfacets shadowing each other.
+        let smooth = albedo(
+            &Conductor {
+                color: gray(1.0),
+                alpha: 0.1,
+            },
+            &wo,
+            &normal,
+        );
+        let rough = albedo(
+            &Conductor {
+                color: gray(1.0),
+                alpha: 0.8,
+            },
+            &wo,
+            &normal,
+        );
+        assert!(smooth > 0.97 && smooth <= 1.0, "{}", smooth);
+        assert!(rough > 0.4 && rough < smooth, "{}", rough);
+
+        // Rough metal spreads its highlight out
+        let bsdf = Conductor {
+            color: gray(1.0),
+            alpha: 0.3,
+        };
+        let mirrored = reflect(&wo, &normal);
+        let off = Vector3D::new([-0.2, 1.0, 0.5]).normalized();
+        assert!(bsdf.evaluate(&wo, &mirrored, &normal).g > bsdf.evaluate(&wo, &off, &normal).g);
+        assert!(bsdf.evaluate(&wo, &off, &normal).g > 0.0);
+    }
+
+    #[test]
+    fn dielectric() {
+        let (wo, normal) = directions();
+        // Clear glass keeps all the light, between reflecting and refracting
+        let smooth = Dielectric {
+            color: gray(1.0),
+            alpha: 0.0,
+            eta: 1.5,
+        };
+        assert!((albedo(&smooth, &wo, &normal) - 1.0).abs() < 1e-5);
+        let directions = smooth.specular_directions(&wo, &normal);
+        assert_eq!(directions.len(), 2);
+        assert!((directions[0].1.g + directions[1].1.g - 1.0).abs() < 1e-6);
+        for eta in [1.5, 1.0 / 1.5] {
+            let rough = Dielectric {
+                color: gray(1.0),
+                alpha: 0.2,
+                eta,
+            };
+            let kept = albedo(&rough, &wo, &normal);
+            assert!(kept > 0.9 && kept < 1.01, "{} for {}", kept, eta);
+        }
+
+        // Light goes through rough glass bent towards the normal
+        let rough = Dielectric {
+            color: gray(1.0),
+            alpha: 0.2,
+            eta: 1.5,
+        };
+        let bent = refract(&-wo, &normal, 1.0 / 1.5).unwrap();
+        let straight = -wo;
+        assert!(rough.evaluate(&wo, &bent, &normal).g > rough.evaluate(&wo, &straight, &normal).g);
+        assert!(rough.pdf(&wo, &bent, &normal) > 0.0);
+    }
+
+    #[test]
+    fn model_names() {
+        assert_eq!(
+            BsdfModel::from_name("conductor"),
+            Some(BsdfModel::Conductor)
+        );
+        assert_eq!(BsdfModel::from_name("phong"), None);
+    }
+}
@@ -0,0 +1,373 @@
+use crate::matrix::vector::{Point3D, Vector2D, Vector3D};
+use crate::raytracer::geometry::Rayhit;
+use crate::raytracer::refraction::Boundary;
+
+// Rays through the neighbouring pixels to the right and below, followed
+// alongside the main ray through mirrors and glass. Where they land next to
+// its hit tells textures how much of the surface one pixel covers.
+// Directions are unit length.
+#[derive(Clone, Copy, Debug)]
+pub struct RayDifferential {
+    pub x_origin: Point3D,
+    pub x_direction: Vector3D,
+    pub y_origin: Point3D,
+    pub y_direction: Vector3D,
+}
+
+// How far a hit moves across the surface from one pixel to the next, and
+// its uvs with it. All zero when unknown, which turns texture filtering off.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Footprint {
+    pub dpdx: Vector3D,
+    pub dpdy: Vector3D,
+    pub duvdx: Vector2D,
+    pub duvdy: Vector2D,
+}
+
+impl Footprint {
+    pub fn none() -> Footprint {
+        return Footprint {
+            dpdx: Vector3D::zero(),
+            dpdy: Vector3D::zero(),
+            duvdx: Vector2D::zero(),
+            duvdy: Vector2D::zero(),
+        };
+    }
+
+    // The longer of the two steps across the surface
+    pub fn width(&self) -> f32 {
+        return self.dpdx.norm().max(self.dpdy.norm());
+    }
+}
+
+// Where a ray crosses the plane through point with the given normal,
+// relative to point
+fn plane_offset(
+    origin: &Point3D,
+    direction: &Vector3D,
+    point: &Point3D,
+    normal: &Vector3D,
+) -> Option<Vector3D> {
+    let facing = *normal * *direction;
+    if facing.is_nan() || facing.abs() < 1e-8 {
+        return None;
+    }
+    let t = (*point - *origin) * *normal / facing;
+    return Some(*origin + *direction * t - *point);
+}
+
+// The change in uv that best explains a step across the surface, given how
+// position changes with u and v
+fn uv_step(step: &Vector3D, tangent: &Vector3D, bitangent: &Vector3D) -> Vector2D {
+    // Least squares, through the normal equations
+    let (a, b, c) = (
+        *tangent * *tangent,
+        *tangent * *bitangent,
+        *bitangent * *bitangent,
+    );
+    let determinant = a * c - b * b;
+    if determinant.abs() < 1e-12 {
+        return Vector2D::zero();
+    }
+    let (along_u, along_v) = (*tangent * *step, *bitangent * *step);
+    return Vector2D::new([
+        (c * along_u - b * along_v) / determinant,
+        (a * along_v - b * along_u) / determinant,
+    ]);
+}
+
+// How the hit's normal, turned to face the same way as normal, changes
+// over a step across the surface from it. Flat faces don't bend, and
+// neither does anything whose change can't be worked out.
+fn normal_change(hit: &Rayhit, normal: &Vector3D, step: &Vector3D) -> Vector3D {
+    let change = hit.dndp * *step;
+    if !change.norm().is_finite() {
+        return Vector3D::zero();
+    }
+    return if hit.normal * *normal < 0.0 {
+        -change
+    } else {
+        change
+    };
+}
+
+impl RayDifferential {
+    // How far apart the rays land on the plane touching the hit
+    pub fn footprint(&self, hit: &Rayhit) -> Footprint {
+        let dpdx = plane_offset(&self.x_origin, &self.x_direction, &hit.pos, &hit.normal);
+        let dpdy = plane_offset(&self.y_origin, &self.y_direction, &hit.pos, &hit.normal);
+        let (dpdx, dpdy) = match (dpdx, dpdy) {
+            (Some(dpdx), Some(dpdy)) => (dpdx, dpdy),
+            _ => return Footprint::none(),
+        };
+        return Footprint {
+            dpdx,
+            dpdy,
+            duvdx: uv_step(&dpdx, &hit.tangent, &hit.bitangent),
+            duvdy: uv_step(&dpdy, &hit.tangent, &hit.bitangent),
+        };
+    }
+
+    // Follows the differential into a mirror reflection off hit, whose
+    // footprint has been measured. incoming is the main ray's direction.
+    pub fn reflect(&self, incoming: &Vector3D, hit: &Rayhit, normal: &Vector3D) -> RayDifferential {
+        let forward = incoming.normalized();
+        let normal = if *normal * forward > 0.0 {
+            -*normal
+        } else {
+            *normal
+        };
+        let wo = -forward;
+        let cos_theta = wo * normal;
+        let reflected = forward + normal * (2.0 * cos_theta);
+        // The change in direction for a neighbouring ray, differentiated from
+        // reflected = -wo + 2 (wo . n) n
+        let bend = |origin_step: &Vector3D, direction: &Vector3D| {
+            let normal_step = normal_change(hit, &normal, origin_step);
+            let wo_step = forward - *direction;
+            let cos_step = wo_step * normal + wo * normal_step;
+            let direction =
+                reflected - wo_step + (normal_step * cos_theta + normal * cos_step) * 2.0;
+            return (hit.pos + *origin_step, direction.normalized());
+        };
+        let (x_origin, x_direction) = bend(&hit.footprint.dpdx, &self.x_direction);
+        let (y_origin, y_direction) = bend(&hit.footprint.dpdy, &self.y_direction);
+        return RayDifferential {
+            x_origin,
+            x_direction,
+            y_origin,
+            y_direction,
+        };
+    }
+
+    // Follows the differential through a surface into refracted, which the
+    // main ray bent into crossing from one index of refraction to another
+    // eta times as large
+    pub fn refract(
+        &self,
+        incoming: &Vector3D,
+        refracted: &Vector3D,
+        eta: f32,
+        hit: &Rayhit,
+        normal: &Vector3D,
+    ) -> RayDifferential {
+        let forward = incoming.normalized();
+        let refracted = refracted.normalized();
+        let normal = if *normal * forward > 0.0 {
+            -*normal
+        } else {
+            *normal
+        };
+        let ratio = 1.0 / eta;
+        let wo = -forward;
+        let cos_theta = wo * normal;
+        let cos_refracted = (refracted * normal).abs().max(1e-4);
+        // refracted = -ratio wo + mu n, with mu changing as the angle does
+        let mu = ratio * cos_theta - cos_refracted;
+        let bend = |origin_step: &Vector3D, direction: &Vector3D| {
+            let normal_step = normal_change(hit, &normal, origin_step);
+            let wo_step = forward - *direction;
+            let cos_step = wo_step * normal + wo * normal_step;
+            let mu_step = (ratio - ratio * ratio * cos_theta / cos_refracted) * cos_step;
+            let direction = refracted - wo_step * ratio + normal_step * mu + normal * mu_step;
+            return (hit.pos + *origin_step, direction.normalized());
+        };
+        let (x_origin, x_direction) = bend(&hit.footprint.dpdx, &self.x_direction);
+        let (y_origin, y_direction) = bend(&hit.footprint.dpdy, &self.y_direction);
+        return RayDifferential {
+            x_origin,
+            x_direction,
+            y_origin,
+            y_direction,
+        };
+    }
+
+    // Follows the differential into a ray leaving boundary in direction,
+    // refracted if it goes through and reflected if not
+    pub fn through(
+        &self,
+        boundary: &Boundary,
+        hit: &Rayhit,
+        direction: &Vector3D,
+    ) -> RayDifferential {
+        if *direction * boundary.normal < 0.0 {
+            return self.refract(
+                &boundary.direction,
+                direction,
+                boundary.eta(),
+                hit,
+                &boundary.normal,
+            );
+        }
+        return self.reflect(&boundary.direction, hit, &boundary.normal);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matrix::matrix::Matrix;
+    use crate::raytracer::geometry::material::plain_material;
+    use crate::raytracer::geometry::mesh::TriangleMesh;
+    use crate::raytracer::geometry::transformed::Transformed;
+    use crate::raytracer::geometry::{Geometry, Ray, Sphere, Triangle};
+    use std::sync::Arc;
+
+    // A 20 by 20 floor at y = 0, with uvs running along x and z
+    fn floor_hit(ray: &Ray) -> Rayhit {
+        let floor = Arc::new(Triangle {
+            a: Point3D::new([-10.0, 0.0, -10.0]),
+            b: Point3D::new([10.0, 0.0, -10.0]),
+            c: Point3D::new([-10.0, 0.0, 10.0]),
+            material: plain_material(),
+        });
+        return floor.intersect(ray, f32::INFINITY).unwrap();
+    }
+
+    // Rays fanning out from origin a hundredth of a unit across per unit
+    // forward from straight down
+    fn looking_down(origin: Point3D) -> (Ray, RayDifferential) {
+        let ray = Ray {
+            origin,
+            direction: Vector3D::new([0.0, -1.0, 0.0]),
+        };
+        let differential = RayDifferential {
+            x_origin: origin,
+            x_direction: Vector3D::new([0.01, -1.0, 0.0]).normalized(),
+            y_origin: origin,
+            y_direction: Vector3D::new([0.0, -1.0, 0.01]).normalized(),
+        };
+        return (ray, differential);
+    }
+
+    #[test]
+    fn floor_footprint() {
+        let (ray, differential) = looking_down(Point3D::new([-1.0, 2.0, -3.0]));
+        let hit = floor_hit(&ray);
+        let footprint = differential.footprint(&hit);
+        assert!((footprint.dpdx - Vector3D::new([0.02, 0.0, 0.0])).norm() < 1e-5);
+        assert!((footprint.dpdy - Vector3D::new([0.0, 0.0, 0.02])).norm() < 1e-5);
+        assert!((footprint.width() - 0.02).abs() < 1e-5);
+        // The floor's uvs cover 20 units
+        assert!((footprint.duvdx - Vector2D::new([0.001, 0.0])).norm() < 1e-6);
+        assert!((footprint.duvdy - Vector2D::new([0.0, 0.001])).norm() < 1e-6);
+    }
+
+    #[test]
+    fn flat_mirror() {
+        let (ray, differential) = looking_down(Point3D::new([-1.0, 2.0, -3.0]));
+        let mut hit = floor_hit(&ray);
+        hit.footprint = differential.footprint(&hit);
+        let reflected = differential.reflect(&ray.direction, &hit, &hit.normal);
+        // The rays carry on spreading as they were, but upwards
+        let expected = Vector3D::new([0.01, 1.0, 0.0]).normalized();
+        assert!((reflected.x_direction - expected).norm() < 1e-5);
+        assert!((reflected.x_origin - (hit.pos + hit.footprint.dpdx)).norm() < 1e-5);
+        let expected = Vector3D::new([0.0, 1.0, 0.01]).normalized();
+        assert!((reflected.y_direction - expected).norm() < 1e-5);
+    }
+
+    #[test]
+    fn curved_mirror() {
+        // A ball bends neighbouring rays apart far more than a flat mirror
+        let ball = Arc::new(Sphere {
+            origin: Point3D::zero(),
+            radius: 1.0,
+            material: plain_material(),
+        });
+        let (ray, differential) = looking_down(Point3D::new([0.0, 5.0, 0.0]));
+        let mut hit = ball.intersect(&ray, f32::INFINITY).unwrap();
+        hit.footprint = differential.footprint(&hit);
+        let reflected = differential.reflect(&ray.direction, &hit, &hit.normal);
+        // Landing 0.04 along, where the normal leans 0.04 further, turns
+        // the ray an extra 0.08
+        assert!((reflected.x_direction.x() - 0.09).abs() < 5e-3);
+        assert!(reflected.x_direction.y() > 0.99);
+    }
+
+    #[test]
+    fn transformed_mirror() {
+        // A unit ball scaled and moved bends rays just like a ball built
+        // where it ends up
+        let ball: Arc<dyn Geometry> = Arc::new(Sphere {
+            origin: Point3D::new([3.0, 0.0, 0.0]),
+            radius: 2.0,
+            material: plain_material(),
+        });
+        let unit: Arc<dyn Geometry> = Arc::new(Sphere {
+            origin: Point3D::zero(),
+            radius: 1.0,
+            material: plain_material(),
+        });
+        let transform = Matrix::translation(&Vector3D::new([3.0, 0.0, 0.0]))
+            .multiply(Matrix::scaling(&Vector3D::new([2.0, 2.0, 2.0])));
+        let moved: Arc<dyn Geometry> = Arc::new(Transformed::new(unit, transform).unwrap());
+        let (ray, differential) = looking_down(Point3D::new([3.0, 10.0, 0.0]));
+        let reflect = |object: Arc<dyn Geometry>| {
+            let mut hit = object.intersect(&ray, f32::INFINITY).unwrap();
+            hit.footprint = differential.footprint(&hit);
+            return differential.reflect(&ray.direction, &hit, &hit.normal);
+        };
+        let (expected, reflected) = (reflect(ball), reflect(moved));
+        assert!((reflected.x_direction - expected.x_direction).norm() < 1e-4);
+        assert!((reflected.y_direction - expected.y_direction).norm() < 1e-4);
+        assert!((reflected.x_origin - expected.x_origin).norm() < 1e-4);
+    }
+
+    // A square of mesh lying flat at y = 0 but shaded like a dome, with
+    // normals leaning out towards its corners, scaled and moved along x
+    fn dome(scale: f32, x: f32) -> Arc<dyn Geometry> {
+        let corners = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+        let positions = corners
+            .iter()
+            .map(|&(u, v)| Point3D::new([u * scale + x, 0.0, v * scale]))
+            .collect();
+        let normals = corners
+            .iter()
+            .map(|&(u, v)| Vector3D::new([0.5 * u, 1.0, 0.5 * v]).normalized())
+            .collect();
+        return Arc::new(TriangleMesh::new(
+            positions,
+            Some(normals),
+            vec![],
+            vec![[0, 2, 1], [0, 3, 2]],
+            plain_material(),
+        ));
+    }
+
+    #[test]
+    fn smooth_mesh_mirror() {
+        let reflect = |object: Arc<dyn Geometry>, origin: Point3D| {
+            let (ray, differential) = looking_down(origin);
+            let mut hit = object.intersect(&ray, f32::INFINITY).unwrap();
+            hit.footprint = differential.footprint(&hit);
+            return differential.reflect(&ray.direction, &hit, &hit.normal);
+        };
+        // Its shading bends rays apart more than the flat face would
+        let built = reflect(dome(2.0, 3.0), Point3D::new([3.2, 5.0, 0.3]));
+        assert!(built.x_direction.x() > 0.011);
+        assert!(built.y_direction.z() > 0.011);
+        // and the same again when the dome is placed by a transform
+        let transform = Matrix::translation(&Vector3D::new([3.0, 0.0, 0.0]))
+            .multiply(Matrix::scaling(&Vector3D::new([2.0, 2.0, 2.0])));
+        let placed: Arc<dyn Geometry> =
+            Arc::new(Transformed::new(dome(1.0, 0.0), transform).unwrap());
+        let placed = reflect(placed, Point3D::new([3.2, 5.0, 0.3]));
+        assert!((placed.x_direction - built.x_direction).norm() < 1e-4);
+        assert!((placed.y_direction - built.y_direction).norm() < 1e-4);
+        assert!((placed.x_origin - built.x_origin).norm() < 1e-4);
+    }
+
+    #[test]
+    fn glass() {
+        // Going into glass, rays turn towards the normal and spread slower
+        let (ray, differential) = looking_down(Point3D::new([-1.0, 2.0, -3.0]));
+        let mut hit = floor_hit(&ray);
+        hit.footprint = differential.footprint(&hit);
+        let refracted =
+            differential.refract(&ray.direction, &ray.direction, 1.5, &hit, &hit.normal);
+        assert!((refracted.x_direction.x() - 0.01 / 1.5).abs() < 1e-4);
+        assert!(refracted.x_direction.y() < -0.99);
+        assert!((refracted.y_direction.z() - 0.01 / 1.5).abs() < 1e-4);
+    }
+}
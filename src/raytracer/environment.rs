@@ -0,0 +1,299 @@
+use std::f32::consts::PI;
+
+use crate::image::{Color, HdrImage};
+use crate::matrix::vector::Vector3D;
+use crate::raytracer::sampling;
+
+// A direction picked by an environment, with the light arriving from it and
+// the chance of picking it, per steradian
+pub struct EnvironmentSample {
+    pub direction: Vector3D,
+    pub radiance: Color,
+    pub pdf: f32,
+}
+
+// The light arriving from infinitely far away, which rays that miss
+// everything see and which lights the scene like a huge area light.
+// Environments are shared between the render threads.
+pub trait Environment: Send + Sync {
+    // The light arriving along direction, which doesn't have to be unit length
+    fn radiance(&self, direction: &Vector3D) -> Color;
+
+    // Picks a direction with u1 and u2, favouring the bright ones. Returns
+    // None if the environment gives no light.
+    fn sample(&self, u1: f32, u2: f32) -> Option<EnvironmentSample>;
+
+    // The chance of sample picking direction, per steradian
+    fn pdf(&self, direction: &Vector3D) -> f32;
+}
+
+// The same color in every direction. A black one gives no light at all.
+pub struct UniformEnvironment {
+    pub color: Color,
+}
+
+impl UniformEnvironment {
+    fn is_black(&self) -> bool {
+        return self.color.r <= 0.0 && self.color.g <= 0.0 && self.color.b <= 0.0;
+    }
+}
+
+impl Environment for UniformEnvironment {
+    fn radiance(&self, _direction: &Vector3D) -> Color {
+        return self.color;
+    }
+
+    fn sample(&self, u1: f32, u2: f32) -> Option<EnvironmentSample> {
+        if self.is_black() {
+            return None;
+        }
+        return Some(EnvironmentSample {
+            direction: sampling::uniform_sphere(u1, u2),
+            radiance: self.color,
+            pdf: 1.0 / (4.0 * PI),
+        });
+    }
+
+    fn pdf(&self, _direction: &Vector3D) -> f32 {
+        if self.is_black() {
+            return 0.0;
+        }
+        return 1.0 / (4.0 * PI);
+    }
+}
+
+// An equirectangular image wrapped around the scene, laid out as in
+// to_image. rotation turns it around the vertical axis.
+pub struct EnvironmentMap {
+    image: HdrImage,
+    strength: f32,
+    rotation: f32, // As a fraction of a whole turn
+    // Running totals of each pixel's brightness, weighted by how much of the
+    // sphere it covers, for picking pixels by how much light they give.
+    // There is one list for each row, and one across the rows.
+    pixel_sums: Vec<Vec<f32>>,
+    row_sums: Vec<f32>,
+}
+
+// Where a unit direction lands on an equirectangular image, from 0 to 1
+// across and down. The middle of the image is straight ahead along +z, its
+// top is straight up, and it goes around to the right towards +x.
+pub fn to_image(direction: &Vector3D) -> (f32, f32) {
+    let phi = f32::atan2(direction.x(), direction.z());
+    let u = (0.5 + phi / (2.0 * PI)).rem_euclid(1.0);
+    // More accurate than acos near the poles, where pdfs change quickly
+    let across = f32::hypot(direction.x(), direction.z());
+    let v = f32::atan2(across, direction.y()) / PI;
+    return (u, v);
+}
+
+pub fn to_direction(u: f32, v: f32) -> Vector3D {
+    let phi = 2.0 * PI * (u - 0.5);
+    let theta = PI * v;
+    return Vector3D::new([
+        theta.sin() * phi.sin(),
+        theta.cos(),
+        theta.sin() * phi.cos(),
+    ]);
+}
+
+// How bright a color looks, for picking the parts of an image to sample
+fn luminance(color: &Color) -> f32 {
+    return (0.2126 * color.r + 0.7152 * color.g + 0.0722 * color.b).max(0.0);
+}
+
+// Picks the entry of a list of running totals that target falls in, and how
+// far through the entry it is
+fn pick(sums: &[f32], target: f32) -> (usize, f32) {
+    let index = sums
+        .partition_point(|&sum| sum <= target)
+        .min(sums.len() - 1);
+    let start = if index == 0 { 0.0 } else { sums[index - 1] };
+    let fraction = ((target - start) / (sums[index] - start)).clamp(0.0, 1.0);
+    return (index, fraction);
+}
+
+impl EnvironmentMap {
+    // strength scales the image's colors, and rotation is in degrees
+    pub fn new(image: HdrImage, strength: f32, rotation: f32) -> EnvironmentMap {
+        let mut row_total = 0.0;
+        let mut row_sums = Vec::with_capacity(image.height);
+        let mut pixel_sums = Vec::with_capacity(image.height);
+        for y in 0..image.height {
+            // Rows near the poles are squeezed into less of the sphere
+            let sin_theta = (PI * (y as f32 + 0.5) / image.height as f32).sin();
+            let mut total = 0.0;
+            let sums = (0..image.width)
+                .map(|x| {
+                    total += luminance(&image.get_pixel(x, y)) * sin_theta;
+                    total
+                })
+                .collect();
+            pixel_sums.push(sums);
+            row_total += total;
+            row_sums.push(row_total);
+        }
+        return EnvironmentMap {
+            image,
+            strength,
+            rotation: rotation / 360.0,
+            pixel_sums,
+            row_sums,
+        };
+    }
+
+    fn total(&self) -> f32 {
+        return self.row_sums.last().copied().unwrap_or(0.0);
+    }
+
+    fn to_image(&self, direction: &Vector3D) -> (f32, f32) {
+        let (u, v) = to_image(direction);
+        return ((u - self.rotation).rem_euclid(1.0), v);
+    }
+
+    fn to_direction(&self, u: f32, v: f32) -> Vector3D {
+        return to_direction(u + self.rotation, v);
+    }
+
+    fn pixel_at(&self, u: f32, v: f32) -> (usize, usize) {
+        let x = ((u * self.image.width as f32) as usize).min(self.image.width - 1);
+        let y = ((v * self.image.height as f32) as usize).min(self.image.height - 1);
+        return (x, y);
+    }
+
+    // The chance of picking pixel x y, spread evenly over the sphere it
+    // covers. The image covers 2pi^2 sin(theta) steradians per unit area.
+    fn pixel_pdf(&self, x: usize, y: usize, v: f32) -> f32 {
+        let sin_theta = (PI * v).sin();
+        let total = self.total();
+        if sin_theta <= 0.0 || total <= 0.0 {
+            return 0.0;
+        }
+        let row = &self.pixel_sums[y];
+        let weight = row[x] - if x == 0 { 0.0 } else { row[x - 1] };
+        let pixels = (self.image.width * self.image.height) as f32;
+        return weight / total * pixels / (2.0 * PI * PI * sin_theta);
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, direction: &Vector3D) -> Color {
+        let (u, v) = self.to_image(&direction.normalized());
+        let (x, y) = self.pixel_at(u, v);
+        return self.image.get_pixel(x, y) * self.strength;
+    }
+
+    fn sample(&self, u1: f32, u2: f32) -> Option<EnvironmentSample> {
+        let total = self.total();
+        if total <= 0.0 || self.strength <= 0.0 {
+            return None;
+        }
+        let (y, fraction_y) = pick(&self.row_sums, u1 * total);
+        let row = &self.pixel_sums[y];
+        let (x, fraction_x) = pick(row, u2 * row[row.len() - 1]);
+        let u = (x as f32 + fraction_x) / self.image.width as f32;
+        let v = (y as f32 + fraction_y) / self.image.height as f32;
+        let pdf = self.pixel_pdf(x, y, v);
+        if pdf <= 0.0 {
+            return None;
+        }
+        return Some(EnvironmentSample {
+            direction: self.to_direction(u, v),
+            radiance: self.image.get_pixel(x, y) * self.strength,
+            pdf,
+        });
+    }
+
+    fn pdf(&self, direction: &Vector3D) -> f32 {
+        if self.strength <= 0.0 {
+            return 0.0;
+        }
+        let (u, v) = self.to_image(&direction.normalized());
+        let (x, y) = self.pixel_at(u, v);
+        return self.pixel_pdf(x, y, v);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raytracer::sampling::Rng;
+
+    fn gray(value: f32) -> Color {
+        return Color {
+            r: value,
+            g: value,
+            b: value,
+            a: 1.0,
+        };
+    }
+
+    // A dim sky with one bright pixel in the upper half
+    fn map(rotation: f32) -> EnvironmentMap {
+        let (width, height) = (16, 8);
+        let mut pixels = vec![gray(0.1); width * height];
+        pixels[5 + 2 * width] = gray(50.0);
+        let image = HdrImage {
+            width,
+            height,
+            pixels,
+        };
+        return EnvironmentMap::new(image, 2.0, rotation);
+    }
+
+    #[test]
+    fn image_directions() {
+        let map = map(90.0);
+        // Turned a quarter of the way round, the middle of the image is +x
+        let ahead = map.to_direction(0.5, 0.5);
+        assert!((ahead - Vector3D::new([1.0, 0.0, 0.0])).norm() < 1e-5);
+        let (u, v) = map.to_image(&Vector3D::new([0.0, 1.0, 0.0]));
+        assert_eq!(v, 0.0);
+        for (u1, v1) in [(0.1, 0.3), (0.7, 0.6), (0.95, 0.9)] {
+            let (u2, v2) = map.to_image(&map.to_direction(u1, v1));
+            assert!((u1 - u2).abs() < 1e-4 && (v1 - v2).abs() < 1e-4);
+        }
+        assert!(u.is_finite());
+        assert_eq!(map.radiance(&Vector3D::new([0.0, -3.0, 0.0])).g, 0.2);
+    }
+
+    #[test]
+    fn importance_sampling() {
+        let map = map(30.0);
+        let mut rng = Rng::new(5, 0);
+        let mut bright = 0;
+        let mut total = 0.0;
+        let count = 20000;
+        for _ in 0..count {
+            let sample = map.sample(rng.next_f32(), rng.next_f32()).unwrap();
+            assert!((map.pdf(&sample.direction) - sample.pdf).abs() < 1e-3 * sample.pdf);
+            if sample.radiance.g > 1.0 {
+                bright += 1;
+            }
+            total += sample.radiance.g / sample.pdf;
+        }
+        // The bright pixel gives most of the light, so it gets picked most
+        assert!(bright > count / 2);
+
+        // Averaging radiance over pdf gives the light from every direction
+        let mut expected = 0.0;
+        for y in 0..8 {
+            let theta = PI * (y as f32 + 0.5) / 8.0;
+            for x in 0..16 {
+                let solid_angle = 2.0 * PI * PI * theta.sin() / (16.0 * 8.0);
+                expected += map.image.get_pixel(x, y).g * 2.0 * solid_angle;
+            }
+        }
+        assert!((total / count as f32 - expected).abs() < 0.02 * expected);
+    }
+
+    #[test]
+    fn uniform() {
+        let white = UniformEnvironment { color: gray(1.0) };
+        let sample = white.sample(0.3, 0.6).unwrap();
+        assert_eq!(sample.pdf, white.pdf(&sample.direction));
+        let black = UniformEnvironment { color: gray(0.0) };
+        assert!(black.sample(0.3, 0.6).is_none());
+        assert_eq!(black.pdf(&Vector3D::new([0.0, 1.0, 0.0])), 0.0);
+    }
+}
@@ -1,47 +1,51 @@
+use std::f32::consts::PI;
 use std::fmt;
-use std::rc::Rc;
+use std::sync::Arc;
 
+use crate::matrix::matrix::Matrix;
 use crate::matrix::vector::Point3D;
+use crate::matrix::vector::Vector2D;
 use crate::matrix::vector::Vector3D;
 
-use crate::Color;
+use crate::raytracer::differential::Footprint;
+use crate::raytracer::sampling;
+use bvh::Aabb;
 use material::Material;
 
+pub mod bvh;
 pub mod material;
+pub mod mesh;
+pub mod transformed;
 
 // Some coordinate ground rules:
 // x is east/west, y is up/down, z is north/south
 
-pub struct Lights {
-    pub sources: Vec<Light>,
-    pub total_intensity: f32,
-}
-
-impl Lights {
-    pub fn new(sources: Vec<Light>) -> Lights {
-        let mut intensity = 0.0;
-        for light in &sources {
-            intensity += light.intensity;
-        }
-        return Lights {
-            sources,
-            total_intensity: intensity,
-        };
-    }
-}
-
-pub struct Light {
-    pub source: Point3D,
-    pub color: Color,
-    pub intensity: f32,
-}
-
 pub struct Rayhit {
     pub dist: f32,
     pub pos: Point3D,
     pub normal: Vector3D,
-    pub material: Rc<Material>,
-    pub obj: Rc<dyn Geometry>,
+    pub uv: Vector2D, // Surface coordinate, for geometry that has them
+    // How the position changes with u and v, along the surface. They aren't
+    // unit length, and are zero where u or v doesn't change.
+    pub tangent: Vector3D,
+    pub bitangent: Vector3D,
+    // How the normal changes over a small step across the surface, as a
+    // matrix applied to the step. Zero where the surface is flat.
+    pub dndp: Matrix<f32, 3, 3>,
+    // How much of each corner is in the hit, for triangles
+    pub barycentrics: Vector3D,
+    // Where the hit is before any transforms, for solid textures, and its
+    // tangents in the same space
+    pub local: Point3D,
+    pub local_tangent: Vector3D,
+    pub local_bitangent: Vector3D,
+    // How much of the surface around the hit one pixel covers, once it's
+    // been measured from a ray differential
+    pub footprint: Footprint,
+    pub material: Arc<Material>,
+    pub obj: Arc<dyn Geometry>,
+    // The outermost Transformed the object was hit through, if any
+    pub instance: Option<Arc<dyn Geometry>>,
 }
 
 impl Rayhit {
@@ -49,17 +53,52 @@ impl Rayhit {
         dist: f32,
         pos: Point3D,
         normal: Vector3D,
-        material: Rc<Material>,
-        obj: Rc<dyn Geometry>,
+        material: Arc<Material>,
+        obj: Arc<dyn Geometry>,
     ) -> Rayhit {
         return Rayhit {
             dist: dist,
             pos: pos,
             normal: normal,
+            uv: Vector2D::zero(),
+            tangent: Vector3D::zero(),
+            bitangent: Vector3D::zero(),
+            dndp: Matrix::zero(),
+            barycentrics: Vector3D::zero(),
+            local: pos,
+            local_tangent: Vector3D::zero(),
+            local_bitangent: Vector3D::zero(),
+            footprint: Footprint::none(),
             material: material,
             obj: obj,
+            instance: None,
         };
     }
+
+    pub fn surface(&self) -> Surface {
+        return Surface {
+            obj: Arc::clone(&self.obj),
+            instance: self.instance.clone(),
+        };
+    }
+}
+
+// The surface a ray is leaving, so it doesn't hit it again straight away.
+// One object can be placed many times by Transformed, so which placement
+// it was hit through is part of it.
+#[derive(Clone)]
+pub struct Surface {
+    pub obj: Arc<dyn Geometry>,
+    pub instance: Option<Arc<dyn Geometry>>,
+}
+
+// A point picked at random on the surface of an object, so area lights can
+// be sampled
+pub struct SurfaceSample {
+    pub pos: Point3D,
+    pub normal: Vector3D,
+    pub pdf: f32, // Per unit area
+    pub material: Arc<Material>,
 }
 
 pub struct Ray {
@@ -79,34 +118,74 @@ impl fmt::Display for Ray {
     }
 }
 
-pub trait Geometry {
-    fn intersect(self: Rc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit>;
+// Geometry is shared between the render threads, so it must be Send + Sync
+pub trait Geometry: Send + Sync {
+    fn intersect(self: Arc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit>;
     fn normal(&self, position: Point3D) -> Vector3D;
+    fn bounds(&self) -> Aabb;
+
+    // Like intersect, but never hits `ignore`. Containers of other geometry
+    // override this to pass `ignore` down to their children.
+    fn intersect_ignoring(
+        self: Arc<Self>,
+        ray: &Ray,
+        closest_dist: f32,
+        ignore: Option<&Surface>,
+    ) -> Option<Rayhit> {
+        if let Some(ignore) = ignore {
+            if ignore.instance.is_none()
+                && std::ptr::addr_eq(Arc::as_ptr(&self), Arc::as_ptr(&ignore.obj))
+            {
+                return None;
+            }
+        }
+        return self.intersect(ray, closest_dist);
+    }
+
+    // Whether any of the object gives off light. Only finite objects can.
+    fn is_emissive(&self) -> bool {
+        return false;
+    }
+
+    // Picks a point evenly over the surface from two uniform random numbers.
+    // Objects that can be emissive have to support this.
+    fn sample_surface(&self, _u1: f32, _u2: f32) -> Option<SurfaceSample> {
+        return None;
+    }
+}
+
+// How a unit normal changes when the vector it was normalized from changes
+// by change
+pub fn normalized_change(unnormalized: &Vector3D, change: &Vector3D) -> Vector3D {
+    let length = unnormalized.norm();
+    if length == 0.0 {
+        return Vector3D::zero();
+    }
+    let normal = *unnormalized / length;
+    return (*change - normal * (normal * *change)) / length;
+}
+
+// The matrix that takes each axis to what map gives for it, for linear maps
+pub fn linear_map(map: impl Fn(Vector3D) -> Vector3D) -> Matrix<f32, 3, 3> {
+    let x = map(Vector3D::new([1.0, 0.0, 0.0]));
+    let y = map(Vector3D::new([0.0, 1.0, 0.0]));
+    let z = map(Vector3D::new([0.0, 0.0, 1.0]));
+    return Matrix::new([[x[0], y[0], z[0]], [x[1], y[1], z[1]], [x[2], y[2], z[2]]]);
+}
+
+// Compares the addresses of two objects, ignoring their vtables
+pub fn same_object(a: &Arc<dyn Geometry>, b: &Arc<dyn Geometry>) -> bool {
+    return std::ptr::addr_eq(Arc::as_ptr(a), Arc::as_ptr(b));
 }
 
 pub struct Sphere {
     pub origin: Point3D,
     pub radius: f32,
-    pub material: Rc<Material>,
+    pub material: Arc<Material>,
 }
 
 impl Geometry for Sphere {
-    fn intersect(self: Rc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit> {
-        // We trivially hit an infinite sphere infinitely far away
-        if f32::is_infinite(self.radius) {
-            return if f32::is_finite(closest_dist) {
-                None
-            } else {
-                Some(Rayhit::new(
-                    f32::INFINITY,
-                    ray.direction * f32::INFINITY,
-                    -ray.direction,
-                    Rc::clone(&self.material),
-                    self,
-                ))
-            };
-        }
-
+    fn intersect(self: Arc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit> {
         let d = ray.direction;
         let e = ray.origin;
         let c = self.origin;
@@ -132,84 +211,230 @@ impl Geometry for Sphere {
         //println!("Disc: {}", discriminant);
         //println!("t1: {}, t2: {}", t1, t2);
 
-        return if t1 < 0.0 && t2 < 0.0 {
-            None
+        let t = if t1 < 0.0 && t2 < 0.0 {
+            return None;
         } else if t1 < closest_dist && (t2 < 0.0 || t1 < t2) {
-            let hit_pos = ray.at(t1);
-            Some(Rayhit::new(
-                t1,
-                hit_pos,
-                self.normal(hit_pos),
-                Rc::clone(&self.material),
-                self,
-            ))
+            t1
         } else if t2 < closest_dist && (t1 < 0.0 || t2 < t1) {
-            let hit_pos = ray.at(t2);
-            Some(Rayhit::new(
-                t2,
-                hit_pos,
-                self.normal(hit_pos),
-                Rc::clone(&self.material),
-                self,
-            ))
+            t2
         } else {
-            None
+            return None;
         };
+        let hit_pos = ray.at(t);
+        let normal = self.normal(hit_pos);
+        let (uv, tangent, bitangent) = self.parameterize(&normal);
+        let curvature = 1.0 / self.radius;
+        let mut hit = Rayhit::new(t, hit_pos, normal, Arc::clone(&self.material), self);
+        hit.uv = uv;
+        hit.tangent = tangent;
+        hit.bitangent = bitangent;
+        // The normal is the position from the centre over the radius
+        hit.dndp = Matrix::identity().scale(curvature);
+        hit.local_tangent = tangent;
+        hit.local_bitangent = bitangent;
+        return Some(hit);
     }
 
     fn normal(self: &Sphere, position: Point3D) -> Vector3D {
         return (position - self.origin).normalized();
     }
+
+    fn bounds(&self) -> Aabb {
+        let extent = Vector3D::one() * self.radius;
+        return Aabb::new(self.origin - extent, self.origin + extent);
+    }
+
+    fn is_emissive(&self) -> bool {
+        return self.material.is_emissive();
+    }
+
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SurfaceSample> {
+        let normal = sampling::uniform_sphere(u1, u2);
+        return Some(SurfaceSample {
+            pos: self.origin + normal * self.radius,
+            normal,
+            pdf: 1.0 / (4.0 * PI * self.radius * self.radius),
+            material: Arc::clone(&self.material),
+        });
+    }
+}
+
+impl Sphere {
+    // Wraps uv coordinates around the sphere like a globe. u goes once
+    // around the vertical axis, starting and ending at the back (+z), and v
+    // goes up from the bottom pole to the top one. Also returns how the
+    // position changes with u and v.
+    fn parameterize(&self, normal: &Vector3D) -> (Vector2D, Vector3D, Vector3D) {
+        let phi = f32::atan2(normal.x(), -normal.z());
+        let theta = normal.y().clamp(-1.0, 1.0).acos();
+        let uv = Vector2D::new([0.5 + phi / (2.0 * PI), 1.0 - theta / PI]);
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let tangent = Vector3D::new([cos_phi, 0.0, sin_phi]) * (2.0 * PI * self.radius * sin_theta);
+        let bitangent = Vector3D::new([-cos_theta * sin_phi, sin_theta, cos_theta * cos_phi])
+            * (PI * self.radius);
+        return (uv, tangent, bitangent);
+    }
 }
 
 pub struct Triangle {
     pub a: Point3D,
     pub b: Point3D,
     pub c: Point3D,
-    pub material: Rc<Material>,
+    pub material: Arc<Material>,
+}
+
+// Solves for where the ray crosses the plane of the triangle abc with
+// Cramer's rule. Returns the distance along the ray and the barycentric
+// coordinates beta and gamma of b and c if the ray hits inside the triangle.
+pub fn intersect_triangle(
+    a: &Point3D,
+    b: &Point3D,
+    c: &Point3D,
+    ray: &Ray,
+    closest_dist: f32,
+) -> Option<(f32, f32, f32)> {
+    let (xa, xb, xc) = (a, b, c);
+    let a = xa.x() - xb.x();
+    let b = xa.y() - xb.y();
+    let c = xa.z() - xb.z();
+    let d = xa.x() - xc.x();
+    let e = xa.y() - xc.y();
+    let f = xa.z() - xc.z();
+    let g = ray.direction.x();
+    let h = ray.direction.y();
+    let i = ray.direction.z();
+    let j = xa.x() - ray.origin.x();
+    let k = xa.y() - ray.origin.y();
+    let l = xa.z() - ray.origin.z();
+
+    let m = a * (e * i - h * f) + b * (g * f - d * i) + c * (d * h - e * g);
+    let beta = (j * (e * i - h * f) + k * (g * f - d * i) + l * (d * h - e * g)) / m;
+    let gamma = (i * (a * k - j * b) + h * (j * c - a * l) + g * (b * l - k * c)) / m;
+    let t = -(f * (a * k - j * b) + e * (j * c - a * l) + d * (b * l - k * c)) / m;
+
+    return if t < 0.0
+        || t > closest_dist
+        || !(0.0..=1.0).contains(&gamma)
+        || !(0.0..=1.0 - gamma).contains(&beta)
+    {
+        None
+    } else {
+        Some((t, beta, gamma))
+    };
 }
 
 impl Geometry for Triangle {
-    fn intersect(self: Rc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit> {
-        let a = self.a.x() - self.b.x();
-        let b = self.a.y() - self.b.y();
-        let c = self.a.z() - self.b.z();
-        let d = self.a.x() - self.c.x();
-        let e = self.a.y() - self.c.y();
-        let f = self.a.z() - self.c.z();
-        let g = ray.direction.x();
-        let h = ray.direction.y();
-        let i = ray.direction.z();
-        let j = self.a.x() - ray.origin.x();
-        let k = self.a.y() - ray.origin.y();
-        let l = self.a.z() - ray.origin.z();
-
-        let m = a * (e * i - h * f) + b * (g * f - d * i) + c * (d * h - e * g);
-        let beta = (j * (e * i - h * f) + k * (g * f - d * i) + l * (d * h - e * g)) / m;
-        let gamma = (i * (a * k - j * b) + h * (j * c - a * l) + g * (b * l - k * c)) / m;
-        let t = -(f * (a * k - j * b) + e * (j * c - a * l) + d * (b * l - k * c)) / m;
-
-        return if t < 0.0
-            || t > closest_dist
-            || gamma < 0.0
-            || gamma > 1.0
-            || beta < 0.0
-            || beta > 1.0 - gamma
-        {
-            None
-        } else {
-            let hit_pos = ray.at(t);
-            Some(Rayhit::new(
-                t,
-                hit_pos,
-                self.normal(hit_pos),
-                Rc::clone(&self.material),
-                self,
-            ))
-        };
+    fn intersect(self: Arc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit> {
+        let (t, beta, gamma) = intersect_triangle(&self.a, &self.b, &self.c, ray, closest_dist)?;
+        let hit_pos = ray.at(t);
+        // The barycentric coordinates of b and c double as uv coordinates,
+        // with a at the origin
+        let (tangent, bitangent) = (self.b - self.a, self.c - self.a);
+        let mut hit = Rayhit::new(
+            t,
+            hit_pos,
+            self.normal(hit_pos),
+            Arc::clone(&self.material),
+            self,
+        );
+        hit.uv = Vector2D::new([beta, gamma]);
+        hit.tangent = tangent;
+        hit.bitangent = bitangent;
+        hit.local_tangent = tangent;
+        hit.local_bitangent = bitangent;
+        hit.barycentrics = Vector3D::new([1.0 - beta - gamma, beta, gamma]);
+        return Some(hit);
     }
 
     fn normal(self: &Triangle, _position: Point3D) -> Vector3D {
         return (self.c - self.a).cross(&(self.b - self.a)).normalized();
     }
+
+    fn bounds(&self) -> Aabb {
+        return Aabb::new(
+            self.a.min(&self.b).min(&self.c),
+            self.a.max(&self.b).max(&self.c),
+        );
+    }
+
+    fn is_emissive(&self) -> bool {
+        return self.material.is_emissive();
+    }
+
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SurfaceSample> {
+        let (beta, gamma) = sampling::uniform_triangle(u1, u2);
+        let area = (self.c - self.a).cross(&(self.b - self.a)).norm() / 2.0;
+        return Some(SurfaceSample {
+            pos: self.a + (self.b - self.a) * beta + (self.c - self.a) * gamma,
+            normal: self.normal(self.a),
+            pdf: 1.0 / area,
+            material: Arc::clone(&self.material),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raytracer::geometry::material::plain_material;
+
+    fn ray_towards(target: Point3D) -> Ray {
+        return Ray {
+            origin: Point3D::zero(),
+            direction: target.normalized(),
+        };
+    }
+
+    #[test]
+    fn sphere_uvs() {
+        let sphere = Arc::new(Sphere {
+            origin: Point3D::new([0.0, 0.0, 10.0]),
+            radius: 2.0,
+            material: plain_material(),
+        });
+        // The middle of the texture faces the camera
+        let hit = Arc::clone(&sphere)
+            .intersect(&ray_towards(Point3D::new([0.0, 0.0, 1.0])), f32::INFINITY)
+            .unwrap();
+        assert!((hit.uv - Vector2D::new([0.5, 0.5])).norm() < 1e-6);
+        assert!((hit.tangent.normalized() - Vector3D::new([1.0, 0.0, 0.0])).norm() < 1e-6);
+        assert!((hit.bitangent.normalized() - Vector3D::new([0.0, 1.0, 0.0])).norm() < 1e-6);
+
+        // The tangents are how far the surface moves for a change in uv
+        let hit = Arc::clone(&sphere)
+            .intersect(&ray_towards(Point3D::new([0.5, 0.8, 9.0])), f32::INFINITY)
+            .unwrap();
+        let step = 1e-3;
+        let along = |du: f32, dv: f32| {
+            let u = 2.0 * PI * (hit.uv[0] + du - 0.5);
+            let theta = PI * (1.0 - hit.uv[1] - dv);
+            let normal =
+                Vector3D::new([theta.sin() * u.sin(), theta.cos(), -theta.sin() * u.cos()]);
+            return sphere.origin + normal * sphere.radius;
+        };
+        assert!(((along(0.0, 0.0) - hit.pos).norm()) < 1e-4);
+        let tangent = (along(step, 0.0) - along(-step, 0.0)) / (2.0 * step);
+        let bitangent = (along(0.0, step) - along(0.0, -step)) / (2.0 * step);
+        assert!((tangent - hit.tangent).norm() < 1e-2 * hit.tangent.norm());
+        assert!((bitangent - hit.bitangent).norm() < 1e-2 * hit.bitangent.norm());
+        assert_eq!(hit.barycentrics, Vector3D::zero());
+    }
+
+    #[test]
+    fn triangle_uvs() {
+        let triangle = Arc::new(Triangle {
+            a: Point3D::new([0.0, 0.0, 5.0]),
+            b: Point3D::new([2.0, 0.0, 5.0]),
+            c: Point3D::new([0.0, 4.0, 5.0]),
+            material: plain_material(),
+        });
+        let hit = triangle
+            .intersect(&ray_towards(Point3D::new([0.5, 1.0, 5.0])), f32::INFINITY)
+            .unwrap();
+        assert!((hit.uv - Vector2D::new([0.25, 0.25])).norm() < 1e-6);
+        assert!((hit.barycentrics - Vector3D::new([0.5, 0.25, 0.25])).norm() < 1e-6);
+        assert_eq!(hit.tangent, Vector3D::new([2.0, 0.0, 0.0]));
+        assert_eq!(hit.bitangent, Vector3D::new([0.0, 4.0, 0.0]));
+    }
 }
@@ -0,0 +1,449 @@
+use std::sync::Arc;
+
+use crate::matrix::vector::Point3D;
+use crate::matrix::vector::Vector3D;
+use crate::raytracer::texture::TexturePoint;
+
+use super::same_object;
+use super::Geometry;
+use super::Ray;
+use super::Rayhit;
+use super::Surface;
+
+// Objects are split into bins along an axis when searching for the best split
+const SAH_BINS: usize = 16;
+// Nodes with this many objects or fewer always become leaves
+const MAX_LEAF_SIZE: usize = 4;
+// Cost of visiting a node relative to intersecting one object
+const TRAVERSAL_COST: f32 = 0.125;
+
+// Axis aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3D,
+    pub max: Point3D,
+}
+
+impl Aabb {
+    pub fn new(min: Point3D, max: Point3D) -> Aabb {
+        return Aabb { min, max };
+    }
+
+    // A box containing nothing. Growing it by anything gives that thing's bounds.
+    pub fn empty() -> Aabb {
+        return Aabb {
+            min: Point3D::one() * f32::INFINITY,
+            max: Point3D::one() * f32::NEG_INFINITY,
+        };
+    }
+
+    pub fn infinite() -> Aabb {
+        return Aabb {
+            min: Point3D::one() * f32::NEG_INFINITY,
+            max: Point3D::one() * f32::INFINITY,
+        };
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        return Aabb {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        };
+    }
+
+    pub fn grow(&self, point: &Point3D) -> Aabb {
+        return Aabb {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        };
+    }
+
+    pub fn centroid(&self) -> Point3D {
+        return (self.min + self.max) * 0.5;
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = self.max - self.min;
+        if size.x() < 0.0 || size.y() < 0.0 || size.z() < 0.0 {
+            return 0.0;
+        }
+        return 2.0 * (size.x() * size.y() + size.y() * size.z() + size.z() * size.x());
+    }
+
+    pub fn is_finite(&self) -> bool {
+        return (0..3).all(|axis| self.min[axis].is_finite() && self.max[axis].is_finite());
+    }
+
+    // Returns the distance at which the ray enters the box, if it does so
+    // before closest_dist. inv_direction is 1 / ray.direction per component.
+    pub fn hit(&self, ray: &Ray, inv_direction: &Vector3D, closest_dist: f32) -> Option<f32> {
+        let mut t_min = 0.0;
+        let mut t_max = closest_dist;
+        for axis in 0..3 {
+            let t1 = (self.min[axis] - ray.origin[axis]) * inv_direction[axis];
+            let t2 = (self.max[axis] - ray.origin[axis]) * inv_direction[axis];
+            // NaNs from 0 * infinity fall through min/max without shrinking the range
+            t_min = f32::max(t_min, f32::min(t1, t2));
+            t_max = f32::min(t_max, f32::max(t1, t2));
+        }
+        return if t_min <= t_max { Some(t_min) } else { None };
+    }
+}
+
+enum BvhNodeKind {
+    // Objects first..first + count in the object list
+    Leaf { first: usize, count: usize },
+    // The first child always directly follows its parent in the node list
+    Interior { second_child: usize, axis: usize },
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    kind: BvhNodeKind,
+}
+
+struct BuildItem {
+    bounds: Aabb,
+    centroid: Point3D,
+    object: Arc<dyn Geometry>,
+}
+
+// Bounding volume hierarchy built with the surface area heuristic.
+// It is a drop-in container for any geometry, and is itself geometry, so
+// BVHs can be nested.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    objects: Vec<Arc<dyn Geometry>>,
+    // Things without finite bounds can't be put in a box, so they are
+    // checked on every ray.
+    unbounded: Vec<Arc<dyn Geometry>>,
+}
+
+impl Bvh {
+    pub fn new(objects: Vec<Arc<dyn Geometry>>) -> Bvh {
+        let mut items = Vec::new();
+        let mut unbounded = Vec::new();
+        for object in objects {
+            let bounds = object.bounds();
+            if bounds.is_finite() {
+                items.push(BuildItem {
+                    bounds,
+                    centroid: bounds.centroid(),
+                    object,
+                });
+            } else {
+                unbounded.push(object);
+            }
+        }
+
+        let mut nodes = Vec::new();
+        if !items.is_empty() {
+            Bvh::build(&mut items, 0, &mut nodes);
+        }
+        return Bvh {
+            nodes,
+            objects: items.into_iter().map(|item| item.object).collect(),
+            unbounded,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.objects.len() + self.unbounded.len();
+    }
+
+    // Builds the subtree for items, whose first element is at offset in the final object list
+    fn build(items: &mut [BuildItem], offset: usize, nodes: &mut Vec<BvhNode>) {
+        let bounds = items
+            .iter()
+            .fold(Aabb::empty(), |bounds, item| bounds.union(&item.bounds));
+        let index = nodes.len();
+        nodes.push(BvhNode {
+            bounds,
+            kind: BvhNodeKind::Leaf {
+                first: offset,
+                count: items.len(),
+            },
+        });
+
+        if items.len() <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let split = match Bvh::find_split(items, &bounds) {
+            Some(split) => split,
+            None => return,
+        };
+        let (axis, mid) = split;
+
+        Bvh::build(&mut items[..mid], offset, nodes);
+        let second_child = nodes.len();
+        Bvh::build(&mut items[mid..], offset + mid, nodes);
+        nodes[index].kind = BvhNodeKind::Interior { second_child, axis };
+    }
+
+    // Picks the cheapest split according to the surface area heuristic and
+    // partitions the items around it. Returns None if a leaf is cheaper.
+    fn find_split(items: &mut [BuildItem], bounds: &Aabb) -> Option<(usize, usize)> {
+        let centroid_bounds = items
+            .iter()
+            .fold(Aabb::empty(), |bounds, item| bounds.grow(&item.centroid));
+        let extent = centroid_bounds.max - centroid_bounds.min;
+
+        let mut best: Option<(f32, usize, usize)> = None; // (cost, axis, bin)
+        for axis in 0..3 {
+            if extent[axis] <= 0.0 {
+                continue;
+            }
+            let mut bin_bounds = [Aabb::empty(); SAH_BINS];
+            let mut bin_counts = [0; SAH_BINS];
+            for item in items.iter() {
+                let bin = Bvh::bin(item, axis, &centroid_bounds);
+                bin_bounds[bin] = bin_bounds[bin].union(&item.bounds);
+                bin_counts[bin] += 1;
+            }
+
+            // Sweep from the right so each split can be costed in one pass from the left
+            let mut right_area = [0.0; SAH_BINS];
+            let mut right_count = [0; SAH_BINS];
+            let mut right_bounds = Aabb::empty();
+            let mut count = 0;
+            for bin in (1..SAH_BINS).rev() {
+                right_bounds = right_bounds.union(&bin_bounds[bin]);
+                count += bin_counts[bin];
+                right_area[bin] = right_bounds.surface_area();
+                right_count[bin] = count;
+            }
+
+            let mut left_bounds = Aabb::empty();
+            let mut left_count = 0;
+            for bin in 1..SAH_BINS {
+                left_bounds = left_bounds.union(&bin_bounds[bin - 1]);
+                left_count += bin_counts[bin - 1];
+                if left_count == 0 || right_count[bin] == 0 {
+                    continue;
+                }
+                let cost = left_bounds.surface_area() * left_count as f32
+                    + right_area[bin] * right_count[bin] as f32;
+                if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, axis, bin));
+                }
+            }
+        }
+
+        let (cost, axis, bin) = best?;
+        let area = bounds.surface_area();
+        let split_cost = if area > 0.0 {
+            TRAVERSAL_COST + cost / area
+        } else {
+            TRAVERSAL_COST
+        };
+        if split_cost >= items.len() as f32 {
+            return None;
+        }
+
+        // Partition the items so everything left of the split comes first
+        let mut mid = 0;
+        for i in 0..items.len() {
+            if Bvh::bin(&items[i], axis, &centroid_bounds) < bin {
+                items.swap(i, mid);
+                mid += 1;
+            }
+        }
+        return Some((axis, mid));
+    }
+
+    fn bin(item: &BuildItem, axis: usize, centroid_bounds: &Aabb) -> usize {
+        let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+        let relative = (item.centroid[axis] - centroid_bounds.min[axis]) / extent;
+        return usize::min((relative * SAH_BINS as f32) as usize, SAH_BINS - 1);
+    }
+
+    // Calls visit on every object whose leaf the ray passes through before
+    // closest_dist. visit returns the new closest_dist, or None to stop early.
+    fn traverse<F>(&self, ray: &Ray, closest_dist: f32, mut visit: F)
+    where
+        F: FnMut(&Arc<dyn Geometry>, f32) -> Option<f32>,
+    {
+        let mut closest_dist = closest_dist;
+        for object in self.unbounded.iter() {
+            match visit(object, closest_dist) {
+                Some(dist) => closest_dist = dist,
+                None => return,
+            }
+        }
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inv_direction = Vector3D::new([
+            1.0 / ray.direction.x(),
+            1.0 / ray.direction.y(),
+            1.0 / ray.direction.z(),
+        ]);
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.bounds.hit(ray, &inv_direction, closest_dist).is_none() {
+                continue;
+            }
+            match node.kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for object in self.objects[first..first + count].iter() {
+                        match visit(object, closest_dist) {
+                            Some(dist) => closest_dist = dist,
+                            None => return,
+                        }
+                    }
+                }
+                BvhNodeKind::Interior { second_child, axis } => {
+                    // Visit the nearer child first so hits there can cull the other one
+                    if ray.direction[axis] < 0.0 {
+                        stack.push(index + 1);
+                        stack.push(second_child);
+                    } else {
+                        stack.push(second_child);
+                        stack.push(index + 1);
+                    }
+                }
+            }
+        }
+    }
+
+    // How much light makes it dist along the ray without being blocked.
+    // Transparent objects let some of it through.
+    pub fn transmittance(&self, ray: &Ray, dist: f32, ignore: Option<&Surface>) -> f32 {
+        let mut light_amount = 1.0;
+        self.traverse(ray, dist, |object, closest_dist| {
+            if let Some(shadow_hit) = Arc::clone(object).intersect_ignoring(ray, dist, ignore) {
+                light_amount *= 1.0
+                    - shadow_hit
+                        .material
+                        .at(&TexturePoint::of(&shadow_hit))
+                        .opacity();
+                if light_amount <= 0.0 {
+                    light_amount = 0.0;
+                    return None;
+                }
+            }
+            return Some(closest_dist);
+        });
+        return light_amount;
+    }
+}
+
+impl Geometry for Bvh {
+    fn intersect(self: Arc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit> {
+        return self.intersect_ignoring(ray, closest_dist, None);
+    }
+
+    // The normal comes from whichever object inside was hit
+    fn normal(&self, _position: Point3D) -> Vector3D {
+        return Vector3D::zero();
+    }
+
+    fn bounds(&self) -> Aabb {
+        return match self.nodes.first() {
+            Some(root) if self.unbounded.is_empty() => root.bounds,
+            None if self.unbounded.is_empty() => Aabb::empty(),
+            _ => Aabb::infinite(),
+        };
+    }
+
+    fn intersect_ignoring(
+        self: Arc<Self>,
+        ray: &Ray,
+        closest_dist: f32,
+        ignore: Option<&Surface>,
+    ) -> Option<Rayhit> {
+        if let Some(ignore) = ignore {
+            if same_object(&(Arc::clone(&self) as Arc<dyn Geometry>), &ignore.obj) {
+                return None;
+            }
+        }
+        let mut closest_hit: Option<Rayhit> = None;
+        self.traverse(ray, closest_dist, |object, closest_dist| {
+            return match Arc::clone(object).intersect_ignoring(ray, closest_dist, ignore) {
+                Some(hit) => {
+                    let dist = hit.dist;
+                    closest_hit = Some(hit);
+                    Some(dist)
+                }
+                None => Some(closest_dist),
+            };
+        });
+        return closest_hit;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raytracer::geometry::material::plain_material;
+    use crate::raytracer::geometry::Sphere;
+
+    fn spheres() -> Vec<Arc<dyn Geometry>> {
+        let material = plain_material();
+        let mut objects: Vec<Arc<dyn Geometry>> = Vec::new();
+        for x in -5..5 {
+            for y in -5..5 {
+                for z in 0..10 {
+                    objects.push(Arc::new(Sphere {
+                        origin: Point3D::new([x as f32 * 3.0, y as f32 * 3.0, z as f32 * 3.0]),
+                        radius: 1.0,
+                        material: Arc::clone(&material),
+                    }));
+                }
+            }
+        }
+        return objects;
+    }
+
+    #[test]
+    fn aabb_hit() {
+        let aabb = Aabb::new(
+            Point3D::new([-1.0, -1.0, 4.0]),
+            Point3D::new([1.0, 1.0, 6.0]),
+        );
+        let ray = Ray {
+            origin: Point3D::zero(),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let inv_direction = Vector3D::new([f32::INFINITY, f32::INFINITY, 1.0]);
+        assert_eq!(aabb.hit(&ray, &inv_direction, f32::INFINITY), Some(4.0));
+        assert_eq!(aabb.hit(&ray, &inv_direction, 3.0), None);
+        assert_eq!(aabb.surface_area(), 24.0);
+    }
+
+    #[test]
+    fn matches_linear_search() {
+        let objects = spheres();
+        let bvh = Arc::new(Bvh::new(objects.clone()));
+        assert_eq!(bvh.len(), objects.len());
+
+        for i in 0..200 {
+            let angle = i as f32 * 0.1;
+            let ray = Ray {
+                origin: Point3D::new([angle.sin() * 2.0, angle.cos() * 3.0, -20.0]),
+                direction: Vector3D::new([angle.cos() * 0.4, (angle * 0.7).sin() * 0.4, 1.0]),
+            };
+
+            let mut expected: Option<Rayhit> = None;
+            for object in objects.iter() {
+                let closest_dist = expected.as_ref().map_or(f32::INFINITY, |hit| hit.dist);
+                if let Some(hit) = Arc::clone(object).intersect(&ray, closest_dist) {
+                    expected = Some(hit);
+                }
+            }
+
+            let actual = Arc::clone(&bvh).intersect(&ray, f32::INFINITY);
+            match (expected, actual) {
+                (Some(expected), Some(actual)) => {
+                    assert_eq!(expected.dist, actual.dist);
+                    assert!(same_object(&expected.obj, &actual.obj));
+                }
+                (None, None) => {}
+                _ => panic!("BVH disagrees with linear search for {}", ray),
+            }
+        }
+    }
+}
@@ -1,15 +1,66 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
 use crate::image::Color;
+use crate::matrix::vector::{Vector2D, Vector3D};
+use crate::raytracer::bsdf::{Bsdf, BsdfModel};
+use crate::raytracer::geometry::Rayhit;
+use crate::raytracer::sampling;
+use crate::raytracer::spectrum::Dispersion;
+use crate::raytracer::texture::{Texture, TexturePoint};
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Material {
     pub color: Color,
     pub diffuse: f32,
     pub specular: f32,
     pub specular_n: i32,
     pub reflectivity: f32,
+    // Index of refraction of whatever's inside, for transparent materials
+    pub ior: f32,
+    // How ior changes with wavelength, when rendering spectrally
+    pub dispersion: Option<Dispersion>,
+    // Light given off by the surface, which makes whatever it's on an area
+    // light. Channels can go above 1.
+    pub emission: Color,
+    // A physically based surface to use instead of the Phong terms above.
+    // roughness goes from 0 for polished to 1.
+    pub model: Option<BsdfModel>,
+    pub roughness: f32,
+    // Textures looked up at each hit. The color texture tints color, and
+    // the others replace specular and roughness.
+    pub color_texture: Option<Arc<dyn Texture>>,
+    pub specular_texture: Option<Arc<dyn Texture>>,
+    pub roughness_texture: Option<Arc<dyn Texture>>,
+    // Fine detail that tilts the normal without changing the geometry. Normal
+    // maps give the normal in the surface's tangent space, while bump maps
+    // give a height, with bump_height the height of white.
+    pub normal_map: Option<Arc<dyn Texture>>,
+    pub bump_map: Option<Arc<dyn Texture>>,
+    pub bump_height: f32,
     // pub tint: bool,
-    // Hack, should not be u32, should be image, but image can't be copied, so i need to rethink my structure
-    pub texture: Option<u32>,
+}
+
+// How far apart in uv the bump map is sampled to find its slope
+const BUMP_STEP: f32 = 1e-3;
+
+// Unit vectors along the surface in the directions of increasing u and v
+// as near as possible, at right angles to the normal and each other
+fn tangent_frame(hit: &Rayhit) -> (Vector3D, Vector3D) {
+    let normal = hit.normal;
+    let along = hit.tangent - normal * (hit.tangent * normal);
+    let tangent = if along.norm() > 1e-6 {
+        along.normalized()
+    } else {
+        sampling::basis(&normal).0
+    };
+    let bitangent = normal.cross(&tangent);
+    // The uvs may run either way around the normal
+    return if bitangent * hit.bitangent < 0.0 {
+        (tangent, -bitangent)
+    } else {
+        (tangent, bitangent)
+    };
 }
 
 impl Material {
@@ -20,7 +71,6 @@ impl Material {
         specular_n: i32,
         reflectivity: f32,
         // tint: bool,
-        texture: Option<u32>,
     ) -> Material {
         return Material {
             color,
@@ -28,7 +78,238 @@ impl Material {
             specular,
             specular_n,
             reflectivity,
-            texture,
+            ior: 1.0,
+            dispersion: None,
+            emission: Color::new(0, 0, 0, 0),
+            model: None,
+            roughness: 0.0,
+            color_texture: None,
+            specular_texture: None,
+            roughness_texture: None,
+            normal_map: None,
+            bump_map: None,
+            bump_height: 0.01,
+        };
+    }
+
+    // The material as it is at a point on the surface, with its textures
+    // looked up. Untextured materials are used as they are.
+    pub fn at(&self, point: &TexturePoint) -> Cow<'_, Material> {
+        if self.color_texture.is_none()
+            && self.specular_texture.is_none()
+            && self.roughness_texture.is_none()
+        {
+            return Cow::Borrowed(self);
+        }
+        let mut material = self.clone();
+        if let Some(texture) = &self.color_texture {
+            material.color = self.color * texture.color(point);
+        }
+        if let Some(texture) = &self.specular_texture {
+            material.specular = texture.value(point);
+        }
+        if let Some(texture) = &self.roughness_texture {
+            material.roughness = texture.value(point);
+        }
+        return Cow::Owned(material);
+    }
+
+    // The normal to shade hit with: its own tilted by the material's normal
+    // map, or else by its bump map.
+    pub fn shading_normal(&self, hit: &Rayhit) -> Vector3D {
+        let normal = hit.normal;
+        if let Some(map) = &self.normal_map {
+            // Colors from 0 to 1 stand for -1 to 1 along each axis
+            let color = map.color(&TexturePoint::of(hit));
+            let (tangent, bitangent) = tangent_frame(hit);
+            let tilted = tangent * (2.0 * color.r - 1.0)
+                + bitangent * (2.0 * color.g - 1.0)
+                + normal * (2.0 * color.b - 1.0);
+            return if tilted * normal > 0.0 {
+                tilted.normalized()
+            } else {
+                normal
+            };
+        }
+        if let Some(map) = &self.bump_map {
+            if hit.tangent.norm() == 0.0 || hit.bitangent.norm() == 0.0 {
+                return normal;
+            }
+            let here = TexturePoint::of(hit);
+            let height = |du: f32, dv: f32| {
+                let point = TexturePoint {
+                    uv: here.uv + Vector2D::new([du, dv]),
+                    position: here.position + hit.local_tangent * du + hit.local_bitangent * dv,
+                    footprint: here.footprint,
+                };
+                return map.value(&point) * self.bump_height;
+            };
+            // Steps as big as a pixel's footprint, when it's known, so
+            // distant bumps are smoothed along with the map
+            let step = |axis: usize| {
+                let width =
+                    0.5 * (hit.footprint.duvdx[axis].abs() + hit.footprint.duvdy[axis].abs());
+                return if width > 0.0 { width } else { BUMP_STEP };
+            };
+            let (step_u, step_v) = (step(0), step(1));
+            let slope_u = (height(step_u, 0.0) - height(-step_u, 0.0)) / (2.0 * step_u);
+            let slope_v = (height(0.0, step_v) - height(0.0, -step_v)) / (2.0 * step_v);
+            // The surface raised along its normal by the height
+            let bumped =
+                (hit.tangent + normal * slope_u).cross(&(hit.bitangent + normal * slope_v));
+            if bumped.norm() == 0.0 {
+                return normal;
+            }
+            // The cross product points whichever way the uvs wind
+            return if bumped * normal < 0.0 {
+                -bumped.normalized()
+            } else {
+                bumped.normalized()
+            };
+        }
+        return normal;
+    }
+
+    // The index of refraction for light of a wavelength in nanometres, or
+    // for white light
+    pub fn ior_at(&self, wavelength: Option<f32>) -> f32 {
+        return match (self.dispersion, wavelength) {
+            (Some(dispersion), Some(wavelength)) => dispersion.ior(wavelength),
+            _ => self.ior,
+        };
+    }
+
+    // The surface's bsdf, if it has one, with color in place of the
+    // material's own. eta is the index of refraction on the far side of
+    // the surface over the one on the near side.
+    pub fn bsdf(&self, color: Color, eta: f32) -> Option<Box<dyn Bsdf>> {
+        return self
+            .model
+            .map(|model| model.bsdf(color, self.roughness, eta));
+    }
+
+    // How much light shadow rays lose going through the surface. Light
+    // only gets through physically based surfaces by being traced.
+    pub fn opacity(&self) -> f32 {
+        return match self.model {
+            Some(_) => 1.0,
+            None => self.color.a,
+        };
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        return self.emission.r > 0.0 || self.emission.g > 0.0 || self.emission.b > 0.0;
+    }
+}
+
+// A plain white diffuse material for tests
+#[cfg(test)]
+pub fn plain_material() -> Arc<Material> {
+    return Arc::new(Material::new(
+        Color::new(255, 255, 255, 255),
+        1.0,
+        0.0,
+        0,
+        0.0,
+    ));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::Image;
+    use crate::matrix::matrix::Matrix;
+    use crate::matrix::vector::Point3D;
+    use crate::raytracer::geometry::transformed::Transformed;
+    use crate::raytracer::geometry::{Geometry, Ray, Triangle};
+    use crate::raytracer::texture::procedural::{Pattern, Procedural};
+    use crate::raytracer::texture::ImageTexture;
+
+    // Where a ray straight ahead hits a triangle in the z = 5 plane, facing
+    // back at it, with u along x and v along y. mirrored turns v around.
+    fn hit(material: Material, mirrored: bool) -> Rayhit {
+        let triangle = Arc::new(Triangle {
+            a: Point3D::new([0.0, 0.0, 5.0]),
+            b: Point3D::new([2.0, 0.0, 5.0]),
+            c: Point3D::new([0.0, 2.0, 5.0]),
+            material: Arc::new(material),
+        });
+        let ray = Ray {
+            origin: Point3D::new([0.5, 0.5, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let mut hit = triangle.intersect(&ray, f32::INFINITY).unwrap();
+        if mirrored {
+            hit.bitangent = -hit.bitangent;
+        }
+        return hit;
+    }
+
+    #[test]
+    fn normal_maps() {
+        // One pixel leaning halfway towards +u
+        let pixel = vec![255, 128, 255, 255];
+        let mut material = Material::clone(&plain_material());
+        material.normal_map = Some(Arc::new(ImageTexture::new(Image::from_rgba(1, 1, pixel))));
+        for mirrored in [false, true] {
+            let hit = hit(material.clone(), mirrored);
+            let normal = material.shading_normal(&hit);
+            let tangent = hit.tangent.normalized();
+            assert!((normal * tangent - f32::sqrt(0.5)).abs() < 1e-2);
+            assert!((normal * hit.normal - f32::sqrt(0.5)).abs() < 1e-2);
+        }
+        // Untextured materials leave the normal alone
+        let hit = hit(Material::clone(&plain_material()), false);
+        assert_eq!(hit.material.shading_normal(&hit), hit.normal);
+    }
+
+    // Rising half a unit for every unit along x
+    fn ramp_bumps() -> Material {
+        let ramp = Pattern::Gradient {
+            start: Point3D::new([0.0, 0.0, 0.0]),
+            end: Point3D::new([1.0, 0.0, 0.0]),
+        };
+        let mut material = Material::clone(&plain_material());
+        material.bump_map = Some(Arc::new(Procedural::new(
+            ramp,
+            Color::new(0, 0, 0, 255),
+            Color::new(255, 255, 255, 255),
+            1.0,
+        )));
+        material.bump_height = 0.5;
+        return material;
+    }
+
+    #[test]
+    fn bump_maps() {
+        let material = ramp_bumps();
+        // The normal leans away from the slope, either way round the uvs go
+        let expected = Vector3D::new([-1.0, 0.0, -2.0]).normalized();
+        for mirrored in [false, true] {
+            let hit = hit(material.clone(), mirrored);
+            assert!((material.shading_normal(&hit) - expected).norm() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn rotated_bump_maps() {
+        // The same triangle turned a quarter around the view axis, so the
+        // ramp rises along y in world space and the normal leans with it
+        let material = ramp_bumps();
+        let rotation = Matrix::rotation(&Vector3D::new([0.0, 0.0, 1.0]), 90.0);
+        let triangle: Arc<dyn Geometry> = Arc::new(Triangle {
+            a: Point3D::new([0.0, 0.0, 5.0]),
+            b: Point3D::new([2.0, 0.0, 5.0]),
+            c: Point3D::new([0.0, 2.0, 5.0]),
+            material: Arc::new(material.clone()),
+        });
+        let rotated = Arc::new(Transformed::new(triangle, rotation).unwrap());
+        let ray = Ray {
+            origin: Point3D::new([-0.5, 0.5, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
         };
+        let hit = rotated.intersect(&ray, f32::INFINITY).unwrap();
+        let expected = Vector3D::new([0.0, -1.0, -2.0]).normalized();
+        assert!((material.shading_normal(&hit) - expected).norm() < 1e-3);
     }
 }
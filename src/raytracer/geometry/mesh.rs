@@ -0,0 +1,373 @@
+use std::sync::Arc;
+
+use crate::matrix::vector::Point3D;
+use crate::matrix::vector::Vector2D;
+use crate::matrix::vector::Vector3D;
+
+use super::bvh::{Aabb, Bvh};
+use super::intersect_triangle;
+use super::linear_map;
+use super::material::Material;
+use super::normalized_change;
+use super::Geometry;
+use super::Ray;
+use super::Rayhit;
+use super::Surface;
+use super::SurfaceSample;
+use crate::raytracer::sampling;
+
+// Vertex buffers shared by every face of a mesh. Faces index into the
+// positions, normals and uvs, which all have one entry per vertex.
+pub struct MeshBuffers {
+    pub positions: Vec<Point3D>,
+    pub normals: Vec<Vector3D>,
+    pub uvs: Vec<Vector2D>,
+    pub faces: Vec<[u32; 3]>,
+    pub material: Arc<Material>,
+}
+
+// A single face of a mesh. These are what end up in the mesh's BVH and what
+// gets reported as the hit object.
+struct MeshTriangle {
+    mesh: Arc<MeshBuffers>,
+    face: usize,
+}
+
+impl MeshTriangle {
+    fn vertices(&self) -> [usize; 3] {
+        let face = self.mesh.faces[self.face];
+        return [face[0] as usize, face[1] as usize, face[2] as usize];
+    }
+}
+
+impl Geometry for MeshTriangle {
+    fn intersect(self: Arc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit> {
+        let [a, b, c] = self.vertices();
+        let positions = &self.mesh.positions;
+        let (t, beta, gamma) = intersect_triangle(
+            &positions[a],
+            &positions[b],
+            &positions[c],
+            ray,
+            closest_dist,
+        )?;
+        let alpha = 1.0 - beta - gamma;
+
+        // Blend the vertex attributes with the barycentric coordinates of the hit
+        let normals = &self.mesh.normals;
+        let unnormalized = normals[a] * alpha + normals[b] * beta + normals[c] * gamma;
+        // Without uvs of their own, faces are parameterized like Triangle
+        let uvs = &self.mesh.uvs;
+        let corner_uvs = if uvs.is_empty() {
+            [
+                Vector2D::zero(),
+                Vector2D::new([1.0, 0.0]),
+                Vector2D::new([0.0, 1.0]),
+            ]
+        } else {
+            [uvs[a], uvs[b], uvs[c]]
+        };
+        let uv = corner_uvs[0] * alpha + corner_uvs[1] * beta + corner_uvs[2] * gamma;
+        let (tangent, bitangent) =
+            uv_derivatives([&positions[a], &positions[b], &positions[c]], &corner_uvs);
+
+        // The blended normal changes across the face with the barycentrics,
+        // which rise along the face away from the edges opposite them
+        let (edge1, edge2) = (positions[b] - positions[a], positions[c] - positions[a]);
+        let face = edge1.cross(&edge2);
+        let area_squared = face * face;
+        let dndp = linear_map(|step| {
+            let step_beta = (edge2.cross(&face) * step) / area_squared;
+            let step_gamma = (face.cross(&edge1) * step) / area_squared;
+            let change =
+                (normals[b] - normals[a]) * step_beta + (normals[c] - normals[a]) * step_gamma;
+            return normalized_change(&unnormalized, &change);
+        });
+
+        let material = Arc::clone(&self.mesh.material);
+        let normal = unnormalized.normalized();
+        let mut hit = Rayhit::new(t, ray.at(t), normal, material, self);
+        hit.uv = uv;
+        hit.tangent = tangent;
+        hit.bitangent = bitangent;
+        hit.dndp = dndp;
+        hit.local_tangent = tangent;
+        hit.local_bitangent = bitangent;
+        hit.barycentrics = Vector3D::new([alpha, beta, gamma]);
+        return Some(hit);
+    }
+
+    // The flat face normal. Hits use the smooth interpolated normal instead.
+    fn normal(&self, _position: Point3D) -> Vector3D {
+        let [a, b, c] = self.vertices();
+        let positions = &self.mesh.positions;
+        return face_normal(&positions[a], &positions[b], &positions[c]).normalized();
+    }
+
+    fn bounds(&self) -> Aabb {
+        let [a, b, c] = self.vertices();
+        let positions = &self.mesh.positions;
+        return Aabb::new(positions[a], positions[a])
+            .grow(&positions[b])
+            .grow(&positions[c]);
+    }
+}
+
+// How position changes with u and v across a triangle, from the uvs at its
+// corners. Falls back to the edges out of the first corner when the uvs
+// don't span any area.
+fn uv_derivatives(positions: [&Point3D; 3], uvs: &[Vector2D; 3]) -> (Vector3D, Vector3D) {
+    let (edge1, edge2) = (*positions[1] - *positions[0], *positions[2] - *positions[0]);
+    let (du1, dv1) = (uvs[1][0] - uvs[0][0], uvs[1][1] - uvs[0][1]);
+    let (du2, dv2) = (uvs[2][0] - uvs[0][0], uvs[2][1] - uvs[0][1]);
+    let determinant = du1 * dv2 - dv1 * du2;
+    if determinant.abs() < 1e-12 {
+        return (edge1, edge2);
+    }
+    let tangent = (edge1 * dv2 - edge2 * dv1) / determinant;
+    let bitangent = (edge2 * du1 - edge1 * du2) / determinant;
+    return (tangent, bitangent);
+}
+
+// Unnormalized normal, with the same winding as Triangle. Its length is
+// twice the face area.
+fn face_normal(a: &Point3D, b: &Point3D, c: &Point3D) -> Vector3D {
+    return (*c - *a).cross(&(*b - *a));
+}
+
+// Indexed triangle mesh with smooth shading. Every face shares one set of
+// vertex buffers and one material.
+pub struct TriangleMesh {
+    buffers: Arc<MeshBuffers>,
+    bvh: Arc<Bvh>,
+    // The running total of face areas, for picking faces by their area
+    area_sums: Vec<f32>,
+}
+
+impl TriangleMesh {
+    // If normals are not given, each vertex gets the area weighted average of
+    // the faces around it. uvs may be empty if the mesh has none.
+    pub fn new(
+        positions: Vec<Point3D>,
+        normals: Option<Vec<Vector3D>>,
+        uvs: Vec<Vector2D>,
+        faces: Vec<[u32; 3]>,
+        material: Arc<Material>,
+    ) -> TriangleMesh {
+        let normals = match normals {
+            Some(normals) => normals,
+            None => TriangleMesh::vertex_normals(&positions, &faces),
+        };
+        assert_eq!(normals.len(), positions.len());
+        assert!(uvs.is_empty() || uvs.len() == positions.len());
+
+        let buffers = Arc::new(MeshBuffers {
+            positions,
+            normals,
+            uvs,
+            faces,
+            material,
+        });
+        let triangles = (0..buffers.faces.len())
+            .map(|face| {
+                Arc::new(MeshTriangle {
+                    mesh: Arc::clone(&buffers),
+                    face,
+                }) as Arc<dyn Geometry>
+            })
+            .collect();
+        let mut area = 0.0;
+        let area_sums = buffers
+            .faces
+            .iter()
+            .map(|face| {
+                let [a, b, c] = face.map(|index| buffers.positions[index as usize]);
+                area += face_normal(&a, &b, &c).norm() / 2.0;
+                area
+            })
+            .collect();
+        return TriangleMesh {
+            buffers,
+            bvh: Arc::new(Bvh::new(triangles)),
+            area_sums,
+        };
+    }
+
+    pub fn vertex_normals(positions: &[Point3D], faces: &[[u32; 3]]) -> Vec<Vector3D> {
+        let mut normals = vec![Vector3D::zero(); positions.len()];
+        for face in faces {
+            let [a, b, c] = face.map(|index| index as usize);
+            let normal = face_normal(&positions[a], &positions[b], &positions[c]);
+            for vertex in [a, b, c] {
+                normals[vertex] = normals[vertex] + normal;
+            }
+        }
+        return normals
+            .into_iter()
+            .map(|normal| {
+                if normal.norm_squared() > 0.0 {
+                    normal.normalized()
+                } else {
+                    normal
+                }
+            })
+            .collect();
+    }
+
+    #[cfg(test)]
+    pub fn buffers(&self) -> &MeshBuffers {
+        return &self.buffers;
+    }
+
+    pub fn area(&self) -> f32 {
+        return self.area_sums.last().copied().unwrap_or(0.0);
+    }
+}
+
+impl Geometry for TriangleMesh {
+    fn intersect(self: Arc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit> {
+        return Arc::clone(&self.bvh).intersect(ray, closest_dist);
+    }
+
+    // The normal comes from whichever face was hit
+    fn normal(&self, _position: Point3D) -> Vector3D {
+        return Vector3D::zero();
+    }
+
+    fn bounds(&self) -> Aabb {
+        return self.bvh.bounds();
+    }
+
+    // Faces are the hit objects, so pass ignore down to them
+    fn intersect_ignoring(
+        self: Arc<Self>,
+        ray: &Ray,
+        closest_dist: f32,
+        ignore: Option<&Surface>,
+    ) -> Option<Rayhit> {
+        return Arc::clone(&self.bvh).intersect_ignoring(ray, closest_dist, ignore);
+    }
+
+    fn is_emissive(&self) -> bool {
+        return self.buffers.material.is_emissive();
+    }
+
+    // Picks a face by its area with u1, then reuses what's left of u1 to pick
+    // a point on it
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        let target = u1 * area;
+        let face = self
+            .area_sums
+            .partition_point(|&sum| sum <= target)
+            .min(self.area_sums.len() - 1);
+        let start = if face == 0 {
+            0.0
+        } else {
+            self.area_sums[face - 1]
+        };
+        let face_area = self.area_sums[face] - start;
+        let u1 = ((target - start) / face_area).clamp(0.0, 1.0);
+
+        let [a, b, c] =
+            self.buffers.faces[face].map(|index| self.buffers.positions[index as usize]);
+        let (beta, gamma) = sampling::uniform_triangle(u1, u2);
+        return Some(SurfaceSample {
+            pos: a + (b - a) * beta + (c - a) * gamma,
+            normal: face_normal(&a, &b, &c).normalized(),
+            pdf: 1.0 / area,
+            material: Arc::clone(&self.buffers.material),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raytracer::geometry::material::plain_material;
+
+    // A unit square in the z = 5 plane, facing back towards the origin
+    fn square(normals: Option<Vec<Vector3D>>) -> Arc<TriangleMesh> {
+        let material = plain_material();
+        return Arc::new(TriangleMesh::new(
+            vec![
+                Point3D::new([0.0, 0.0, 5.0]),
+                Point3D::new([1.0, 0.0, 5.0]),
+                Point3D::new([1.0, 1.0, 5.0]),
+                Point3D::new([0.0, 1.0, 5.0]),
+            ],
+            normals,
+            vec![
+                Vector2D::new([0.0, 0.0]),
+                Vector2D::new([1.0, 0.0]),
+                Vector2D::new([1.0, 1.0]),
+                Vector2D::new([0.0, 1.0]),
+            ],
+            vec![[0, 1, 2], [0, 2, 3]],
+            material,
+        ));
+    }
+
+    fn ray_at(x: f32, y: f32) -> Ray {
+        return Ray {
+            origin: Point3D::new([x, y, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+    }
+
+    #[test]
+    fn computed_normals() {
+        let mesh = square(None);
+        for normal in &mesh.buffers().normals {
+            assert_eq!(*normal, Vector3D::new([0.0, 0.0, -1.0]));
+        }
+        let hit = mesh.intersect(&ray_at(0.25, 0.5), f32::INFINITY).unwrap();
+        assert_eq!(hit.dist, 5.0);
+        assert_eq!(hit.normal, Vector3D::new([0.0, 0.0, -1.0]));
+    }
+
+    #[test]
+    fn interpolated_attributes() {
+        let left = Vector3D::new([-1.0, 0.0, -1.0]).normalized();
+        let right = Vector3D::new([1.0, 0.0, -1.0]).normalized();
+        let mesh = square(Some(vec![left, right, right, left]));
+
+        let hit = Arc::clone(&mesh)
+            .intersect(&ray_at(0.5, 0.25), f32::INFINITY)
+            .unwrap();
+        assert!((hit.normal - Vector3D::new([0.0, 0.0, -1.0])).norm() < 1e-6);
+        assert!((hit.uv - Vector2D::new([0.5, 0.25])).norm() < 1e-6);
+        // The uvs follow x and y across the square
+        assert!((hit.tangent - Vector3D::new([1.0, 0.0, 0.0])).norm() < 1e-6);
+        assert!((hit.bitangent - Vector3D::new([0.0, 1.0, 0.0])).norm() < 1e-6);
+        let weights = hit.barycentrics;
+        assert!((weights.x() + weights.y() + weights.z() - 1.0).abs() < 1e-6);
+        assert!((weights.x() - 0.5).abs() < 1e-6);
+
+        assert!(mesh.intersect(&ray_at(1.5, 0.5), f32::INFINITY).is_none());
+    }
+
+    #[test]
+    fn surface_samples() {
+        let mesh = square(None);
+        assert_eq!(mesh.area(), 1.0);
+        let mut rng = sampling::Rng::new(5, 0);
+        let mut below_diagonal = 0;
+        for _ in 0..10000 {
+            let sample = mesh.sample_surface(rng.next_f32(), rng.next_f32()).unwrap();
+            assert_eq!(sample.pdf, 1.0);
+            assert_eq!(sample.normal, Vector3D::new([0.0, 0.0, -1.0]));
+            assert_eq!(sample.pos.z(), 5.0);
+            assert!((0.0..=1.0).contains(&sample.pos.x()));
+            assert!((0.0..=1.0).contains(&sample.pos.y()));
+            if sample.pos.y() < sample.pos.x() {
+                below_diagonal += 1;
+            }
+        }
+        // Both faces have the same area, so get the same share of points
+        assert!((below_diagonal as f32 / 10000.0 - 0.5).abs() < 0.02);
+    }
+}
@@ -0,0 +1,315 @@
+use std::sync::Arc;
+
+use crate::matrix::matrix::Matrix;
+use crate::matrix::vector::Point3D;
+use crate::matrix::vector::Vector3D;
+
+use super::bvh::Aabb;
+use super::linear_map;
+use super::normalized_change;
+use super::same_object;
+use super::Geometry;
+use super::Ray;
+use super::Rayhit;
+use super::Surface;
+use super::SurfaceSample;
+use crate::raytracer::sampling;
+
+// Places any geometry in the world with a 4x4 affine transform. Rays are
+// moved into the object's own space to be intersected, and the hit is moved
+// back out again.
+pub struct Transformed {
+    object: Arc<dyn Geometry>,
+    to_world: Matrix<f32, 4, 4>,
+    to_object: Matrix<f32, 4, 4>,
+}
+
+impl Transformed {
+    // Returns None if the transform can't be undone, like a scale by zero
+    pub fn new(object: Arc<dyn Geometry>, to_world: Matrix<f32, 4, 4>) -> Option<Transformed> {
+        let to_object = to_world.inverse()?;
+        return Some(Transformed {
+            object,
+            to_world,
+            to_object,
+        });
+    }
+
+    fn to_object_ray(&self, ray: &Ray) -> Ray {
+        // The direction isn't renormalized, so distances along the ray are
+        // the same in both spaces.
+        return Ray {
+            origin: self.to_object.transform_point(&ray.origin),
+            direction: self.to_object.transform_vector(&ray.direction),
+        };
+    }
+
+    // Normals transform by the inverse transpose to stay perpendicular to
+    // surfaces that have been scaled unevenly.
+    fn to_world_normal(&self, normal: &Vector3D) -> Vector3D {
+        return self
+            .to_object
+            .transpose()
+            .transform_vector(normal)
+            .normalized();
+    }
+
+    // The hit's local position and tangents are left in object space, so
+    // solid textures move with the object. The world space tangents are
+    // stretched along with the surface, and the normal's changes follow it.
+    fn to_world_hit(&self, ray: &Ray, mut hit: Rayhit) -> Rayhit {
+        let normal_matrix = self.to_object.transpose();
+        let unnormalized = normal_matrix.transform_vector(&hit.normal);
+        // A step in the world is a step in the object's space, where the
+        // normal changes, and the change is carried back like the normal
+        let object_dndp = hit.dndp;
+        hit.dndp = linear_map(|step| {
+            let change = object_dndp * self.to_object.transform_vector(&step);
+            return normalized_change(&unnormalized, &normal_matrix.transform_vector(&change));
+        });
+        hit.pos = ray.at(hit.dist);
+        hit.normal = unnormalized.normalized();
+        hit.tangent = self.to_world.transform_vector(&hit.tangent);
+        hit.bitangent = self.to_world.transform_vector(&hit.bitangent);
+        return hit;
+    }
+}
+
+impl Geometry for Transformed {
+    fn intersect(self: Arc<Self>, ray: &Ray, closest_dist: f32) -> Option<Rayhit> {
+        return self.intersect_ignoring(ray, closest_dist, None);
+    }
+
+    fn normal(&self, position: Point3D) -> Vector3D {
+        let local = self.to_object.transform_point(&position);
+        return self.to_world_normal(&self.object.normal(local));
+    }
+
+    fn bounds(&self) -> Aabb {
+        let bounds = self.object.bounds();
+        if !bounds.is_finite() {
+            return Aabb::infinite();
+        }
+        // Bound all 8 corners of the object's box once they've been moved
+        let mut result = Aabb::empty();
+        for corner in 0..8 {
+            let pick = |axis: usize| {
+                if corner & (1 << axis) == 0 {
+                    bounds.min[axis]
+                } else {
+                    bounds.max[axis]
+                }
+            };
+            let point = Point3D::new([pick(0), pick(1), pick(2)]);
+            result = result.grow(&self.to_world.transform_point(&point));
+        }
+        return result;
+    }
+
+    // One object can be placed many times, so hits record which placement
+    // they came through. A ray leaving one placement skips the part of the
+    // object it left, but can hit the rest of it and every other placement.
+    // Placements inside this one were matched on the way in, since only the
+    // outermost is recorded.
+    fn intersect_ignoring(
+        self: Arc<Self>,
+        ray: &Ray,
+        closest_dist: f32,
+        ignore: Option<&Surface>,
+    ) -> Option<Rayhit> {
+        let inner_ignore = match ignore {
+            Some(Surface {
+                obj,
+                instance: Some(instance),
+            }) => {
+                if same_object(&(Arc::clone(&self) as Arc<dyn Geometry>), instance) {
+                    Some(Surface {
+                        obj: Arc::clone(obj),
+                        instance: None,
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => ignore.cloned(),
+        };
+        let local_ray = self.to_object_ray(ray);
+        let hit = Arc::clone(&self.object).intersect_ignoring(
+            &local_ray,
+            closest_dist,
+            inner_ignore.as_ref(),
+        )?;
+        let mut hit = self.to_world_hit(ray, hit);
+        hit.instance = Some(self);
+        return Some(hit);
+    }
+
+    fn is_emissive(&self) -> bool {
+        return self.object.is_emissive();
+    }
+
+    // Even in the object's space, but the transform can stretch some parts
+    // of the surface more than others, which changes the density
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SurfaceSample> {
+        let sample = self.object.sample_surface(u1, u2)?;
+        let (tangent, bitangent) = sampling::basis(&sample.normal);
+        let stretch = self
+            .to_world
+            .transform_vector(&tangent)
+            .cross(&self.to_world.transform_vector(&bitangent))
+            .norm();
+        return Some(SurfaceSample {
+            pos: self.to_world.transform_point(&sample.pos),
+            normal: self.to_world_normal(&sample.normal),
+            pdf: sample.pdf / stretch,
+            material: sample.material,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raytracer::geometry::bvh::Bvh;
+    use crate::raytracer::geometry::material::plain_material;
+    use crate::raytracer::geometry::mesh::TriangleMesh;
+    use crate::raytracer::geometry::Sphere;
+    use std::f32::consts::PI;
+
+    fn unit_sphere() -> Arc<dyn Geometry> {
+        return Arc::new(Sphere {
+            origin: Point3D::zero(),
+            radius: 1.0,
+            material: plain_material(),
+        });
+    }
+
+    #[test]
+    fn scaled_and_moved_sphere() {
+        // An ellipsoid stretched along x, moved 10 units forward
+        let transform = Matrix::translation(&Vector3D::new([0.0, 0.0, 10.0]))
+            .multiply(Matrix::scaling(&Vector3D::new([4.0, 1.0, 1.0])));
+        let ellipsoid = Arc::new(Transformed::new(unit_sphere(), transform).unwrap());
+
+        let ray = Ray {
+            origin: Point3D::zero(),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let hit = Arc::clone(&ellipsoid)
+            .intersect(&ray, f32::INFINITY)
+            .unwrap();
+        assert!((hit.dist - 9.0).abs() < 1e-5);
+        assert!((hit.normal - Vector3D::new([0.0, 0.0, -1.0])).norm() < 1e-5);
+        // but is still at the front of the unit sphere underneath, which
+        // has been stretched around it
+        assert!((hit.local - Point3D::new([0.0, 0.0, -1.0])).norm() < 1e-5);
+        assert!((hit.tangent - Vector3D::new([8.0 * PI, 0.0, 0.0])).norm() < 1e-4);
+
+        // Off to the side the normal leans towards x much less than the position does
+        let ray = Ray {
+            origin: Point3D::new([2.0, 0.0, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let hit = Arc::clone(&ellipsoid)
+            .intersect(&ray, f32::INFINITY)
+            .unwrap();
+        let expected = Vector3D::new([0.5 / 4.0, 0.0, -f32::sqrt(0.75)]).normalized();
+        assert!((hit.normal - expected).norm() < 1e-5);
+
+        let bounds = ellipsoid.bounds();
+        assert!((bounds.min - Point3D::new([-4.0, -1.0, 9.0])).norm() < 1e-5);
+        assert!((bounds.max - Point3D::new([4.0, 1.0, 11.0])).norm() < 1e-5);
+    }
+
+    #[test]
+    fn instances() {
+        // The same sphere placed twice along z
+        let sphere = unit_sphere();
+        let place = |z: f32| -> Arc<dyn Geometry> {
+            let transform = Matrix::translation(&Vector3D::new([0.0, 0.0, z]));
+            return Arc::new(Transformed::new(Arc::clone(&sphere), transform).unwrap());
+        };
+        let (near, far) = (place(5.0), place(10.0));
+        let ray = Ray {
+            origin: Point3D::zero(),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let hit = Arc::clone(&near).intersect(&ray, f32::INFINITY).unwrap();
+        assert!(same_object(&hit.obj, &sphere));
+        assert!(same_object(hit.instance.as_ref().unwrap(), &near));
+
+        // Ignoring the near one doesn't hide the far one
+        let onward = Ray {
+            origin: Point3D::new([0.0, 0.0, 6.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        assert!(Arc::clone(&near)
+            .intersect_ignoring(&onward, f32::INFINITY, Some(&hit.surface()))
+            .is_none());
+        let hit = Arc::clone(&far)
+            .intersect_ignoring(&onward, f32::INFINITY, Some(&hit.surface()))
+            .unwrap();
+        assert!((hit.dist - 3.0).abs() < 1e-5);
+        assert!(same_object(hit.instance.as_ref().unwrap(), &far));
+    }
+
+    #[test]
+    fn concave_instance() {
+        // Two squares of one mesh facing the origin, at z = 5 and z = 7,
+        // moved along x. A ray leaving the near one still hits the far one.
+        let mut positions = Vec::new();
+        for z in [5.0, 7.0] {
+            for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                positions.push(Point3D::new([x, y, z]));
+            }
+        }
+        let faces = vec![[0, 1, 2], [0, 2, 3], [4, 5, 6], [4, 6, 7]];
+        let mesh = Arc::new(TriangleMesh::new(
+            positions,
+            None,
+            vec![],
+            faces,
+            plain_material(),
+        ));
+        let transform = Matrix::translation(&Vector3D::new([3.0, 0.0, 0.0]));
+        let placed: Arc<dyn Geometry> = Arc::new(Transformed::new(mesh, transform).unwrap());
+        let ray = Ray {
+            origin: Point3D::new([3.2, 0.1, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let hit = Arc::clone(&placed).intersect(&ray, f32::INFINITY).unwrap();
+        assert!((hit.dist - 5.0).abs() < 1e-5);
+        let onward = Ray {
+            origin: hit.pos,
+            direction: ray.direction,
+        };
+        let far = Arc::clone(&placed)
+            .intersect_ignoring(&onward, f32::INFINITY, Some(&hit.surface()))
+            .unwrap();
+        assert!((far.dist - 2.0).abs() < 1e-5);
+        // so light from behind is blocked by the far square
+        let scene = Bvh::new(vec![placed]);
+        assert_eq!(
+            scene.transmittance(&onward, 10.0, Some(&hit.surface())),
+            0.0
+        );
+    }
+
+    #[test]
+    fn stretched_surface_samples() {
+        // Doubling the size of a sphere quarters the density of points on it
+        let transform = Matrix::scaling(&Vector3D::new([2.0, 2.0, 2.0]));
+        let sphere = Transformed::new(unit_sphere(), transform).unwrap();
+        let sample = sphere.sample_surface(0.3, 0.6).unwrap();
+        assert!((sample.pos.norm() - 2.0).abs() < 1e-5);
+        assert!((sample.normal - sample.pos.normalized()).norm() < 1e-5);
+        let area = 4.0 * std::f32::consts::PI * 4.0;
+        assert!((sample.pdf * area - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn singular_transform() {
+        let flat = Matrix::scaling(&Vector3D::new([1.0, 0.0, 1.0]));
+        assert!(Transformed::new(unit_sphere(), flat).is_none());
+    }
+}
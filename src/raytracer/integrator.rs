@@ -0,0 +1,569 @@
+use std::f32::consts::PI;
+
+use crate::image::Color;
+use crate::matrix::vector::Vector3D;
+use crate::raytracer::clamp;
+use crate::raytracer::Shading;
+
+use super::bsdf::Bsdf;
+use super::differential::RayDifferential;
+use super::geometry::material::Material;
+use super::geometry::Ray;
+use super::geometry::Rayhit;
+use super::geometry::Surface;
+use super::light::Lights;
+use super::refraction::{Boundary, Media};
+use super::sampling::Rng;
+use super::scene::Scene;
+use super::texture::TexturePoint;
+
+pub use debug::{DepthView, NormalView, ObjectView};
+pub use occlusion::AmbientOcclusion;
+pub use path::PathTracer;
+
+pub mod debug;
+pub mod occlusion;
+pub mod path;
+
+const AMBIENT: f32 = 0.2;
+// Reflections and refractions that would add less than one step of an 8 bit
+// color channel aren't worth tracing. Glass splits every ray in two, so
+// without this the number of rays grows exponentially with the depth.
+const MIN_WEIGHT: f32 = 1.0 / 256.0;
+// Area lights are sampled on a jittered grid this many points across, which
+// softens their shadows
+const SHADOW_GRID: u32 = 4;
+
+// A way of working out how much light comes back along a camera ray. The
+// renderer picks the rays, and the integrator decides what they see.
+pub trait Integrator: Send + Sync {
+    // The color seen along ray and how many rays were traced to find it.
+    // differential follows the ray to filter the textures it sees.
+    fn trace(
+        &self,
+        ray: &Ray,
+        differential: Option<&RayDifferential>,
+        scene: &Scene,
+        lights: &Lights,
+        rng: &mut Rng,
+    ) -> (Color, u32);
+
+    // Integrators that average random samples want this many camera rays
+    // jittered across each pixel. The rest use the antialiasing grid.
+    fn samples_per_pixel(&self) -> Option<u32> {
+        return None;
+    }
+
+    // Whether the colors are light, which the camera's exposure scales.
+    // Debug views and occlusion show other things.
+    fn measures_light(&self) -> bool {
+        return true;
+    }
+}
+
+impl Shading {
+    // reflections limits how deep reflections go, or how many times a path
+    // can bounce
+    pub fn integrator(self, reflections: u32) -> Box<dyn Integrator> {
+        return match self {
+            Shading::Whitted => Box::new(Whitted { reflections }),
+            Shading::PathTraced(samples) => Box::new(PathTracer {
+                samples,
+                max_bounces: reflections,
+                spectral: false,
+            }),
+            Shading::Spectral(samples) => Box::new(PathTracer {
+                samples,
+                max_bounces: reflections,
+                spectral: true,
+            }),
+            Shading::AmbientOcclusion { samples, distance } => {
+                Box::new(AmbientOcclusion { samples, distance })
+            }
+            Shading::Normals => Box::new(NormalView),
+            Shading::Depth { far } => Box::new(DepthView { far }),
+            Shading::Objects => Box::new(ObjectView),
+        };
+    }
+}
+
+// Direct light from every light source, perfect reflections and a flat
+// ambient term standing in for everything else
+pub struct Whitted {
+    pub reflections: u32,
+}
+
+impl Whitted {
+    pub fn shade(
+        ray: &Ray,
+        differential: Option<&RayDifferential>,
+        hit: &Rayhit,
+        scene: &Scene,
+        lights: &Lights,
+        reflections: u32,
+        media: &Media,
+        weight: f32,
+        rng: &mut Rng,
+    ) -> (Color, u32) {
+        // Textures make the material vary over the surface
+        let material = hit.material.at(&TexturePoint::of(hit));
+        if material.model.is_some() {
+            let boundary = Boundary::new(ray, hit, media, None);
+            if let Some(bsdf) = material.bsdf(material.color, boundary.eta()) {
+                return Whitted::shade_bsdf(
+                    hit,
+                    differential,
+                    &*bsdf,
+                    &boundary,
+                    scene,
+                    lights,
+                    reflections,
+                    media,
+                    weight,
+                    rng,
+                );
+            }
+        }
+        let mut color = Color::new(0, 0, 0, 0);
+        let mut ray_count = 1;
+
+        let boundary = if material.color.a < 1.0 {
+            Some(Boundary::new(ray, hit, media, None))
+        } else {
+            None
+        };
+        // On the way out of a transparent object only the surface between
+        // it and whatever is beyond matters, not how the outside is lit
+        if let Some(boundary) = boundary.as_ref().filter(|boundary| !boundary.entering) {
+            let (crossed_color, crossed_rays) = Whitted::cross(
+                boundary,
+                hit,
+                differential,
+                scene,
+                lights,
+                reflections,
+                media,
+                weight,
+                rng,
+            );
+            return (crossed_color, ray_count + crossed_rays);
+        }
+
+        // Lights give irradiance, which diffuse surfaces spread out over the
+        // hemisphere, hence the divide by pi
+        let reflect = ray.direction - hit.normal * (ray.direction * hit.normal) * 2.0;
+        for light_source in &lights.sources {
+            let light = match light_source.illuminate(&hit.pos) {
+                Some(light) => light,
+                None => continue,
+            };
+            let to_light = light.direction;
+            let ray_to_light = Ray {
+                direction: to_light,
+                origin: hit.pos,
+            };
+
+            let half_angle = (to_light - ray.direction).normalized();
+
+            // How much of the light reaches the hit position?
+            // Don't let an object cast a shadow on itself
+            let light_amount = scene.transmittance(&ray_to_light, light.dist, Some(&hit.surface()));
+
+            let mixed_color = light.color * material.color;
+
+            // TODO: Make this section look less gross
+            let light_ambient = mixed_color * AMBIENT; //TODO: Make a parameter for the raytracer.
+            let light_diffuse =
+                mixed_color * (clamp(hit.normal * to_light) * light_amount * material.diffuse);
+            let light_specular = light.color
+                * (f32::powi(clamp(hit.normal * half_angle), material.specular_n)
+                    * light_amount
+                    * material.specular);
+
+            color =
+                color + ((light_ambient + light_diffuse + light_specular) * (light.irradiance / PI))
+        }
+        let response = |to_light: &Vector3D| Whitted::response(ray, hit, &material, to_light);
+        let area_light = material.emission
+            + Whitted::area_light(hit, scene, &response, rng)
+            + Whitted::environment_light(hit, scene, &response, rng);
+
+        let reflected_weight = weight * material.reflectivity;
+        let light_reflected = if reflections > 0 && reflected_weight >= MIN_WEIGHT {
+            let reflected_differential = differential
+                .map(|differential| differential.reflect(&ray.direction, hit, &hit.normal));
+            let (reflected_color, reflected_rays) = Whitted::trace_from(
+                &Ray {
+                    direction: reflect,
+                    origin: hit.pos,
+                },
+                reflected_differential.as_ref(),
+                scene,
+                lights,
+                reflections - 1,
+                Some(&hit.surface()),
+                media,
+                reflected_weight,
+                rng,
+            );
+            ray_count += reflected_rays;
+            reflected_color
+        } else {
+            material.color
+        } * material.reflectivity;
+        let light_transparent = match &boundary {
+            Some(boundary) => {
+                let transparency = 1.0 - material.color.a;
+                let (crossed_color, crossed_rays) = Whitted::cross(
+                    boundary,
+                    hit,
+                    differential,
+                    scene,
+                    lights,
+                    reflections,
+                    media,
+                    weight * transparency,
+                    rng,
+                );
+                ray_count += crossed_rays;
+                crossed_color
+            }
+            None => Color::new(0, 0, 0, 0),
+        } * (1.0 - material.color.a);
+        // color = color.overlay(passthrough_color);
+        // println!("light intensity: {}", total_intensity);
+
+        return (
+            color + area_light + light_reflected + light_transparent,
+            ray_count,
+        );
+    }
+
+    // Physically based surfaces get direct light weighted by their bsdf.
+    // Only perfectly smooth surfaces carry the ray on, so rough glass only
+    // shows its highlights.
+    fn shade_bsdf(
+        hit: &Rayhit,
+        differential: Option<&RayDifferential>,
+        bsdf: &dyn Bsdf,
+        boundary: &Boundary,
+        scene: &Scene,
+        lights: &Lights,
+        reflections: u32,
+        media: &Media,
+        weight: f32,
+        rng: &mut Rng,
+    ) -> (Color, u32) {
+        let wo = -boundary.direction;
+        let normal = boundary.normal;
+        let scattering = |to_light: &Vector3D| {
+            bsdf.evaluate(&wo, to_light, &normal) * (*to_light * normal).abs()
+        };
+        let mut color = hit.material.emission
+            + Whitted::area_light(hit, scene, &scattering, rng)
+            + Whitted::environment_light(hit, scene, &scattering, rng);
+        let mut ray_count = 1;
+        for light_source in &lights.sources {
+            let light = match light_source.illuminate(&hit.pos) {
+                Some(light) => light,
+                None => continue,
+            };
+            let ray_to_light = Ray {
+                direction: light.direction,
+                origin: hit.pos,
+            };
+            let light_amount = scene.transmittance(&ray_to_light, light.dist, Some(&hit.surface()));
+            color = color
+                + scattering(&light.direction) * light.color * (light_amount * light.irradiance);
+        }
+
+        for (direction, scale) in bsdf.specular_directions(&wo, &normal) {
+            let next_weight = weight * scale.r.max(scale.g).max(scale.b);
+            // Like Phong glass, refracting doesn't count as a reflection
+            let crossing = direction * normal < 0.0;
+            if next_weight < MIN_WEIGHT || (!crossing && reflections == 0) {
+                continue;
+            }
+            let (next_reflections, next_media) = if crossing {
+                (reflections, &boundary.beyond)
+            } else {
+                (reflections - 1, media)
+            };
+            let next_differential =
+                differential.map(|differential| differential.through(boundary, hit, &direction));
+            let (traced_color, traced_rays) = Whitted::trace_from(
+                &boundary.ray_towards(direction),
+                next_differential.as_ref(),
+                scene,
+                lights,
+                next_reflections,
+                None,
+                next_media,
+                next_weight,
+                rng,
+            );
+            color = color + traced_color * scale;
+            ray_count += traced_rays;
+        }
+        return (color, ray_count);
+    }
+
+    // Soft light and shadows from every emissive object, from a jittered
+    // grid of points on each. response gives how much of the light from a
+    // direction the surface sends back along the ray.
+    fn area_light(
+        hit: &Rayhit,
+        scene: &Scene,
+        response: &dyn Fn(&Vector3D) -> Color,
+        rng: &mut Rng,
+    ) -> Color {
+        let mut color = Color::new(0, 0, 0, 0);
+        let samples = SHADOW_GRID * SHADOW_GRID;
+        for emitter in scene.emitters() {
+            for i in 0..samples {
+                let (u1, u2) = Whitted::grid_point(i, rng);
+                let (to_light, incoming) =
+                    match scene.sample_emitter(emitter, &hit.pos, Some(&hit.surface()), u1, u2) {
+                        Some(sample) => sample,
+                        None => continue,
+                    };
+                color = color + response(&to_light) * incoming * (1.0 / samples as f32);
+            }
+        }
+        return color;
+    }
+
+    // Light from the environment, from a jittered grid of directions picked
+    // by how bright they are
+    fn environment_light(
+        hit: &Rayhit,
+        scene: &Scene,
+        response: &dyn Fn(&Vector3D) -> Color,
+        rng: &mut Rng,
+    ) -> Color {
+        let mut color = Color::new(0, 0, 0, 0);
+        let samples = SHADOW_GRID * SHADOW_GRID;
+        for i in 0..samples {
+            let (u1, u2) = Whitted::grid_point(i, rng);
+            let (to_light, incoming, _) =
+                match scene.sample_environment(&hit.pos, Some(&hit.surface()), u1, u2) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+            color = color + response(&to_light) * incoming * (1.0 / samples as f32);
+        }
+        return color;
+    }
+
+    // The i-th cell of the sampling grid, jittered
+    fn grid_point(i: u32, rng: &mut Rng) -> (f32, f32) {
+        let u1 = ((i % SHADOW_GRID) as f32 + rng.next_f32()) / SHADOW_GRID as f32;
+        let u2 = ((i / SHADOW_GRID) as f32 + rng.next_f32()) / SHADOW_GRID as f32;
+        return (u1, u2);
+    }
+
+    // How much light arriving from to_light the surface sends back along
+    // the ray. Normalized so a surface never reflects more than it receives.
+    fn response(ray: &Ray, hit: &Rayhit, material: &Material, to_light: &Vector3D) -> Color {
+        let exponent = material.specular_n;
+        let half_angle = (*to_light - ray.direction).normalized();
+        let diffuse = material.color * (clamp(hit.normal * *to_light) * material.diffuse / PI);
+        let specular = Color::new(255, 255, 255, 255)
+            * (f32::powi(clamp(hit.normal * half_angle), exponent)
+                * material.specular
+                * (exponent as f32 + 8.0)
+                / (8.0 * PI));
+        return diffuse + specular;
+    }
+
+    // Splits the light at a transparent surface between what it reflects
+    // and what refracts through it, by the Fresnel equations
+    fn cross(
+        boundary: &Boundary,
+        hit: &Rayhit,
+        differential: Option<&RayDifferential>,
+        scene: &Scene,
+        lights: &Lights,
+        reflections: u32,
+        media: &Media,
+        weight: f32,
+        rng: &mut Rng,
+    ) -> (Color, u32) {
+        let mut color = Color::new(0, 0, 0, 0);
+        let mut ray_count = 0;
+        let reflectance = boundary.reflectance();
+
+        if reflections > 0 && weight * reflectance >= MIN_WEIGHT {
+            let reflected_ray = boundary.reflected_ray();
+            let reflected_differential = differential
+                .map(|differential| differential.through(boundary, hit, &reflected_ray.direction));
+            let (reflected_color, reflected_rays) = Whitted::trace_from(
+                &reflected_ray,
+                reflected_differential.as_ref(),
+                scene,
+                lights,
+                reflections - 1,
+                None,
+                media,
+                weight * reflectance,
+                rng,
+            );
+            color = color + reflected_color * reflectance;
+            ray_count += reflected_rays;
+        }
+        // Nothing gets through past the critical angle
+        let refracted_ray = boundary
+            .refracted_ray()
+            .filter(|_| weight * (1.0 - reflectance) >= MIN_WEIGHT);
+        if let Some(refracted_ray) = refracted_ray {
+            let refracted_differential = differential
+                .map(|differential| differential.through(boundary, hit, &refracted_ray.direction));
+            let (refracted_color, refracted_rays) = Whitted::trace_from(
+                &refracted_ray,
+                refracted_differential.as_ref(),
+                scene,
+                lights,
+                reflections,
+                None,
+                &boundary.beyond,
+                weight * (1.0 - reflectance),
+                rng,
+            );
+            color = color + refracted_color * (1.0 - reflectance);
+            ray_count += refracted_rays;
+        }
+        return (color, ray_count);
+    }
+
+    pub fn trace_from(
+        ray: &Ray,
+        differential: Option<&RayDifferential>,
+        scene: &Scene,
+        lights: &Lights,
+        reflections: u32,
+        ignore: Option<&Surface>,
+        media: &Media,
+        weight: f32,
+        rng: &mut Rng,
+    ) -> (Color, u32) {
+        let closest_hit = scene.intersect(ray, f32::INFINITY, ignore);
+
+        return match closest_hit {
+            Some(mut hit) => {
+                if let Some(differential) = differential {
+                    hit.footprint = differential.footprint(&hit);
+                }
+                hit.normal = hit.material.shading_normal(&hit);
+                Whitted::shade(
+                    ray,
+                    differential,
+                    &hit,
+                    scene,
+                    lights,
+                    reflections,
+                    media,
+                    weight,
+                    rng,
+                )
+            }
+            None => (scene.background(&ray.direction), 1),
+        };
+    }
+}
+
+impl Integrator for Whitted {
+    fn trace(
+        &self,
+        ray: &Ray,
+        differential: Option<&RayDifferential>,
+        scene: &Scene,
+        lights: &Lights,
+        rng: &mut Rng,
+    ) -> (Color, u32) {
+        return Whitted::trace_from(
+            ray,
+            differential,
+            scene,
+            lights,
+            self.reflections,
+            None,
+            &Media::new(),
+            1.0,
+            rng,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matrix::vector::Point3D;
+    use crate::raytracer::bsdf::BsdfModel;
+    use crate::raytracer::environment::UniformEnvironment;
+    use crate::raytracer::geometry::{Geometry, Sphere};
+    use crate::raytracer::light::PointLight;
+    use std::sync::Arc;
+
+    #[test]
+    fn glass_keeps_all_the_light() {
+        // Clear glass in front of a white background reflects and refracts
+        // everything, so it should look just as bright as the background
+        let background = UniformEnvironment {
+            color: Color::new(255, 255, 255, 255),
+        };
+        let mut glass = Material::new(Color::new(0, 0, 0, 0), 0.0, 0.0, 0, 0.0);
+        glass.ior = 1.5;
+        let ball: Arc<dyn Geometry> = Arc::new(Sphere {
+            origin: Point3D::new([0.0, 0.0, 5.0]),
+            radius: 1.0,
+            material: Arc::new(glass),
+        });
+        let scene = Scene::with_environment(vec![ball], Box::new(background));
+        let lights = Lights::new(vec![Box::new(PointLight {
+            position: Point3D::new([0.0, 5.0, 0.0]),
+            color: Color::new(255, 255, 255, 255),
+            intensity: 1.0,
+        })]);
+
+        let whitted = Whitted { reflections: 10 };
+        let mut rng = Rng::new(0, 0);
+        for x in [0.0, 0.3, 0.6, 0.9] {
+            let ray = Ray {
+                origin: Point3D::new([x, 0.0, 0.0]),
+                direction: Vector3D::new([0.0, 0.0, 1.0]),
+            };
+            let (color, rays) = whitted.trace(&ray, None, &scene, &lights, &mut rng);
+            assert!((color.g - 1.0).abs() < 0.01, "{} at {}", color.g, x);
+            assert!(rays > 2);
+        }
+    }
+
+    #[test]
+    fn dielectric_keeps_all_the_light() {
+        // The same for physically based glass, whose color only tints what
+        // goes through
+        let background = UniformEnvironment {
+            color: Color::new(255, 255, 255, 255),
+        };
+        let mut glass = Material::new(Color::new(255, 255, 255, 255), 0.0, 0.0, 0, 0.0);
+        glass.ior = 1.5;
+        glass.model = Some(BsdfModel::Dielectric);
+        let ball: Arc<dyn Geometry> = Arc::new(Sphere {
+            origin: Point3D::new([0.0, 0.0, 5.0]),
+            radius: 1.0,
+            material: Arc::new(glass),
+        });
+        let scene = Scene::with_environment(vec![ball], Box::new(background));
+
+        let whitted = Whitted { reflections: 10 };
+        let mut rng = Rng::new(0, 0);
+        for x in [0.0, 0.3, 0.6, 0.9] {
+            let ray = Ray {
+                origin: Point3D::new([x, 0.0, 0.0]),
+                direction: Vector3D::new([0.0, 0.0, 1.0]),
+            };
+            let (color, _) = whitted.trace(&ray, None, &scene, &Lights::new(Vec::new()), &mut rng);
+            assert!((color.g - 1.0).abs() < 0.01, "{} at {}", color.g, x);
+        }
+    }
+}
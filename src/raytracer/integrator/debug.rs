@@ -0,0 +1,172 @@
+// Views of the scene's geometry rather than its lighting, for finding out
+// why a render looks wrong. Rays that hit nothing come out black.
+
+use std::sync::Arc;
+
+use crate::image::Color;
+use crate::raytracer::differential::RayDifferential;
+use crate::raytracer::geometry::{Ray, Rayhit};
+use crate::raytracer::light::Lights;
+use crate::raytracer::sampling::Rng;
+use crate::raytracer::scene::Scene;
+
+use super::Integrator;
+
+fn closest_hit(ray: &Ray, scene: &Scene) -> Option<Rayhit> {
+    return scene.intersect(ray, f32::INFINITY, None);
+}
+
+fn black() -> Color {
+    return Color::new(0, 0, 0, 255);
+}
+
+// Surface normals, with each axis mapped from -1..1 to a color channel
+pub struct NormalView;
+
+impl Integrator for NormalView {
+    fn trace(
+        &self,
+        ray: &Ray,
+        _differential: Option<&RayDifferential>,
+        scene: &Scene,
+        _lights: &Lights,
+        _rng: &mut Rng,
+    ) -> (Color, u32) {
+        return match closest_hit(ray, scene) {
+            // As shaded, with any normal or bump map
+            Some(hit) => {
+                let normal = hit.material.shading_normal(&hit);
+                (
+                    Color {
+                        r: normal.x() * 0.5 + 0.5,
+                        g: normal.y() * 0.5 + 0.5,
+                        b: normal.z() * 0.5 + 0.5,
+                        a: 1.0,
+                    },
+                    1,
+                )
+            }
+            None => (black(), 1),
+        };
+    }
+
+    fn measures_light(&self) -> bool {
+        return false;
+    }
+}
+
+// Distance from the camera, fading from white up close to black at far
+pub struct DepthView {
+    pub far: f32,
+}
+
+impl Integrator for DepthView {
+    fn trace(
+        &self,
+        ray: &Ray,
+        _differential: Option<&RayDifferential>,
+        scene: &Scene,
+        _lights: &Lights,
+        _rng: &mut Rng,
+    ) -> (Color, u32) {
+        // Unit length, so the hit distance is in scene units
+        let ray = Ray {
+            direction: ray.direction.normalized(),
+            origin: ray.origin,
+        };
+        return match closest_hit(&ray, scene) {
+            Some(hit) => {
+                let mut color =
+                    Color::new(255, 255, 255, 255) * (1.0 - hit.dist / self.far).max(0.0);
+                color.a = 1.0;
+                (color, 1)
+            }
+            None => (black(), 1),
+        };
+    }
+
+    fn measures_light(&self) -> bool {
+        return false;
+    }
+}
+
+// A made up color for each object, so it's easy to see where one ends and
+// the next begins. Every triangle of a mesh counts as its own object.
+pub struct ObjectView;
+
+impl Integrator for ObjectView {
+    fn trace(
+        &self,
+        ray: &Ray,
+        _differential: Option<&RayDifferential>,
+        scene: &Scene,
+        _lights: &Lights,
+        _rng: &mut Rng,
+    ) -> (Color, u32) {
+        return match closest_hit(ray, scene) {
+            Some(hit) => {
+                // The address is different every run, but stays put for the
+                // whole of one render
+                let address = Arc::as_ptr(&hit.obj) as *const () as usize;
+                let mut rng = Rng::new(address as u64, 0);
+                let color = Color {
+                    r: 0.2 + 0.8 * rng.next_f32(),
+                    g: 0.2 + 0.8 * rng.next_f32(),
+                    b: 0.2 + 0.8 * rng.next_f32(),
+                    a: 1.0,
+                };
+                (color, 1)
+            }
+            None => (black(), 1),
+        };
+    }
+
+    fn measures_light(&self) -> bool {
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matrix::vector::{Point3D, Vector3D};
+    use crate::raytracer::geometry::material::plain_material;
+    use crate::raytracer::geometry::{Geometry, Sphere};
+
+    fn sphere(x: f32) -> Arc<dyn Geometry> {
+        return Arc::new(Sphere {
+            origin: Point3D::new([x, 0.0, 10.0]),
+            radius: 1.0,
+            material: plain_material(),
+        });
+    }
+
+    fn view(integrator: &dyn Integrator, scene: &Scene, x: f32) -> Color {
+        let ray = Ray {
+            origin: Point3D::new([x, 0.0, 0.0]),
+            direction: Vector3D::new([0.0, 0.0, 2.0]),
+        };
+        let mut rng = Rng::new(0, 0);
+        return integrator
+            .trace(&ray, None, scene, &Lights::new(Vec::new()), &mut rng)
+            .0;
+    }
+
+    #[test]
+    fn debug_views() {
+        let scene = Scene::new(vec![sphere(0.0), sphere(5.0)]);
+
+        let normal = view(&NormalView, &scene, 0.0);
+        assert_eq!((normal.r, normal.g, normal.b), (0.5, 0.5, 0.0));
+
+        let depth = view(&DepthView { far: 18.0 }, &scene, 0.0);
+        assert!((depth.r - 0.5).abs() < 1e-5);
+        assert_eq!(view(&DepthView { far: 18.0 }, &scene, 2.5).r, 0.0);
+
+        let first = view(&ObjectView, &scene, 0.0);
+        let again = view(&ObjectView, &scene, 0.5);
+        let second = view(&ObjectView, &scene, 5.0);
+        assert_eq!((first.r, first.g, first.b), (again.r, again.g, again.b));
+        assert_ne!((first.r, first.g, first.b), (second.r, second.g, second.b));
+    }
+}
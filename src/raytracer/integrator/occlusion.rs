@@ -0,0 +1,104 @@
+use crate::image::Color;
+use crate::raytracer::differential::RayDifferential;
+use crate::raytracer::geometry::Ray;
+use crate::raytracer::light::Lights;
+use crate::raytracer::sampling;
+use crate::raytracer::sampling::Rng;
+use crate::raytracer::scene::Scene;
+
+use super::Integrator;
+
+// Shades each point by how much of the sky above it is open within
+// distance, ignoring the lights. Creases and corners come out dark.
+pub struct AmbientOcclusion {
+    pub samples: u32,
+    pub distance: f32,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn trace(
+        &self,
+        ray: &Ray,
+        _differential: Option<&RayDifferential>,
+        scene: &Scene,
+        _lights: &Lights,
+        rng: &mut Rng,
+    ) -> (Color, u32) {
+        let hit = match scene.intersect(ray, f32::INFINITY, None) {
+            Some(hit) => hit,
+            // Nothing in the way of the sky
+            None => return (Color::new(255, 255, 255, 255), 1),
+        };
+
+        let normal = if hit.normal * ray.direction > 0.0 {
+            -hit.normal
+        } else {
+            hit.normal
+        };
+        // Cosine weighted directions make the average visibility the answer
+        let occlusion_ray = Ray {
+            direction: sampling::cosine_hemisphere(&normal, rng.next_f32(), rng.next_f32()),
+            origin: hit.pos,
+        };
+        let open = scene.transmittance(&occlusion_ray, self.distance, Some(&hit.surface()));
+        let mut color = Color::new(255, 255, 255, 255) * open;
+        color.a = 1.0;
+        return (color, 2);
+    }
+
+    fn samples_per_pixel(&self) -> Option<u32> {
+        return Some(self.samples);
+    }
+
+    fn measures_light(&self) -> bool {
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::matrix::vector::{Point3D, Vector3D};
+    use crate::raytracer::geometry::material::plain_material;
+    use crate::raytracer::geometry::{Geometry, Triangle};
+
+    // A triangle big enough to cover everything the tests look at
+    fn plane(y: f32) -> Arc<dyn Geometry> {
+        return Arc::new(Triangle {
+            a: Point3D::new([-100.0, y, -100.0]),
+            b: Point3D::new([0.0, y, 100.0]),
+            c: Point3D::new([100.0, y, -100.0]),
+            material: plain_material(),
+        });
+    }
+
+    fn average(scene: &Scene) -> f32 {
+        let occlusion = AmbientOcclusion {
+            samples: 1,
+            distance: 5.0,
+        };
+        let ray = Ray {
+            origin: Point3D::new([0.0, 0.05, 0.0]),
+            direction: Vector3D::new([0.0, -1.0, 0.0]),
+        };
+        let mut rng = Rng::new(5, 0);
+        let mut total = 0.0;
+        for _ in 0..1000 {
+            total += occlusion
+                .trace(&ray, None, scene, &Lights::new(Vec::new()), &mut rng)
+                .0
+                .r;
+        }
+        return total / 1000.0;
+    }
+
+    #[test]
+    fn open_and_covered_floor() {
+        assert_eq!(average(&Scene::new(vec![plane(0.0)])), 1.0);
+
+        // A low ceiling hides almost all of the sky
+        assert!(average(&Scene::new(vec![plane(0.0), plane(0.1)])) < 0.01);
+    }
+}
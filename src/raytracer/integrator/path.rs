@@ -0,0 +1,502 @@
+use std::f32::consts::PI;
+
+use crate::image::Color;
+use crate::matrix::vector::Vector3D;
+use crate::raytracer::clamp;
+use crate::raytracer::differential::RayDifferential;
+use crate::raytracer::geometry::material::Material;
+use crate::raytracer::geometry::{Ray, Rayhit, Surface};
+use crate::raytracer::light::Lights;
+use crate::raytracer::refraction::{Boundary, Media};
+use crate::raytracer::sampling;
+use crate::raytracer::sampling::Rng;
+use crate::raytracer::scene::Scene;
+use crate::raytracer::spectrum;
+use crate::raytracer::spectrum::{MAX_WAVELENGTH, MIN_WAVELENGTH};
+use crate::raytracer::texture::TexturePoint;
+
+use super::Integrator;
+
+// Path tracing always follows this many bounces before Russian roulette can
+// end a path early
+const MIN_BOUNCES: u32 = 3;
+
+// Unbiased Monte Carlo global illumination, so light bounces off walls onto
+// whatever is next to them. Spectral path tracing gives each path its own
+// wavelength, so dispersive glass splits light into rainbows.
+pub struct PathTracer {
+    pub samples: u32,
+    pub max_bounces: u32,
+    pub spectral: bool,
+}
+
+// How a material splits the light that hits it when path tracing. Each
+// weight is the chance a bounce takes that lobe, scaled down if they add up
+// to more than 1 so surfaces never give out more light than they take in.
+struct Lobes {
+    diffuse: f32,
+    glossy: f32,
+    mirror: f32,
+    transmit: f32,
+    exponent: f32, // Of the glossy lobe
+}
+
+impl Lobes {
+    fn new(material: &Material) -> Lobes {
+        let opacity = material.color.a;
+        let mut lobes = Lobes {
+            diffuse: material.diffuse.max(0.0) * opacity,
+            glossy: material.specular.max(0.0) * opacity,
+            mirror: material.reflectivity.max(0.0) * opacity,
+            transmit: 1.0 - opacity,
+            exponent: material.specular_n.max(0) as f32,
+        };
+        let total = lobes.total();
+        if total > 1.0 {
+            lobes.diffuse /= total;
+            lobes.glossy /= total;
+            lobes.mirror /= total;
+            lobes.transmit /= total;
+        }
+        return lobes;
+    }
+
+    // A transparent surface seen from inside, which only lets light across
+    fn crossing() -> Lobes {
+        return Lobes {
+            diffuse: 0.0,
+            glossy: 0.0,
+            mirror: 0.0,
+            transmit: 1.0,
+            exponent: 0.0,
+        };
+    }
+
+    fn total(&self) -> f32 {
+        return self.diffuse + self.glossy + self.mirror + self.transmit;
+    }
+
+    // The chance of a bounce going in direction, per steradian. Mirror and
+    // transmitted bounces only go one way, so they don't count.
+    fn pdf(&self, normal: &Vector3D, reflect: &Vector3D, direction: &Vector3D) -> f32 {
+        let total = self.total();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let diffuse = clamp(*normal * *direction) / PI;
+        let glossy = f32::powf(clamp(*reflect * *direction), self.exponent) * (self.exponent + 1.0)
+            / (2.0 * PI);
+        return (self.diffuse * diffuse + self.glossy * glossy) / total;
+    }
+}
+
+impl PathTracer {
+    // Follows one random path of light backwards from the camera, bouncing
+    // off whichever lobe of each material it picks. Point lights can't be
+    // hit by chance, so they are sampled directly at every bounce, along
+    // with a point on each area light and a direction from the environment.
+    // A path that misses everything picks up the environment's light,
+    // weighted against sampling it directly so neither way is counted twice.
+    //
+    // A path with a wavelength works with the value of every color's
+    // spectrum at that wavelength, so all three channels of the result are
+    // the same.
+    pub fn trace_path(
+        ray: &Ray,
+        differential: Option<&RayDifferential>,
+        scene: &Scene,
+        lights: &Lights,
+        max_bounces: u32,
+        rng: &mut Rng,
+        wavelength: Option<f32>,
+    ) -> (Color, u32) {
+        let mut color = Color::new(0, 0, 0, 255);
+        let mut throughput = Color::new(255, 255, 255, 255);
+        // Camera rays aren't unit length, but the lobes below need them to be
+        let mut ray = Ray {
+            direction: ray.direction.normalized(),
+            origin: ray.origin,
+        };
+        let mut ignore: Option<Surface> = None;
+        let mut media = Media::new();
+        let mut ray_count = 0;
+        // Area lights hit after a diffuse or glossy bounce were already
+        // counted when they were sampled directly
+        let mut count_emission = true;
+        // The chance of the last diffuse or glossy bounce going the way it
+        // did, per steradian
+        let mut scatter_pdf = None;
+        // Only followed through mirrors and glass. Light scattered every
+        // which way blurs textures far more than a pixel does.
+        let mut differential = differential.copied();
+
+        for bounce in 0..=max_bounces {
+            ray_count += 1;
+            let mut hit = match scene.intersect(&ray, f32::INFINITY, ignore.as_ref()) {
+                Some(hit) => hit,
+                None => {
+                    let weight = match scatter_pdf {
+                        Some(pdf) => {
+                            sampling::power_heuristic(pdf, scene.environment().pdf(&ray.direction))
+                        }
+                        None => 1.0,
+                    };
+                    let background = spectrum::tint(scene.background(&ray.direction), wavelength);
+                    color = color + throughput * background * weight;
+                    break;
+                }
+            };
+            if let Some(differential) = &differential {
+                hit.footprint = differential.footprint(&hit);
+            }
+            // Textures make the material vary over the surface
+            hit.normal = hit.material.shading_normal(&hit);
+            let material = hit.material.at(&TexturePoint::of(&hit));
+            if count_emission {
+                color = color + throughput * spectrum::tint(material.emission, wavelength);
+            }
+
+            // Light both sides of a surface the same way
+            let normal = if hit.normal * ray.direction > 0.0 {
+                -hit.normal
+            } else {
+                hit.normal
+            };
+            let reflect = ray.direction - normal * (ray.direction * normal) * 2.0;
+            let boundary = if material.model.is_some() || material.color.a < 1.0 {
+                Some(Boundary::new(&ray, &hit, &media, wavelength))
+            } else {
+                None
+            };
+            let bsdf = boundary.as_ref().and_then(|boundary| {
+                let material_color = spectrum::tint(material.color, wavelength);
+                material.bsdf(material_color, boundary.eta())
+            });
+            let last_bounce = bounce == max_bounces;
+
+            let (next_ray, next_ignore) = if let (Some(bsdf), Some(boundary)) = (&bsdf, &boundary) {
+                let wo = -ray.direction;
+                let scattering = |to_light: &Vector3D| {
+                    bsdf.evaluate(&wo, to_light, &boundary.normal)
+                        * (*to_light * boundary.normal).abs()
+                };
+                let pdf = |to_light: &Vector3D| bsdf.pdf(&wo, to_light, &boundary.normal);
+                color = color
+                    + throughput
+                        * PathTracer::direct_light(
+                            &hit,
+                            &scattering,
+                            &pdf,
+                            true,
+                            scene,
+                            lights,
+                            wavelength,
+                            last_bounce,
+                            rng,
+                        );
+                if last_bounce {
+                    break;
+                }
+
+                let sample = match bsdf.sample(&wo, &boundary.normal, rng) {
+                    Some(sample) => sample,
+                    None => break,
+                };
+                if sample.direction * boundary.normal < 0.0 {
+                    media = boundary.beyond.clone();
+                }
+                count_emission = sample.specular;
+                scatter_pdf = if sample.specular {
+                    None
+                } else {
+                    Some(sample.pdf)
+                };
+                differential = differential
+                    .filter(|_| sample.specular)
+                    .map(|differential| differential.through(boundary, &hit, &sample.direction));
+                throughput = throughput * sample.weight;
+                // Rays leaving clear surfaces may hit the same object again,
+                // so every ray here starts just off the surface instead
+                (boundary.ray_towards(sample.direction), None)
+            } else {
+                // On the way out of a transparent object only the surface
+                // between it and whatever is beyond matters, not how the
+                // outside is lit
+                let lobes = match &boundary {
+                    Some(boundary) if !boundary.entering => Lobes::crossing(),
+                    _ => {
+                        let lobes = Lobes::new(&material);
+                        let material_color = spectrum::tint(material.color, wavelength);
+                        // The glossy lobe is a normalized Phong lobe around
+                        // the reflection. Dividing by pi makes the lobes
+                        // proper BRDFs.
+                        let scattering = |to_light: &Vector3D| {
+                            let cos_theta = normal * *to_light;
+                            if cos_theta <= 0.0 {
+                                return Color::new(0, 0, 0, 0);
+                            }
+                            let glossy = f32::powf(clamp(reflect * *to_light), lobes.exponent)
+                                * (lobes.exponent + 2.0)
+                                / 2.0;
+                            return (material_color * lobes.diffuse
+                                + Color::new(255, 255, 255, 255) * (lobes.glossy * glossy))
+                                * (cos_theta / PI);
+                        };
+                        let pdf = |to_light: &Vector3D| lobes.pdf(&normal, &reflect, to_light);
+                        color = color
+                            + throughput
+                                * PathTracer::direct_light(
+                                    &hit,
+                                    &scattering,
+                                    &pdf,
+                                    lobes.diffuse + lobes.glossy > 0.0,
+                                    scene,
+                                    lights,
+                                    wavelength,
+                                    last_bounce,
+                                    rng,
+                                );
+                        lobes
+                    }
+                };
+                if last_bounce {
+                    break;
+                }
+
+                let mut next_ignore = Some(hit.surface());
+                let total = lobes.total();
+                let choice = rng.next_f32() * total;
+                count_emission = choice >= lobes.diffuse + lobes.glossy;
+                let previous_differential = differential.take();
+                let next_ray = if choice < lobes.diffuse {
+                    throughput = throughput * spectrum::tint(material.color, wavelength);
+                    Ray {
+                        direction: sampling::cosine_hemisphere(
+                            &normal,
+                            rng.next_f32(),
+                            rng.next_f32(),
+                        ),
+                        origin: hit.pos,
+                    }
+                } else if choice < lobes.diffuse + lobes.glossy {
+                    let exponent = lobes.exponent;
+                    let direction =
+                        sampling::phong_lobe(&reflect, exponent, rng.next_f32(), rng.next_f32());
+                    let cos_theta = direction * normal;
+                    if cos_theta <= 0.0 {
+                        break;
+                    }
+                    throughput = throughput * ((exponent + 2.0) / (exponent + 1.0) * cos_theta);
+                    Ray {
+                        direction,
+                        origin: hit.pos,
+                    }
+                } else if let Some(boundary) = boundary
+                    .as_ref()
+                    .filter(|_| choice >= total - lobes.transmit)
+                {
+                    // Reflect or refract in proportion to the Fresnel
+                    // reflectance. Either way the ray may need to hit this
+                    // object again.
+                    next_ignore = None;
+                    let next_ray = match boundary.refracted_ray() {
+                        Some(refracted_ray) if rng.next_f32() >= boundary.reflectance() => {
+                            media = boundary.beyond.clone();
+                            refracted_ray
+                        }
+                        _ => boundary.reflected_ray(),
+                    };
+                    differential = previous_differential.map(|differential| {
+                        differential.through(boundary, &hit, &next_ray.direction)
+                    });
+                    next_ray
+                } else {
+                    differential = previous_differential
+                        .map(|differential| differential.reflect(&ray.direction, &hit, &normal));
+                    Ray {
+                        direction: reflect,
+                        origin: hit.pos,
+                    }
+                };
+                scatter_pdf = if count_emission {
+                    None
+                } else {
+                    Some(lobes.pdf(&normal, &reflect, &next_ray.direction))
+                };
+                // Picking a lobe by its weight leaves only the total to
+                // account for
+                throughput = throughput * total;
+                (next_ray, next_ignore)
+            };
+
+            // Russian roulette keeps the average right while ending paths
+            // that can't add much
+            if bounce + 1 >= MIN_BOUNCES {
+                let survival = throughput.r.max(throughput.g).max(throughput.b).min(0.95);
+                if rng.next_f32() >= survival {
+                    break;
+                }
+                throughput = throughput * (1.0 / survival);
+            }
+
+            ray = next_ray;
+            ignore = next_ignore;
+        }
+
+        color.a = 1.0;
+        return (color, ray_count);
+    }
+
+    // Light arriving straight from the lights and leaving towards the
+    // camera. scattering gives how much of the light from a direction
+    // leaves towards the camera, including the cosine to the normal, and
+    // pdf the chance of the next bounce going that way. The environment
+    // shares its light with the next bounce, unless this is the last one.
+    fn direct_light(
+        hit: &Rayhit,
+        scattering: &dyn Fn(&Vector3D) -> Color,
+        pdf: &dyn Fn(&Vector3D) -> f32,
+        sample_environment: bool,
+        scene: &Scene,
+        lights: &Lights,
+        wavelength: Option<f32>,
+        last_bounce: bool,
+        rng: &mut Rng,
+    ) -> Color {
+        let mut color = Color::new(0, 0, 0, 0);
+        let is_black = |color: &Color| color.r <= 0.0 && color.g <= 0.0 && color.b <= 0.0;
+        for light_source in &lights.sources {
+            let light = match light_source.illuminate(&hit.pos) {
+                Some(light) => light,
+                None => continue,
+            };
+            let to_light = light.direction;
+            let response = scattering(&to_light);
+            if is_black(&response) {
+                continue;
+            }
+            let ray_to_light = Ray {
+                direction: to_light,
+                origin: hit.pos,
+            };
+            let light_amount = scene.transmittance(&ray_to_light, light.dist, Some(&hit.surface()));
+            if light_amount <= 0.0 {
+                continue;
+            }
+            color = color
+                + response
+                    * spectrum::tint(light.color, wavelength)
+                    * (light_amount * light.irradiance);
+        }
+        for emitter in scene.emitters() {
+            let (to_light, incoming) = match scene.sample_emitter(
+                emitter,
+                &hit.pos,
+                Some(&hit.surface()),
+                rng.next_f32(),
+                rng.next_f32(),
+            ) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            color = color + scattering(&to_light) * spectrum::tint(incoming, wavelength);
+        }
+        if sample_environment {
+            let sample = scene.sample_environment(
+                &hit.pos,
+                Some(&hit.surface()),
+                rng.next_f32(),
+                rng.next_f32(),
+            );
+            if let Some((to_light, incoming, environment_pdf)) = sample {
+                let weight = if last_bounce {
+                    1.0
+                } else {
+                    sampling::power_heuristic(environment_pdf, pdf(&to_light))
+                };
+                color =
+                    color + scattering(&to_light) * spectrum::tint(incoming, wavelength) * weight;
+            }
+        }
+        return color;
+    }
+}
+
+impl Integrator for PathTracer {
+    fn trace(
+        &self,
+        ray: &Ray,
+        differential: Option<&RayDifferential>,
+        scene: &Scene,
+        lights: &Lights,
+        rng: &mut Rng,
+    ) -> (Color, u32) {
+        if !self.spectral {
+            return PathTracer::trace_path(
+                ray,
+                differential,
+                scene,
+                lights,
+                self.max_bounces,
+                rng,
+                None,
+            );
+        }
+        let wavelength = MIN_WAVELENGTH + rng.next_f32() * (MAX_WAVELENGTH - MIN_WAVELENGTH);
+        let (radiance, ray_count) = PathTracer::trace_path(
+            ray,
+            differential,
+            scene,
+            lights,
+            self.max_bounces,
+            rng,
+            Some(wavelength),
+        );
+        return (spectrum::to_color(wavelength, radiance.g), ray_count);
+    }
+
+    fn samples_per_pixel(&self) -> Option<u32> {
+        return Some(self.samples);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matrix::vector::Point3D;
+    use crate::raytracer::environment::UniformEnvironment;
+    use crate::raytracer::geometry::{Geometry, Triangle};
+    use std::sync::Arc;
+
+    fn material(color: Color, diffuse: f32) -> Arc<Material> {
+        return Arc::new(Material::new(color, diffuse, 0.0, 0, 0.0));
+    }
+
+    #[test]
+    fn floor_under_sky() {
+        // A grey floor lit only by an evenly white sky reflects half of it
+        let sky = UniformEnvironment {
+            color: Color::new(255, 255, 255, 255),
+        };
+        let floor: Arc<dyn Geometry> = Arc::new(Triangle {
+            a: Point3D::new([-1000.0, 0.0, -1000.0]),
+            b: Point3D::new([0.0, 0.0, 1000.0]),
+            c: Point3D::new([1000.0, 0.0, -1000.0]),
+            material: material(Color::new(255, 255, 255, 255), 0.5),
+        });
+        let scene = Scene::with_environment(vec![floor], Box::new(sky));
+        let lights = Lights::new(Vec::new());
+        let ray = Ray {
+            origin: Point3D::new([0.0, 1.0, 0.0]),
+            direction: Vector3D::new([0.0, -1.0, 0.5]),
+        };
+
+        let mut rng = Rng::new(3, 0);
+        let mut total = 0.0;
+        for _ in 0..4000 {
+            let (color, _) = PathTracer::trace_path(&ray, None, &scene, &lights, 4, &mut rng, None);
+            total += color.g;
+        }
+        assert!((total / 4000.0 - 0.5).abs() < 0.02);
+    }
+}
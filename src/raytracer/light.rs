@@ -0,0 +1,346 @@
+use std::f32::consts::PI;
+
+use crate::image::Color;
+use crate::matrix::vector::{Point3D, Vector3D};
+use crate::raytracer::sampling;
+
+// Lights are measured in physical units, with distances in metres. Radiant
+// intensity is in watts per steradian and irradiance in watts per square
+// metre. Photometric units turn into these at the peak luminous efficacy
+// of 683 lumens per watt.
+pub const LUMENS_PER_WATT: f32 = 683.0;
+
+// The solid angle of a cone with angle in degrees between its middle and
+// its edge, for spreading a light's power over it
+pub fn cone_solid_angle(angle: f32) -> f32 {
+    return 2.0 * PI * (1.0 - angle.to_radians().cos());
+}
+
+// Every light in a scene
+pub struct Lights {
+    pub sources: Vec<Box<dyn LightSource>>,
+}
+
+impl Lights {
+    pub fn new(sources: Vec<Box<dyn LightSource>>) -> Lights {
+        return Lights { sources };
+    }
+}
+
+// The light from one source arriving at a point
+pub struct Illumination {
+    pub direction: Vector3D, // Unit direction from the point towards the light
+    pub dist: f32,           // How far shadow rays have to go
+    pub color: Color,
+    // On a surface facing the light, in watts per square metre
+    pub irradiance: f32,
+}
+
+// A light that shines from a single point or direction, so shadow rays
+// only need to be traced towards one place. Lights are shared between the
+// render threads.
+pub trait LightSource: Send + Sync {
+    // The light falling on pos, or None if none of it points that way
+    fn illuminate(&self, pos: &Point3D) -> Option<Illumination>;
+}
+
+// Shines evenly in every direction from position, with intensity in watts
+// per steradian. A light giving out P watts in total has an intensity of
+// P / 4pi.
+pub struct PointLight {
+    pub position: Point3D,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+impl LightSource for PointLight {
+    fn illuminate(&self, pos: &Point3D) -> Option<Illumination> {
+        let to_light = self.position - *pos;
+        let dist = to_light.norm();
+        return Some(Illumination {
+            direction: to_light * (1.0 / dist),
+            dist,
+            color: self.color,
+            irradiance: self.intensity / (dist * dist),
+        });
+    }
+}
+
+// Parallel light from infinitely far away, like the sun. direction is the
+// way the light travels. It doesn't fall off with distance, so it is given
+// by its irradiance.
+pub struct DirectionalLight {
+    pub direction: Vector3D,
+    pub color: Color,
+    pub irradiance: f32,
+}
+
+impl LightSource for DirectionalLight {
+    fn illuminate(&self, _pos: &Point3D) -> Option<Illumination> {
+        return Some(Illumination {
+            direction: -self.direction.normalized(),
+            // Not infinite, which would make shadow rays hit the background
+            dist: f32::MAX,
+            color: self.color,
+            irradiance: self.irradiance,
+        });
+    }
+}
+
+// A point light limited to a cone around direction. angle is the angle in
+// degrees between the middle of the cone and its edge, and the light fades
+// out over the last softness degrees before the edge. intensity is in the
+// middle of the cone.
+pub struct SpotLight {
+    pub position: Point3D,
+    pub direction: Vector3D,
+    pub angle: f32,
+    pub softness: f32,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+impl LightSource for SpotLight {
+    fn illuminate(&self, pos: &Point3D) -> Option<Illumination> {
+        let to_light = self.position - *pos;
+        let dist = to_light.norm();
+        let direction = to_light * (1.0 / dist);
+        let cos_angle = -(direction * self.direction.normalized());
+        let outer = self.angle.to_radians().cos();
+        let inner = (self.angle - self.softness).max(0.0).to_radians().cos();
+        let falloff = if cos_angle >= inner {
+            1.0
+        } else if cos_angle <= outer {
+            return None;
+        } else {
+            let t = (cos_angle - outer) / (inner - outer);
+            t * t * (3.0 - 2.0 * t)
+        };
+        return Some(Illumination {
+            direction,
+            dist,
+            color: self.color,
+            irradiance: self.intensity * falloff / (dist * dist),
+        });
+    }
+}
+
+// How bright a real light fixture is in each direction, measured in candela,
+// from an IES photometric file. Vertical angles are in degrees from straight
+// down the fixture's aim, and horizontal angles go around it.
+pub struct IesProfile {
+    pub vertical_angles: Vec<f32>,
+    pub horizontal_angles: Vec<f32>,
+    pub candela: Vec<Vec<f32>>, // One row of vertical angles per horizontal angle
+}
+
+// Where angle falls between two entries of increasing angles, and how far
+// it is from the first to the second
+fn bracket(angles: &[f32], angle: f32) -> Option<(usize, usize, f32)> {
+    let last = angles.len() - 1;
+    if angle < angles[0] || angle > angles[last] {
+        return None;
+    }
+    if last == 0 {
+        return Some((0, 0, 0.0));
+    }
+    let upper = angles
+        .partition_point(|&entry| entry < angle)
+        .clamp(1, last);
+    let lower = upper - 1;
+    let t = (angle - angles[lower]) / (angles[upper] - angles[lower]);
+    return Some((lower, upper, t.clamp(0.0, 1.0)));
+}
+
+impl IesProfile {
+    pub fn max_candela(&self) -> f32 {
+        return self
+            .candela
+            .iter()
+            .flatten()
+            .fold(0.0, |max, &value| f32::max(max, value));
+    }
+
+    // Profiles only store as much of the circle as their symmetry needs, so
+    // horizontal angles are folded back into the part that's there
+    fn fold_horizontal(&self, horizontal: f32) -> f32 {
+        let horizontal = horizontal.rem_euclid(360.0);
+        let last = self.horizontal_angles[self.horizontal_angles.len() - 1];
+        return if last <= 0.0 {
+            0.0
+        } else if last <= 90.0 {
+            let half = if horizontal > 180.0 {
+                360.0 - horizontal
+            } else {
+                horizontal
+            };
+            if half > 90.0 {
+                180.0 - half
+            } else {
+                half
+            }
+        } else if last <= 180.0 && horizontal > 180.0 {
+            360.0 - horizontal
+        } else {
+            horizontal
+        };
+    }
+
+    // The total light given out, found by adding up the candela over the
+    // whole sphere a degree at a time
+    pub fn lumens(&self) -> f32 {
+        let step = 1f32.to_radians();
+        let mut lumens = 0.0;
+        for vertical in 0..180 {
+            let vertical = vertical as f32 + 0.5;
+            let ring = vertical.to_radians().sin() * step * step;
+            for horizontal in 0..360 {
+                lumens += self.candela(vertical, horizontal as f32 + 0.5) * ring;
+            }
+        }
+        return lumens;
+    }
+
+    // Candela in a direction, interpolated between the measured angles
+    pub fn candela(&self, vertical: f32, horizontal: f32) -> f32 {
+        let horizontal = self.fold_horizontal(horizontal);
+        let (v0, v1, tv) = match bracket(&self.vertical_angles, vertical) {
+            Some(bracket) => bracket,
+            None => return 0.0,
+        };
+        let (h0, h1, th) = bracket(&self.horizontal_angles, horizontal).unwrap_or((0, 0, 0.0));
+        let row = |h: usize| self.candela[h][v0] * (1.0 - tv) + self.candela[h][v1] * tv;
+        return row(h0) * (1.0 - th) + row(h1) * th;
+    }
+}
+
+// A point light shaped by an IES profile, aimed along direction. scale is
+// the intensity in watts per steradian for each candela in the profile,
+// which is 1 / 683 to use the profile as it was measured.
+pub struct IesLight {
+    pub position: Point3D,
+    pub direction: Vector3D,
+    pub profile: IesProfile,
+    pub color: Color,
+    pub scale: f32,
+}
+
+impl LightSource for IesLight {
+    fn illuminate(&self, pos: &Point3D) -> Option<Illumination> {
+        let to_light = self.position - *pos;
+        let dist = to_light.norm();
+        let direction = to_light * (1.0 / dist);
+
+        let aim = self.direction.normalized();
+        let (tangent, bitangent) = sampling::basis(&aim);
+        let outwards = -direction;
+        let vertical = (outwards * aim).clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = f32::atan2(outwards * bitangent, outwards * tangent).to_degrees();
+        let candela = self.profile.candela(vertical, horizontal);
+        if candela <= 0.0 {
+            return None;
+        }
+        return Some(Illumination {
+            direction,
+            dist,
+            color: self.color,
+            irradiance: candela * self.scale / (dist * dist),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WHITE: Color = Color {
+        r: 1.0,
+        g: 1.0,
+        b: 1.0,
+        a: 1.0,
+    };
+
+    #[test]
+    fn point_and_directional() {
+        let point = PointLight {
+            position: Point3D::new([0.0, 4.0, 0.0]),
+            color: WHITE,
+            intensity: 2.0,
+        };
+        let light = point.illuminate(&Point3D::zero()).unwrap();
+        assert_eq!(light.direction, Vector3D::new([0.0, 1.0, 0.0]));
+        assert_eq!(light.dist, 4.0);
+        // Twice as far away gets a quarter of the light
+        assert_eq!(light.irradiance, 2.0 / 16.0);
+        let light = point.illuminate(&Point3D::new([0.0, -4.0, 0.0])).unwrap();
+        assert_eq!(light.irradiance, 2.0 / 64.0);
+
+        let sun = DirectionalLight {
+            direction: Vector3D::new([0.0, -3.0, 0.0]),
+            color: WHITE,
+            irradiance: 1000.0,
+        };
+        let light = sun.illuminate(&Point3D::new([5.0, 0.0, 5.0])).unwrap();
+        assert_eq!(light.direction, Vector3D::new([0.0, 1.0, 0.0]));
+        assert!(light.dist.is_finite());
+        assert_eq!(light.irradiance, 1000.0);
+    }
+
+    #[test]
+    fn spot_cone() {
+        let spot = SpotLight {
+            position: Point3D::new([0.0, 1.0, 0.0]),
+            direction: Vector3D::new([0.0, -1.0, 0.0]),
+            angle: 45.0,
+            softness: 10.0,
+            color: WHITE,
+            intensity: 1.0,
+        };
+        let at = |x: f32| spot.illuminate(&Point3D::new([x, 0.0, 0.0]));
+        assert_eq!(at(0.0).unwrap().irradiance, 1.0);
+        // 40 degrees off is in the soft edge, and 50 is outside
+        let x = f32::tan(40f32.to_radians());
+        let edge = at(x).unwrap().irradiance * (1.0 + x * x);
+        assert!(edge > 0.0 && edge < 1.0);
+        assert!(at(f32::tan(50f32.to_radians())).is_none());
+    }
+
+    #[test]
+    fn ies_profile() {
+        // Brighter straight down than to the side, and only on one side
+        let profile = IesProfile {
+            vertical_angles: vec![0.0, 90.0],
+            horizontal_angles: vec![0.0, 90.0, 180.0],
+            candela: vec![vec![100.0, 50.0], vec![100.0, 50.0], vec![100.0, 0.0]],
+        };
+        assert_eq!(profile.max_candela(), 100.0);
+        assert_eq!(profile.candela(45.0, 0.0), 75.0);
+        assert_eq!(profile.candela(90.0, 135.0), 25.0);
+        // Bilateral symmetry mirrors 270 degrees onto 90
+        assert_eq!(profile.candela(90.0, 270.0), 50.0);
+        assert_eq!(profile.candela(120.0, 0.0), 0.0);
+
+        let light = IesLight {
+            position: Point3D::new([0.0, 1.0, 0.0]),
+            direction: Vector3D::new([0.0, -1.0, 0.0]),
+            profile,
+            color: WHITE,
+            scale: 1.0 / LUMENS_PER_WATT,
+        };
+        let below = light.illuminate(&Point3D::zero()).unwrap();
+        assert_eq!(below.irradiance, 100.0 / LUMENS_PER_WATT);
+        assert!(light.illuminate(&Point3D::new([0.0, 2.0, 0.0])).is_none());
+    }
+
+    #[test]
+    fn ies_lumens() {
+        // An even 10 candela in every direction gives out 40pi lumens
+        let profile = IesProfile {
+            vertical_angles: vec![0.0, 180.0],
+            horizontal_angles: vec![0.0],
+            candela: vec![vec![10.0, 10.0]],
+        };
+        assert!((profile.lumens() - 40.0 * PI).abs() < 0.01 * 40.0 * PI);
+        assert!((cone_solid_angle(180.0) - 4.0 * PI).abs() < 1e-5);
+    }
+}
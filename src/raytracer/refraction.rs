@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use crate::matrix::vector::{Point3D, Vector3D};
+
+use super::geometry::material::Material;
+use super::geometry::{Ray, Rayhit};
+
+// How far rays that cross or bounce off a transparent surface start from
+// it. They have to be able to hit the same object again on its far side, so
+// they can't just ignore the object like other rays do.
+pub const SURFACE_OFFSET: f32 = 1e-4;
+
+// Bends a unit direction through a surface by Snell's law. normal faces
+// back against direction and eta is n1 / n2. Returns None for total
+// internal reflection.
+pub fn refract(direction: &Vector3D, normal: &Vector3D, eta: f32) -> Option<Vector3D> {
+    let cos_i = -(*direction * *normal);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t > 1.0 {
+        return None;
+    }
+    let cos_t = f32::sqrt(1.0 - sin2_t);
+    return Some(*direction * eta + *normal * (eta * cos_i - cos_t));
+}
+
+// The fraction of unpolarized light reflected going from a medium with
+// index n1 into one with index n2, given the cosine of the angle of
+// incidence. The rest is transmitted.
+pub fn fresnel(cos_i: f32, n1: f32, n2: f32) -> f32 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin_t = n1 / n2 * f32::sqrt(1.0 - cos_i * cos_i);
+    if sin_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = f32::sqrt(1.0 - sin_t * sin_t);
+    let perpendicular = (n1 * cos_i - n2 * cos_t) / (n1 * cos_i + n2 * cos_t);
+    let parallel = (n1 * cos_t - n2 * cos_i) / (n1 * cos_t + n2 * cos_i);
+    return (perpendicular * perpendicular + parallel * parallel) / 2.0;
+}
+
+// The transparent materials a ray is inside, innermost last. Rays start
+// out in air. Indices of refraction are for the wavelength the ray
+// carries, or white light if it doesn't carry one.
+#[derive(Clone, Default)]
+pub struct Media {
+    inside: Vec<Arc<Material>>,
+}
+
+impl Media {
+    pub fn new() -> Media {
+        return Media { inside: Vec::new() };
+    }
+
+    pub fn ior(&self, wavelength: Option<f32>) -> f32 {
+        return self
+            .inside
+            .last()
+            .map_or(1.0, |material| material.ior_at(wavelength));
+    }
+
+    fn entered(&self, material: &Arc<Material>) -> Media {
+        let mut media = self.clone();
+        media.inside.push(Arc::clone(material));
+        return media;
+    }
+
+    // Leaving something the ray was never inside, like the back of a lone
+    // transparent triangle, leaves it where it was
+    fn exited(&self, material: &Arc<Material>) -> Media {
+        let mut media = self.clone();
+        if let Some(index) = media
+            .inside
+            .iter()
+            .rposition(|inside| Arc::ptr_eq(inside, material))
+        {
+            media.inside.remove(index);
+        }
+        return media;
+    }
+}
+
+// A transparent surface as a ray arriving at it sees it
+pub struct Boundary {
+    pub direction: Vector3D, // The unit direction the ray arrives from
+    pub normal: Vector3D,    // Facing back towards the ray
+    pub position: Point3D,
+    pub entering: bool, // Whether the ray is going into the object's material
+    pub n1: f32,
+    pub n2: f32,
+    pub beyond: Media, // What the ray is inside once it's through
+}
+
+impl Boundary {
+    pub fn new(ray: &Ray, hit: &Rayhit, media: &Media, wavelength: Option<f32>) -> Boundary {
+        let direction = ray.direction.normalized();
+        // Normals face out of objects, so a ray going against one is
+        // going in
+        let entering = direction * hit.normal <= 0.0;
+        let (normal, beyond) = if entering {
+            (hit.normal, media.entered(&hit.material))
+        } else {
+            (-hit.normal, media.exited(&hit.material))
+        };
+        let n1 = if entering {
+            media.ior(wavelength)
+        } else {
+            hit.material.ior_at(wavelength)
+        };
+        return Boundary {
+            direction,
+            normal,
+            position: hit.pos,
+            entering,
+            n1,
+            n2: beyond.ior(wavelength),
+            beyond,
+        };
+    }
+
+    // How much of the light is reflected rather than transmitted
+    pub fn reflectance(&self) -> f32 {
+        return fresnel(-(self.direction * self.normal), self.n1, self.n2);
+    }
+
+    // The index of refraction beyond the surface over the one before it
+    pub fn eta(&self) -> f32 {
+        return self.n2 / self.n1;
+    }
+
+    // A ray leaving the surface in direction, which starts on whichever
+    // side direction goes
+    pub fn ray_towards(&self, direction: Vector3D) -> Ray {
+        let side = if direction * self.normal < 0.0 {
+            -SURFACE_OFFSET
+        } else {
+            SURFACE_OFFSET
+        };
+        return Ray {
+            direction,
+            origin: self.position + self.normal * side,
+        };
+    }
+
+    // Stays on the side the ray came from
+    pub fn reflected_ray(&self) -> Ray {
+        let direction = self.direction - self.normal * (self.direction * self.normal) * 2.0;
+        return Ray {
+            direction,
+            origin: self.position + self.normal * SURFACE_OFFSET,
+        };
+    }
+
+    // Continues on the far side, or None for total internal reflection
+    pub fn refracted_ray(&self) -> Option<Ray> {
+        let direction = refract(&self.direction, &self.normal, self.n1 / self.n2)?;
+        return Some(Ray {
+            direction,
+            origin: self.position - self.normal * SURFACE_OFFSET,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::Color;
+    use crate::raytracer::geometry::{Geometry, Sphere};
+
+    fn glass(ior: f32) -> Arc<Material> {
+        let mut material = Material::new(Color::new(255, 255, 255, 0), 0.0, 0.0, 0, 0.0);
+        material.ior = ior;
+        return Arc::new(material);
+    }
+
+    fn ball(material: &Arc<Material>, radius: f32) -> Arc<dyn Geometry> {
+        return Arc::new(Sphere {
+            origin: Point3D::zero(),
+            radius,
+            material: Arc::clone(material),
+        });
+    }
+
+    #[test]
+    fn snells_law() {
+        let normal = Vector3D::new([0.0, 1.0, 0.0]);
+        let direction = Vector3D::new([1.0, -1.0, 0.0]).normalized();
+        let bent = refract(&direction, &normal, 1.0 / 1.5).unwrap();
+        let sin_i = direction.x();
+        let sin_t = bent.x();
+        assert!((sin_i - 1.5 * sin_t).abs() < 1e-5);
+        assert!((bent.norm() - 1.0).abs() < 1e-5);
+
+        // Going the other way at the same angle is past the critical angle
+        assert!(refract(&direction, &normal, 1.5).is_none());
+        assert_eq!(fresnel(direction * -normal, 1.5, 1.0), 1.0);
+    }
+
+    #[test]
+    fn fresnel_reflectance() {
+        // Head on, glass reflects ((n1 - n2) / (n1 + n2))^2 of the light
+        assert!((fresnel(1.0, 1.0, 1.5) - 0.04).abs() < 1e-5);
+        assert!((fresnel(1.0, 1.5, 1.0) - 0.04).abs() < 1e-5);
+        // and everything at grazing angles
+        assert!(fresnel(0.0, 1.0, 1.5) > 0.999);
+        // Nothing reflects between matching media
+        assert!(fresnel(0.3, 1.33, 1.33) < 1e-6);
+    }
+
+    #[test]
+    fn nested_media() {
+        // A ball of water inside a ball of glass
+        let glass_ball = ball(&glass(1.5), 2.0);
+        let water_ball = ball(&glass(1.33), 1.0);
+        let ray = Ray {
+            origin: Point3D::new([0.0, 0.0, -5.0]),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+
+        let air = Media::new();
+        let hit = Arc::clone(&glass_ball)
+            .intersect(&ray, f32::INFINITY)
+            .unwrap();
+        let into_glass = Boundary::new(&ray, &hit, &air, None);
+        assert!(into_glass.entering);
+        assert_eq!((into_glass.n1, into_glass.n2), (1.0, 1.5));
+
+        let hit = Arc::clone(&water_ball)
+            .intersect(&ray, f32::INFINITY)
+            .unwrap();
+        let into_water = Boundary::new(&ray, &hit, &into_glass.beyond, None);
+        assert!(into_water.entering);
+        assert_eq!((into_water.n1, into_water.n2), (1.5, 1.33));
+
+        // Out the far side of the water, back into glass, then air
+        let inside = Ray {
+            origin: Point3D::zero(),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let hit = Arc::clone(&water_ball)
+            .intersect(&inside, f32::INFINITY)
+            .unwrap();
+        let out_of_water = Boundary::new(&inside, &hit, &into_water.beyond, None);
+        assert!(!out_of_water.entering);
+        assert_eq!((out_of_water.n1, out_of_water.n2), (1.33, 1.5));
+        assert!((out_of_water.normal - Vector3D::new([0.0, 0.0, -1.0])).norm() < 1e-6);
+
+        let hit = Arc::clone(&glass_ball)
+            .intersect(&inside, f32::INFINITY)
+            .unwrap();
+        let out_of_glass = Boundary::new(&inside, &hit, &out_of_water.beyond, None);
+        assert_eq!((out_of_glass.n1, out_of_glass.n2), (1.5, 1.0));
+        assert!(out_of_glass.beyond.inside.is_empty());
+
+        // The refracted ray starts just past the surface
+        let through = out_of_glass.refracted_ray().unwrap();
+        assert!(through.origin.z() > 2.0);
+        assert!((through.direction - inside.direction).norm() < 1e-6);
+    }
+}
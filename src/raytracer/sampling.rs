@@ -0,0 +1,148 @@
+use std::f32::consts::PI;
+
+use crate::matrix::vector::Vector3D;
+
+// A small PCG32 random number generator. Each pixel seeds its own, so a
+// render comes out the same no matter which thread gets which tile.
+pub struct Rng {
+    state: u64,
+    increment: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64, stream: u64) -> Rng {
+        let mut rng = Rng {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        return rng;
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(self.increment);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rotation = (old >> 59) as u32;
+        return xorshifted.rotate_right(rotation);
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        return (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32);
+    }
+}
+
+// Two unit vectors that make a right handed frame with normal
+pub fn basis(normal: &Vector3D) -> (Vector3D, Vector3D) {
+    let helper = if normal.x().abs() > 0.9 {
+        Vector3D::new([0.0, 1.0, 0.0])
+    } else {
+        Vector3D::new([1.0, 0.0, 0.0])
+    };
+    let tangent = helper.cross(normal).normalized();
+    let bitangent = normal.cross(&tangent);
+    return (tangent, bitangent);
+}
+
+// A direction around axis where cos(theta) = cos_theta and the angle around
+// the axis is phi
+fn around(axis: &Vector3D, cos_theta: f32, phi: f32) -> Vector3D {
+    let sin_theta = f32::sqrt((1.0 - cos_theta * cos_theta).max(0.0));
+    let (tangent, bitangent) = basis(axis);
+    return tangent * (sin_theta * phi.cos())
+        + bitangent * (sin_theta * phi.sin())
+        + *axis * cos_theta;
+}
+
+// Directions over the hemisphere around normal with a pdf of cos(theta) / pi
+pub fn cosine_hemisphere(normal: &Vector3D, u1: f32, u2: f32) -> Vector3D {
+    return around(normal, f32::sqrt(1.0 - u1), 2.0 * PI * u2);
+}
+
+// Directions around axis with a pdf of (n + 1) / 2pi * cos(alpha)^n, the
+// shape of a Phong highlight
+pub fn phong_lobe(axis: &Vector3D, exponent: f32, u1: f32, u2: f32) -> Vector3D {
+    return around(axis, u1.powf(1.0 / (exponent + 1.0)), 2.0 * PI * u2);
+}
+
+// Microfacet normals around normal with a pdf of D(h) * cos(theta), where
+// D is the GGX distribution with roughness alpha
+pub fn ggx_normal(normal: &Vector3D, alpha: f32, u1: f32, u2: f32) -> Vector3D {
+    let tan2_theta = alpha * alpha * u1 / (1.0 - u1);
+    return around(normal, 1.0 / f32::sqrt(1.0 + tan2_theta), 2.0 * PI * u2);
+}
+
+// How much to trust a sample taken with a pdf of pdf when another way of
+// sampling could have picked the same direction with a pdf of other. Each
+// way's weights add up to 1, and the way that was more likely to find the
+// direction gets most of the weight.
+pub fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let (pdf, other) = (pdf * pdf, other * other);
+    if pdf + other <= 0.0 {
+        return 0.0;
+    }
+    return pdf / (pdf + other);
+}
+
+// Points spread evenly over the unit sphere
+pub fn uniform_sphere(u1: f32, u2: f32) -> Vector3D {
+    return around(
+        &Vector3D::new([0.0, 0.0, 1.0]),
+        1.0 - 2.0 * u1,
+        2.0 * PI * u2,
+    );
+}
+
+// Barycentric coordinates beta and gamma spread evenly over a triangle
+pub fn uniform_triangle(u1: f32, u2: f32) -> (f32, f32) {
+    let root = u1.sqrt();
+    return (root * (1.0 - u2), root * u2);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rng_is_uniform_and_repeatable() {
+        let mut a = Rng::new(42, 7);
+        let mut b = Rng::new(42, 7);
+        let mut c = Rng::new(43, 7);
+        let mut sum = 0.0;
+        let mut differs = false;
+        for _ in 0..10000 {
+            let value = a.next_f32();
+            assert!((0.0..1.0).contains(&value));
+            assert_eq!(value, b.next_f32());
+            differs |= value != c.next_f32();
+            sum += value;
+        }
+        assert!(differs);
+        assert!((sum / 10000.0 - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn hemisphere_samples() {
+        let normal = Vector3D::new([0.0, 0.6, 0.8]);
+        let mut rng = Rng::new(1, 1);
+        let mut mean_cos = 0.0;
+        for _ in 0..10000 {
+            let direction = cosine_hemisphere(&normal, rng.next_f32(), rng.next_f32());
+            assert!((direction.norm() - 1.0).abs() < 1e-4);
+            assert!(direction * normal >= 0.0);
+            mean_cos += direction * normal;
+        }
+        // The mean of cos(theta) under a cosine distribution is 2/3
+        assert!((mean_cos / 10000.0 - 2.0 / 3.0).abs() < 0.01);
+
+        let (tangent, bitangent) = basis(&normal);
+        assert!((tangent * normal).abs() < 1e-6);
+        assert!((bitangent * normal).abs() < 1e-6);
+        assert!((tangent * bitangent).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,239 @@
+use std::sync::Arc;
+
+use crate::image::Color;
+use crate::matrix::vector::{Point3D, Vector3D};
+use crate::raytracer::environment::Environment;
+use crate::raytracer::geometry::bvh::Bvh;
+use crate::raytracer::geometry::Geometry;
+use crate::raytracer::geometry::Ray;
+use crate::raytracer::geometry::Rayhit;
+use crate::raytracer::geometry::Surface;
+
+// Shadow rays towards a point on an area light stop just short of it, so
+// the light doesn't shadow itself
+const SHADOW_REACH: f32 = 1.0 - 1e-4;
+
+// A collection of geometry that can be shared between render threads.
+// Objects are kept in a BVH so each ray only tests the objects near it.
+// Emissive objects are also kept aside to be sampled as area lights. Rays
+// that miss everything see the environment.
+pub struct Scene {
+    bvh: Arc<Bvh>,
+    emitters: Vec<Arc<dyn Geometry>>,
+    environment: Box<dyn Environment>,
+}
+
+impl Scene {
+    // A scene in a black void
+    #[cfg(test)]
+    pub fn new(objects: Vec<Arc<dyn Geometry>>) -> Scene {
+        let black = crate::raytracer::environment::UniformEnvironment {
+            color: Color::new(0, 0, 0, 255),
+        };
+        return Scene::with_environment(objects, Box::new(black));
+    }
+
+    pub fn with_environment(
+        objects: Vec<Arc<dyn Geometry>>,
+        environment: Box<dyn Environment>,
+    ) -> Scene {
+        let emitters = objects
+            .iter()
+            .filter(|object| object.is_emissive())
+            .cloned()
+            .collect();
+        return Scene {
+            bvh: Arc::new(Bvh::new(objects)),
+            emitters,
+            environment,
+        };
+    }
+
+    pub fn environment(&self) -> &dyn Environment {
+        return &*self.environment;
+    }
+
+    // What a ray going in direction sees if it misses everything
+    pub fn background(&self, direction: &Vector3D) -> Color {
+        return self.environment.radiance(direction);
+    }
+
+    pub fn emitters(&self) -> &[Arc<dyn Geometry>] {
+        return &self.emitters;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.bvh.len();
+    }
+
+    // Finds the closest object along the ray, skipping `ignore` if given
+    pub fn intersect(
+        &self,
+        ray: &Ray,
+        closest_dist: f32,
+        ignore: Option<&Surface>,
+    ) -> Option<Rayhit> {
+        return Arc::clone(&self.bvh).intersect_ignoring(ray, closest_dist, ignore);
+    }
+
+    // How much light makes it dist along the ray without being blocked.
+    // Transparent objects let some of it through.
+    pub fn transmittance(&self, ray: &Ray, dist: f32, ignore: Option<&Surface>) -> f32 {
+        return self.bvh.transmittance(ray, dist, ignore);
+    }
+
+    // Picks a point on emitter with u1 and u2 and returns the unit direction
+    // to it from pos, with the light arriving from it divided by the chance
+    // of picking that direction. Averaging these and weighting them by the
+    // surface's response gives its light from the emitter. Emissive surfaces
+    // light both sides. Returns None when the point is blocked.
+    pub fn sample_emitter(
+        &self,
+        emitter: &Arc<dyn Geometry>,
+        pos: &Point3D,
+        ignore: Option<&Surface>,
+        u1: f32,
+        u2: f32,
+    ) -> Option<(Vector3D, Color)> {
+        let sample = emitter.sample_surface(u1, u2)?;
+        let to_light = sample.pos - *pos;
+        let dist_squared = to_light.norm_squared();
+        if dist_squared <= 0.0 {
+            return None;
+        }
+        let dist = dist_squared.sqrt();
+        let to_light = to_light * (1.0 / dist);
+        let cos_light = (sample.normal * to_light).abs();
+        let ray_to_light = Ray {
+            direction: to_light,
+            origin: *pos,
+        };
+        let light_amount = self.transmittance(&ray_to_light, dist * SHADOW_REACH, ignore);
+        if light_amount <= 0.0 || cos_light <= 0.0 {
+            return None;
+        }
+        let weight = light_amount * cos_light / (dist_squared * sample.pdf);
+        return Some((to_light, sample.material.emission * weight));
+    }
+
+    // Like sample_emitter, but for a direction picked from the environment.
+    // The chance of picking the direction, per steradian, is returned too.
+    pub fn sample_environment(
+        &self,
+        pos: &Point3D,
+        ignore: Option<&Surface>,
+        u1: f32,
+        u2: f32,
+    ) -> Option<(Vector3D, Color, f32)> {
+        let sample = self.environment.sample(u1, u2)?;
+        let ray_to_light = Ray {
+            direction: sample.direction,
+            origin: *pos,
+        };
+        let light_amount = self.transmittance(&ray_to_light, f32::INFINITY, ignore);
+        if light_amount <= 0.0 {
+            return None;
+        }
+        let weight = light_amount / sample.pdf;
+        return Some((sample.direction, sample.radiance * weight, sample.pdf));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::Color;
+    use crate::matrix::vector::{Point3D, Vector3D};
+    use crate::raytracer::geometry::material::{plain_material, Material};
+    use crate::raytracer::geometry::{same_object, Sphere};
+    use crate::raytracer::sampling::Rng;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn sphere(z: f32, alpha: u8) -> Arc<dyn Geometry> {
+        return Arc::new(Sphere {
+            origin: Point3D::new([0.0, 0.0, z]),
+            radius: 1.0,
+            material: Arc::new(Material::new(
+                Color::new(255, 255, 255, alpha),
+                1.0,
+                0.0,
+                0,
+                0.0,
+            )),
+        });
+    }
+
+    #[test]
+    fn scene_is_thread_safe() {
+        assert_send_sync::<Scene>();
+        assert_send_sync::<Rayhit>();
+    }
+
+    #[test]
+    fn closest_hit() {
+        let near = sphere(5.0, 255);
+        let far = sphere(10.0, 255);
+        let scene = Scene::new(vec![Arc::clone(&far), Arc::clone(&near)]);
+        let ray = Ray {
+            origin: Point3D::zero(),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+
+        let hit = scene.intersect(&ray, f32::INFINITY, None).unwrap();
+        assert_eq!(hit.dist, 4.0);
+        assert!(same_object(&hit.obj, &near));
+
+        let hit = scene
+            .intersect(&ray, f32::INFINITY, Some(&hit.surface()))
+            .unwrap();
+        assert_eq!(hit.dist, 9.0);
+        assert!(same_object(&hit.obj, &far));
+    }
+
+    #[test]
+    fn transmittance() {
+        let scene = Scene::new(vec![sphere(5.0, 128), sphere(10.0, 128)]);
+        let ray = Ray {
+            origin: Point3D::zero(),
+            direction: Vector3D::new([0.0, 0.0, 1.0]),
+        };
+        let half = 1.0 - 128.0 / 255.0;
+        assert_eq!(scene.transmittance(&ray, 2.0, None), 1.0);
+        assert_eq!(scene.transmittance(&ray, 7.0, None), half);
+        assert_eq!(scene.transmittance(&ray, 20.0, None), half * half);
+    }
+
+    #[test]
+    fn sphere_light() {
+        // A glowing ball of radius 1 five units straight up gives a surface
+        // facing it pi * (1 / 5)^2 times its brightness
+        let mut material = Material::clone(&plain_material());
+        material.emission = Color::new(255, 255, 255, 255);
+        let light: Arc<dyn Geometry> = Arc::new(Sphere {
+            origin: Point3D::new([0.0, 5.0, 0.0]),
+            radius: 1.0,
+            material: Arc::new(material),
+        });
+        let scene = Scene::new(vec![sphere(-5.0, 255), Arc::clone(&light)]);
+        assert_eq!(scene.emitters().len(), 1);
+
+        let normal = Vector3D::new([0.0, 1.0, 0.0]);
+        let mut rng = Rng::new(0, 0);
+        let mut total = 0.0;
+        for _ in 0..20000 {
+            let sample = scene.sample_emitter(
+                &light,
+                &Point3D::zero(),
+                None,
+                rng.next_f32(),
+                rng.next_f32(),
+            );
+            if let Some((to_light, incoming)) = sample {
+                total += incoming.g * (to_light * normal);
+            }
+        }
+        let expected = std::f32::consts::PI / 25.0;
+        assert!((total / 20000.0 - expected).abs() < 0.02 * expected);
+    }
+}
@@ -0,0 +1,335 @@
+use std::f32::consts::PI;
+
+use crate::image::{Color, HdrImage};
+use crate::matrix::vector::Vector3D;
+use crate::raytracer::environment::{self, Environment, EnvironmentMap, EnvironmentSample};
+use crate::raytracer::light::{DirectionalLight, LUMENS_PER_WATT};
+use crate::raytracer::spectrum;
+
+// Sunlight before it enters the atmosphere, in lux
+const SUN_ILLUMINANCE: f32 = 128_000.0;
+// The sun's surface temperature, which sets the color of its light
+const SUN_TEMPERATURE: f32 = 5778.0;
+// How finely the sky is tabulated for picking bright directions
+const TABLE_WIDTH: usize = 256;
+const TABLE_HEIGHT: usize = 128;
+
+// Compass directions in degrees clockwise from north turn into scene
+// directions with north along +z and east along +x. Elevation is in
+// degrees above the horizon.
+pub fn sun_direction(elevation: f32, azimuth: f32) -> Vector3D {
+    let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+    return Vector3D::new([
+        azimuth.sin() * elevation.cos(),
+        elevation.sin(),
+        azimuth.cos() * elevation.cos(),
+    ]);
+}
+
+// The day of the year, counting 1 January as 1, or None if there's no such
+// date
+pub fn day_of_year(year: i32, month: u32, day: u32) -> Option<u32> {
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let mut lengths = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if leap {
+        lengths[1] = 29;
+    }
+    if !(1..=12).contains(&month) || day < 1 || day > lengths[month as usize - 1] {
+        return None;
+    }
+    return Some(lengths[..month as usize - 1].iter().sum::<u32>() + day);
+}
+
+// Where the sun is in the sky as an elevation and azimuth in degrees, from
+// NOAA's low accuracy solar position equations, which are good to a few
+// tenths of a degree. hours is the local clock time, timezone is the
+// clock's offset from UTC in hours, and latitude and longitude are in
+// degrees north and east.
+pub fn sun_position(
+    day_of_year: u32,
+    hours: f32,
+    latitude: f32,
+    longitude: f32,
+    timezone: f32,
+) -> (f32, f32) {
+    let utc = hours - timezone;
+    // How far through the year it is, in radians
+    let year = 2.0 * PI / 365.0 * (day_of_year as f32 - 1.0 + (utc - 12.0) / 24.0);
+    let harmonics = |coefficients: &[f32]| {
+        let mut total = coefficients[0];
+        for (n, pair) in coefficients[1..].chunks(2).enumerate() {
+            let angle = year * (n + 1) as f32;
+            total += pair[0] * angle.cos() + pair[1] * angle.sin();
+        }
+        return total;
+    };
+    // How far the sun runs ahead of the clock, in minutes
+    let equation_of_time =
+        229.18 * harmonics(&[0.000075, 0.001868, -0.032077, -0.014615, -0.040849]);
+    let declination = harmonics(&[
+        0.006918, -0.399912, 0.070257, -0.006758, 0.000907, -0.002697, 0.00148,
+    ]);
+    let solar_minutes = utc * 60.0 + equation_of_time + 4.0 * longitude;
+    let hour_angle = (solar_minutes / 4.0 - 180.0).to_radians();
+
+    let latitude = latitude.to_radians();
+    let sin_elevation =
+        latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+    let elevation = sin_elevation.clamp(-1.0, 1.0).asin();
+    // Measured from south, then turned to measure from north
+    let from_south = f32::atan2(
+        hour_angle.sin(),
+        hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos(),
+    );
+    let azimuth = (from_south.to_degrees() + 180.0).rem_euclid(360.0);
+    return (elevation.to_degrees(), azimuth);
+}
+
+// The Perez sky model as fitted by Preetham, Shirley and Smits in "A
+// Practical Analytic Model for Daylight". Each of luminance and the two
+// chromaticity coordinates has five coefficients and a value at the zenith.
+struct Preetham {
+    sun: Vector3D,
+    perez: [[f32; 5]; 3],
+    zenith: [f32; 3],
+    // The Perez function in the zenith's direction, which values are
+    // relative to
+    zenith_perez: [f32; 3],
+    strength: f32,
+}
+
+fn perez(coefficients: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = *coefficients;
+    return (1.0 + a * f32::exp(b / cos_theta))
+        * (1.0 + c * f32::exp(d * gamma) + e * gamma.cos() * gamma.cos());
+}
+
+impl Preetham {
+    fn new(sun: Vector3D, turbidity: f32, strength: f32) -> Preetham {
+        let t = turbidity;
+        let coefficients = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        // The model only covers the sun above the horizon
+        let theta_sun = sun.y().clamp(0.0, 1.0).acos();
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        // In thousands of candela per square metre
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let cubic = |coefficients: [f32; 4]| {
+            let [a, b, c, d] = coefficients;
+            return ((a * theta_sun + b) * theta_sun + c) * theta_sun + d;
+        };
+        let zenith_x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.0])
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394])
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_y = t * t * cubic([0.00275, -0.00610, 0.00317, 0.0])
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516])
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688]);
+        let zenith_perez = coefficients.map(|coefficients| perez(&coefficients, 1.0, theta_sun));
+        return Preetham {
+            sun,
+            perez: coefficients,
+            zenith: [luminance * 1000.0 / LUMENS_PER_WATT, zenith_x, zenith_y],
+            zenith_perez,
+            strength,
+        };
+    }
+
+    // Black below the horizon, where the ground would be
+    fn radiance(&self, direction: &Vector3D) -> Color {
+        let direction = direction.normalized();
+        if direction.y() <= 0.0 {
+            return Color::new(0, 0, 0, 255);
+        }
+        // Right at the horizon the model blows up
+        let cos_theta = direction.y().max(0.01);
+        let gamma = (direction * self.sun).clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] = [0, 1, 2].map(|i| {
+            self.zenith[i] * perez(&self.perez[i], cos_theta, gamma) / self.zenith_perez[i]
+        });
+        return spectrum::chromaticity(x, y, luminance * self.strength);
+    }
+}
+
+// A clear daytime sky lit by the sun, for outdoor scenes. turbidity is how
+// hazy the air is, from about 2 for a very clear day to 10 for a hazy one.
+// The sun itself is a separate directional light, from sun_light.
+pub struct Sky {
+    model: Preetham,
+    turbidity: f32,
+    // The sky tabulated as an image, for picking directions by how bright
+    // they are
+    table: EnvironmentMap,
+}
+
+impl Sky {
+    // strength scales both the sky and the sun
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32, strength: f32) -> Sky {
+        // After sunset the sky fades out over civil twilight
+        let twilight = (1.0 + elevation / 6.0).clamp(0.0, 1.0);
+        let model = Preetham::new(
+            sun_direction(elevation, azimuth),
+            turbidity,
+            strength * twilight,
+        );
+        let mut pixels = Vec::with_capacity(TABLE_WIDTH * TABLE_HEIGHT);
+        for y in 0..TABLE_HEIGHT {
+            for x in 0..TABLE_WIDTH {
+                let u = (x as f32 + 0.5) / TABLE_WIDTH as f32;
+                let v = (y as f32 + 0.5) / TABLE_HEIGHT as f32;
+                pixels.push(model.radiance(&environment::to_direction(u, v)));
+            }
+        }
+        let image = HdrImage {
+            width: TABLE_WIDTH,
+            height: TABLE_HEIGHT,
+            pixels,
+        };
+        return Sky {
+            model,
+            turbidity,
+            table: EnvironmentMap::new(image, 1.0, 0.0),
+        };
+    }
+
+    // Sunlight after it has come through the air, which scatters away more
+    // blue the lower the sun is. Rayleigh scattering by the air and
+    // Angstrom's formula for haze give how much of each channel is left.
+    // None once the sun has set.
+    pub fn sun_light(&self) -> Option<DirectionalLight> {
+        let sun = self.model.sun;
+        if sun.y() <= 0.0 {
+            return None;
+        }
+        // Kasten and Young's relative air mass, which stays finite at the
+        // horizon
+        let zenith_angle = sun.y().acos().to_degrees();
+        let air_mass = 1.0 / (sun.y() + 0.50572 * f32::powf(96.07995 - zenith_angle, -1.6364));
+        let haze = 0.04608 * self.turbidity - 0.04586;
+        // Wavelengths in micrometres standing in for each channel
+        let [r, g, b] = [0.68f32, 0.55, 0.44].map(|wavelength| {
+            let rayleigh = 0.008735 * wavelength.powf(-4.08);
+            let aerosol = haze * wavelength.powf(-1.3);
+            f32::exp(-air_mass * (rayleigh + aerosol))
+        });
+        let color = spectrum::blackbody(SUN_TEMPERATURE) * Color { r, g, b, a: 1.0 };
+        return Some(DirectionalLight {
+            direction: -sun,
+            color,
+            irradiance: SUN_ILLUMINANCE / LUMENS_PER_WATT * self.model.strength,
+        });
+    }
+}
+
+impl Environment for Sky {
+    fn radiance(&self, direction: &Vector3D) -> Color {
+        return self.model.radiance(direction);
+    }
+
+    fn sample(&self, u1: f32, u2: f32) -> Option<EnvironmentSample> {
+        let sample = self.table.sample(u1, u2)?;
+        return Some(EnvironmentSample {
+            radiance: self.model.radiance(&sample.direction),
+            ..sample
+        });
+    }
+
+    fn pdf(&self, direction: &Vector3D) -> f32 {
+        return self.table.pdf(direction);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matrix::vector::Point3D;
+    use crate::raytracer::light::LightSource;
+    use crate::raytracer::sampling::Rng;
+
+    #[test]
+    fn dates() {
+        assert_eq!(day_of_year(2023, 1, 1), Some(1));
+        assert_eq!(day_of_year(2023, 3, 1), Some(60));
+        assert_eq!(day_of_year(2024, 3, 1), Some(61));
+        assert_eq!(day_of_year(2000, 12, 31), Some(366));
+        assert_eq!(day_of_year(1900, 2, 29), None);
+        assert_eq!(day_of_year(2023, 13, 1), None);
+    }
+
+    #[test]
+    fn sun_positions() {
+        // Around the June solstice the noon sun in London is 90 - 51.5 +
+        // 23.4 degrees up, due south
+        let day = day_of_year(2023, 6, 21).unwrap();
+        let (elevation, azimuth) = sun_position(day, 13.0, 51.5, 0.0, 1.0);
+        assert!((elevation - 61.9).abs() < 0.5, "{}", elevation);
+        assert!((azimuth - 180.0).abs() < 2.0, "{}", azimuth);
+        // It rises in the north east and is low in the west by evening
+        let (elevation, azimuth) = sun_position(day, 5.0, 51.5, 0.0, 1.0);
+        assert!(elevation > -2.0 && elevation < 5.0, "{}", elevation);
+        assert!(azimuth > 40.0 && azimuth < 60.0, "{}", azimuth);
+        let (elevation, azimuth) = sun_position(day, 20.0, 51.5, 0.0, 1.0);
+        assert!(elevation > 0.0 && elevation < 15.0, "{}", elevation);
+        assert!(azimuth > 280.0 && azimuth < 310.0, "{}", azimuth);
+        // And it's down at midnight
+        assert!(sun_position(day, 0.0, 51.5, 0.0, 1.0).0 < 0.0);
+
+        let east = sun_direction(0.0, 90.0);
+        assert!((east - Vector3D::new([1.0, 0.0, 0.0])).norm() < 1e-6);
+    }
+
+    #[test]
+    fn clear_sky() {
+        let sky = Sky::new(30.0, 180.0, 3.0, 1.0);
+        let zenith = sky.radiance(&Vector3D::new([0.0, 1.0, 0.0]));
+        // A clear sky is blue, and a few thousand candela per square metre
+        assert!(zenith.b > zenith.r, "{:?}", zenith);
+        assert!(zenith.g > 1.0 && zenith.g < 20.0, "{:?}", zenith);
+        // It glows around the sun, and the ground is left to the scene
+        let near_sun = sky.radiance(&sun_direction(35.0, 180.0));
+        let away = sky.radiance(&sun_direction(35.0, 0.0));
+        assert!(near_sun.g > away.g);
+        assert_eq!(sky.radiance(&Vector3D::new([0.0, -1.0, 0.0])).g, 0.0);
+
+        let mut rng = Rng::new(2, 0);
+        for _ in 0..100 {
+            let sample = sky.sample(rng.next_f32(), rng.next_f32()).unwrap();
+            assert!(sample.direction.y() > 0.0);
+            assert!((sample.pdf - sky.pdf(&sample.direction)).abs() < 1e-3 * sample.pdf);
+        }
+    }
+
+    #[test]
+    fn sunlight() {
+        let high = Sky::new(70.0, 180.0, 3.0, 1.0).sun_light().unwrap();
+        let low = Sky::new(3.0, 180.0, 3.0, 1.0).sun_light().unwrap();
+        let at = |light: &DirectionalLight| light.illuminate(&Point3D::zero()).unwrap();
+        // About 100000 lux at midday, in watts
+        let midday = at(&high).irradiance * at(&high).color.g;
+        assert!(midday > 120.0 && midday < 170.0, "{}", midday);
+        // The low sun is dimmer and redder
+        assert!(at(&low).color.g < at(&high).color.g);
+        assert!(at(&low).color.r / at(&low).color.b > at(&high).color.r / at(&high).color.b);
+        assert!(Sky::new(-3.0, 180.0, 3.0, 1.0).sun_light().is_none());
+    }
+}
@@ -0,0 +1,265 @@
+// Spectral rendering, where each path carries one wavelength of light
+// instead of red, green and blue. RGB colors in the scene are turned into
+// spectra by splitting them over three smooth basis curves, and the
+// radiance a path brings back is turned into a color through the CIE 1931
+// XYZ color matching functions.
+
+use std::sync::OnceLock;
+
+use crate::image::Color;
+use crate::matrix::matrix::Matrix;
+use crate::matrix::vector::Vector3D;
+
+// The visible range, in nanometres
+pub const MIN_WAVELENGTH: f32 = 380.0;
+pub const MAX_WAVELENGTH: f32 = 780.0;
+
+// A standard wavelength to use for dispersive materials outside of
+// spectral rendering, the yellow helium d line
+pub const D_LINE: f32 = 587.6;
+
+// How the index of refraction of a material changes with wavelength, which
+// is what splits white light into a rainbow. Both models take wavelengths
+// in micrometres, as their coefficients are usually given.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dispersion {
+    // n = a + b / wavelength^2
+    Cauchy { a: f32, b: f32 },
+    // n^2 = 1 + sum of b * wavelength^2 / (wavelength^2 - c)
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    pub fn ior(&self, wavelength: f32) -> f32 {
+        let micrometres = wavelength / 1000.0;
+        let squared = micrometres * micrometres;
+        return match self {
+            Dispersion::Cauchy { a, b } => a + b / squared,
+            Dispersion::Sellmeier { b, c } => {
+                let mut n_squared = 1.0;
+                for i in 0..3 {
+                    n_squared += b[i] * squared / (squared - c[i]);
+                }
+                n_squared.sqrt()
+            }
+        };
+    }
+}
+
+// A piecewise gaussian, wider on one side than the other
+fn lobe(wavelength: f32, center: f32, below: f32, above: f32) -> f32 {
+    let width = if wavelength < center { below } else { above };
+    let t = (wavelength - center) / width;
+    return f32::exp(-0.5 * t * t);
+}
+
+// The CIE 1931 standard observer, from the multi-lobe fit in Wyman, Sloan
+// and Shirley's "Simple Analytic Approximations to the CIE XYZ Color
+// Matching Functions"
+pub fn color_matching(wavelength: f32) -> Vector3D {
+    let x = 1.056 * lobe(wavelength, 599.8, 37.9, 31.0)
+        + 0.362 * lobe(wavelength, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(wavelength, 501.1, 20.4, 26.2);
+    let y =
+        0.821 * lobe(wavelength, 568.8, 46.9, 40.5) + 0.286 * lobe(wavelength, 530.9, 16.3, 31.1);
+    let z =
+        1.217 * lobe(wavelength, 437.0, 11.8, 36.0) + 0.681 * lobe(wavelength, 459.0, 26.0, 13.8);
+    return Vector3D::new([x, y, z]);
+}
+
+fn smoothstep(start: f32, end: f32, x: f32) -> f32 {
+    let t = ((x - start) / (end - start)).clamp(0.0, 1.0);
+    return t * t * (3.0 - 2.0 * t);
+}
+
+// How much of each of red, green and blue goes into a wavelength. They
+// always add up to 1, so white is the same at every wavelength.
+fn basis(wavelength: f32) -> Vector3D {
+    let blue = 1.0 - smoothstep(470.0, 510.0, wavelength);
+    let red = smoothstep(570.0, 610.0, wavelength);
+    return Vector3D::new([red, 1.0 - red - blue, blue]);
+}
+
+// The value at one wavelength of the spectrum standing in for color
+pub fn sample(color: Color, wavelength: f32) -> f32 {
+    let weights = basis(wavelength);
+    return color.r * weights[0] + color.g * weights[1] + color.b * weights[2];
+}
+
+// Replaces color with its spectrum's value at wavelength, in every channel,
+// when rendering spectrally
+pub fn tint(color: Color, wavelength: Option<f32>) -> Color {
+    return match wavelength {
+        Some(wavelength) => {
+            let value = sample(color, wavelength);
+            Color {
+                r: value,
+                g: value,
+                b: value,
+                a: color.a,
+            }
+        }
+        None => color,
+    };
+}
+
+// The XYZ of each basis curve, as columns, added up a nanometre at a time
+fn rgb_to_xyz() -> &'static Matrix<f32, 3, 3> {
+    static MATRIX: OnceLock<Matrix<f32, 3, 3>> = OnceLock::new();
+    return MATRIX.get_or_init(|| {
+        let mut rgb_to_xyz = Matrix::<f32, 3, 3>::zero();
+        let mut wavelength = MIN_WAVELENGTH;
+        while wavelength <= MAX_WAVELENGTH {
+            let xyz = color_matching(wavelength);
+            let weights = basis(wavelength);
+            for row in 0..3 {
+                for col in 0..3 {
+                    rgb_to_xyz[(row, col)] += xyz[row] * weights[col];
+                }
+            }
+            wavelength += 1.0;
+        }
+        return rgb_to_xyz;
+    });
+}
+
+// Converts XYZ into RGB. The primaries are the basis curves, so an RGB
+// color turned into a spectrum and back comes out the same.
+fn xyz_to_rgb() -> &'static Matrix<f32, 3, 3> {
+    static MATRIX: OnceLock<Matrix<f32, 3, 3>> = OnceLock::new();
+    return MATRIX.get_or_init(|| rgb_to_xyz().inverse().unwrap());
+}
+
+// The color of a black body glowing at a temperature in kelvin, like the
+// filament of a bulb, from Planck's law. It is scaled to be as bright as
+// white, so it only changes the color of a light and not its brightness.
+pub fn blackbody(kelvin: f32) -> Color {
+    // Planck's second radiation constant, in metre kelvins
+    const C2: f32 = 1.4388e-2;
+    let mut xyz = Vector3D::zero();
+    let mut wavelength = MIN_WAVELENGTH;
+    while wavelength <= MAX_WAVELENGTH {
+        let metres = wavelength * 1e-9;
+        // Scaled by a constant so it stays in range for f32
+        let radiance = 1.0 / ((metres * 1e6).powi(5) * (f32::exp(C2 / (metres * kelvin)) - 1.0));
+        xyz = xyz + color_matching(wavelength) * radiance;
+        wavelength += 1.0;
+    }
+    let white = *rgb_to_xyz() * Vector3D::one();
+    let rgb = *xyz_to_rgb() * (xyz * (white.y() / xyz.y()));
+    return Color {
+        r: rgb[0].max(0.0),
+        g: rgb[1].max(0.0),
+        b: rgb[2].max(0.0),
+        a: 1.0,
+    };
+}
+
+// The color with CIE chromaticity x y and a luminance where white's is 1
+pub fn chromaticity(x: f32, y: f32, luminance: f32) -> Color {
+    if y <= 0.0 {
+        return Color::new(0, 0, 0, 255);
+    }
+    let white = *rgb_to_xyz() * Vector3D::one();
+    let xyz = Vector3D::new([x / y, 1.0, (1.0 - x - y) / y]) * (luminance * white.y());
+    let rgb = *xyz_to_rgb() * xyz;
+    return Color {
+        r: rgb[0].max(0.0),
+        g: rgb[1].max(0.0),
+        b: rgb[2].max(0.0),
+        a: 1.0,
+    };
+}
+
+// The color of one path's radiance at a wavelength picked uniformly from
+// the visible range. Averaging many of them gives the pixel's color.
+pub fn to_color(wavelength: f32, radiance: f32) -> Color {
+    let pdf = 1.0 / (MAX_WAVELENGTH - MIN_WAVELENGTH);
+    let xyz = color_matching(wavelength) * (radiance / pdf);
+    let rgb = *xyz_to_rgb() * xyz;
+    return Color {
+        r: rgb[0],
+        g: rgb[1],
+        b: rgb[2],
+        a: 1.0,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn color_matching_peaks() {
+        let peak = color_matching(555.0);
+        assert!((peak.y() - 1.0).abs() < 0.02);
+        assert!(color_matching(600.0).x() > color_matching(450.0).x());
+        assert!(color_matching(445.0).z() > 1.5);
+        assert!(color_matching(780.0).norm() < 0.01);
+    }
+
+    #[test]
+    fn colors_survive_the_round_trip() {
+        for color in [
+            Color::new(255, 255, 255, 255),
+            Color::new(255, 0, 0, 255),
+            Color::new(0, 128, 255, 255),
+        ] {
+            // Integrate over the visible range, one sample per nanometre
+            let mut total = Color::new(0, 0, 0, 0);
+            let steps = (MAX_WAVELENGTH - MIN_WAVELENGTH) as usize;
+            for step in 0..steps {
+                let wavelength = MIN_WAVELENGTH + step as f32 + 0.5;
+                total = total + to_color(wavelength, sample(color, wavelength));
+            }
+            let total = total * (1.0 / steps as f32);
+            assert!((total.r - color.r).abs() < 0.01);
+            assert!((total.g - color.g).abs() < 0.01);
+            assert!((total.b - color.b).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn blackbody_colors() {
+        let candle = blackbody(1900.0);
+        assert!(candle.r > candle.g && candle.g > candle.b);
+        let sky = blackbody(12000.0);
+        assert!(sky.b > sky.g && sky.g > sky.r);
+        // Light with an equal amount of every wavelength is white, and a
+        // black body around 5500K is close to it
+        let noon = blackbody(5500.0);
+        for channel in [noon.r, noon.g, noon.b] {
+            assert!((channel - 1.0).abs() < 0.1, "{}", channel);
+        }
+    }
+
+    #[test]
+    fn chromaticities() {
+        // Equal energy white is the color with every wavelength
+        let white = chromaticity(1.0 / 3.0, 1.0 / 3.0, 2.0);
+        for channel in [white.r, white.g, white.b] {
+            assert!((channel - 2.0).abs() < 0.02, "{}", channel);
+        }
+        let blue = chromaticity(0.2, 0.2, 1.0);
+        assert!(blue.b > blue.g && blue.g > blue.r);
+    }
+
+    #[test]
+    fn dispersion_models() {
+        // Schott N-BK7, from both models
+        let cauchy = Dispersion::Cauchy {
+            a: 1.5046,
+            b: 0.0042,
+        };
+        let sellmeier = Dispersion::Sellmeier {
+            b: [1.039_612, 0.231_792_35, 1.010_469_5],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        };
+        assert!((sellmeier.ior(D_LINE) - 1.5168).abs() < 1e-4);
+        assert!((cauchy.ior(D_LINE) - 1.5168).abs() < 1e-3);
+        for model in [cauchy, sellmeier] {
+            // Blue bends more than red
+            assert!(model.ior(450.0) > model.ior(650.0));
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use crate::image::{Color, Image};
+use crate::matrix::vector::{Point3D, Vector2D};
+use crate::raytracer::differential::Footprint;
+use crate::raytracer::geometry::Rayhit;
+
+pub mod noise;
+pub mod procedural;
+
+// Where on a surface a texture is looked up: by its uv coordinates, or by
+// the position in the object's own space for solid textures. The
+// footprint says how much of the texture to average over.
+pub struct TexturePoint {
+    pub uv: Vector2D,
+    pub position: Point3D,
+    pub footprint: Footprint,
+}
+
+impl TexturePoint {
+    // A point sampled without any filtering
+    #[cfg(test)]
+    pub fn new(uv: Vector2D, position: Point3D) -> TexturePoint {
+        return TexturePoint {
+            uv,
+            position,
+            footprint: Footprint::none(),
+        };
+    }
+
+    // The footprint's steps across the surface are measured in world space.
+    // Solid textures want them in the object's own space, like the position,
+    // so they're rebuilt from the uv steps along the untransformed tangents.
+    // Surfaces without usable tangents keep the world space steps.
+    pub fn of(hit: &Rayhit) -> TexturePoint {
+        let mut footprint = hit.footprint;
+        let local = |duv: &Vector2D| hit.local_tangent * duv[0] + hit.local_bitangent * duv[1];
+        let (dpdx, dpdy) = (local(&footprint.duvdx), local(&footprint.duvdy));
+        if dpdx.norm() > 0.0 && dpdy.norm() > 0.0 {
+            footprint.dpdx = dpdx;
+            footprint.dpdy = dpdy;
+        }
+        return TexturePoint {
+            uv: hit.uv,
+            position: hit.local,
+            footprint,
+        };
+    }
+}
+
+// Something that varies over a surface. Textures are shared between the
+// render threads.
+pub trait Texture: Send + Sync {
+    fn color(&self, point: &TexturePoint) -> Color;
+
+    // For textures standing in for a single number, like roughness
+    fn value(&self, point: &TexturePoint) -> f32 {
+        let color = self.color(point);
+        return (color.r + color.g + color.b) / 3.0;
+    }
+}
+
+// One level of a mip pyramid, each half the size of the one before
+struct MipLevel {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+
+// Wraps a pixel index back into the image
+fn wrap(index: i64, size: usize) -> usize {
+    return index.rem_euclid(size as i64) as usize;
+}
+
+impl MipLevel {
+    fn pixel(&self, x: i64, y: i64) -> Color {
+        return self.pixels[wrap(x, self.width) + wrap(y, self.height) * self.width];
+    }
+
+    // Averages each 2x2 block into one pixel. Odd rows and columns wrap
+    // around, as the texture does.
+    fn halved(&self) -> MipLevel {
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let total = self.pixel(2 * x, 2 * y)
+                    + self.pixel(2 * x + 1, 2 * y)
+                    + self.pixel(2 * x, 2 * y + 1)
+                    + self.pixel(2 * x + 1, 2 * y + 1);
+                pixels.push(total * 0.25);
+            }
+        }
+        return MipLevel {
+            width,
+            height,
+            pixels,
+        };
+    }
+
+    // Blends between the four pixels nearest uv
+    fn bilinear(&self, uv: &Vector2D) -> Color {
+        // Pixel centres are half a pixel in from their corners
+        let x = uv[0] * self.width as f32 - 0.5;
+        let y = (1.0 - uv[1]) * self.height as f32 - 0.5;
+        let (left, top) = (x.floor(), y.floor());
+        let (across, down) = (x - left, y - top);
+        let (left, top) = (left as i64, top as i64);
+        let upper = self.pixel(left, top) * (1.0 - across) + self.pixel(left + 1, top) * across;
+        let lower =
+            self.pixel(left, top + 1) * (1.0 - across) + self.pixel(left + 1, top + 1) * across;
+        return upper * (1.0 - down) + lower * down;
+    }
+}
+
+// An image stretched over the unit square of uv coordinates and repeated
+// outside it. v goes up the image, as in OBJ files. Colors are blended
+// between the four nearest pixels, and between the two mip levels closest
+// to the size of the footprint, so far away the texture blurs instead of
+// shimmering.
+pub struct ImageTexture {
+    levels: Vec<MipLevel>,
+}
+
+impl ImageTexture {
+    pub fn new(image: Image) -> ImageTexture {
+        let (width, height) = (image.get_width(), image.get_height());
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(image.get_pixelu32(x, y));
+            }
+        }
+        let mut levels = vec![MipLevel {
+            width: width as usize,
+            height: height as usize,
+            pixels,
+        }];
+        while let Some(last) = levels
+            .last()
+            .filter(|last| last.width > 1 || last.height > 1)
+        {
+            let halved = last.halved();
+            levels.push(halved);
+        }
+        return ImageTexture { levels };
+    }
+
+    // Which mip level, fractionally, has pixels about as big as the
+    // footprint. 0 is the full image.
+    fn level(&self, footprint: &Footprint) -> f32 {
+        let base = &self.levels[0];
+        let texels = |duv: &Vector2D| {
+            return f32::hypot(duv[0] * base.width as f32, duv[1] * base.height as f32);
+        };
+        let width = texels(&footprint.duvdx).max(texels(&footprint.duvdy));
+        if width.is_nan() || width <= 1.0 {
+            return 0.0;
+        }
+        return width.log2().min((self.levels.len() - 1) as f32);
+    }
+}
+
+impl Texture for ImageTexture {
+    fn color(&self, point: &TexturePoint) -> Color {
+        let level = self.level(&point.footprint);
+        let finer = level.floor() as usize;
+        let color = self.levels[finer].bilinear(&point.uv);
+        let blend = level - finer as f32;
+        if blend == 0.0 {
+            return color;
+        }
+        return color * (1.0 - blend) + self.levels[finer + 1].bilinear(&point.uv) * blend;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matrix::matrix::Matrix;
+    use crate::matrix::vector::Vector3D;
+    use crate::raytracer::differential::RayDifferential;
+    use crate::raytracer::geometry::material::plain_material;
+    use crate::raytracer::geometry::transformed::Transformed;
+    use crate::raytracer::geometry::{Geometry, Ray, Triangle};
+    use std::sync::Arc;
+
+    fn at(u: f32, v: f32) -> TexturePoint {
+        return TexturePoint::new(Vector2D::new([u, v]), Point3D::zero());
+    }
+
+    // Black and white pixels side by side, over a red and a blue one
+    fn texture() -> ImageTexture {
+        let pixels = vec![
+            0, 0, 0, 255, 255, 255, 255, 255, //
+            255, 0, 0, 255, 0, 0, 255, 255,
+        ];
+        return ImageTexture::new(Image::from_rgba(2, 2, pixels));
+    }
+
+    #[test]
+    fn pixel_centres() {
+        let texture = texture();
+        // The top left pixel is at the top of the uv square, where v is 1
+        let black = texture.color(&at(0.25, 0.75));
+        assert_eq!((black.r, black.g, black.b), (0.0, 0.0, 0.0));
+        let blue = texture.color(&at(0.75, 0.25));
+        assert_eq!((blue.r, blue.b), (0.0, 1.0));
+        // and the pattern repeats
+        let white = texture.color(&at(-0.25, 1.75));
+        assert_eq!(white.g, 1.0);
+    }
+
+    #[test]
+    fn blending() {
+        let texture = texture();
+        // Halfway between black and white
+        let gray = texture.color(&at(0.5, 0.75));
+        assert!((gray.g - 0.5).abs() < 1e-6);
+        assert!((texture.value(&at(0.5, 0.75)) - 0.5).abs() < 1e-6);
+        // Wrapping blends the right edge with the left
+        let edge = texture.color(&at(1.0, 0.25));
+        assert!((edge.r - 0.5).abs() < 1e-6 && (edge.b - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn mipmaps() {
+        let texture = texture();
+        assert_eq!(texture.levels.len(), 2);
+        // The smallest level is the average of all four pixels
+        let average = texture.levels[1].pixels[0];
+        assert!((average.r - 0.5).abs() < 1e-6 && (average.b - 0.5).abs() < 1e-6);
+        assert!((average.g - 0.25).abs() < 1e-6);
+
+        // A footprint a pixel wide or less leaves the image sharp
+        let mut point = at(0.25, 0.75);
+        point.footprint.duvdx = Vector2D::new([0.5, 0.0]);
+        assert_eq!(texture.level(&point.footprint), 0.0);
+        assert_eq!(texture.color(&point).g, 0.0);
+        // and one covering the whole texture averages it
+        point.footprint.duvdy = Vector2D::new([0.0, 1.0]);
+        assert_eq!(texture.level(&point.footprint), 1.0);
+        assert!((texture.color(&point).g - 0.25).abs() < 1e-6);
+        // In between the levels are blended
+        point.footprint.duvdy = Vector2D::new([0.0, f32::sqrt(2.0) / 2.0]);
+        assert!((texture.level(&point.footprint) - 0.5).abs() < 1e-5);
+        assert!((texture.color(&point).g - 0.125).abs() < 1e-5);
+    }
+
+    #[test]
+    fn object_space_footprint() {
+        // A floor scaled up ten times covers a tenth as much of its own
+        // space per pixel
+        let floor: Arc<dyn Geometry> = Arc::new(Triangle {
+            a: Point3D::new([-1.0, 0.0, -1.0]),
+            b: Point3D::new([1.0, 0.0, -1.0]),
+            c: Point3D::new([-1.0, 0.0, 1.0]),
+            material: plain_material(),
+        });
+        let scale = Matrix::scaling(&Vector3D::new([10.0, 10.0, 10.0]));
+        let floor = Arc::new(Transformed::new(floor, scale).unwrap());
+        let origin = Point3D::new([-1.0, 2.0, -3.0]);
+        let ray = Ray {
+            origin,
+            direction: Vector3D::new([0.0, -1.0, 0.0]),
+        };
+        let differential = RayDifferential {
+            x_origin: origin,
+            x_direction: Vector3D::new([0.01, -1.0, 0.0]).normalized(),
+            y_origin: origin,
+            y_direction: Vector3D::new([0.0, -1.0, 0.01]).normalized(),
+        };
+        let mut hit = floor.intersect(&ray, f32::INFINITY).unwrap();
+        hit.footprint = differential.footprint(&hit);
+        let footprint = TexturePoint::of(&hit).footprint;
+        assert!((footprint.dpdx - Vector3D::new([0.002, 0.0, 0.0])).norm() < 1e-6);
+        assert!((footprint.dpdy - Vector3D::new([0.0, 0.0, 0.002])).norm() < 1e-6);
+        assert_eq!(footprint.duvdx, hit.footprint.duvdx);
+    }
+}
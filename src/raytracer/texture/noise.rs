@@ -0,0 +1,134 @@
+use crate::matrix::vector::Point3D;
+
+// Mixes the corner of a lattice cell into a pseudo-random number, so every
+// corner gets its own gradient without a permutation table
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+    let mut hash = (x as u32).wrapping_mul(0x8da6b343)
+        ^ (y as u32).wrapping_mul(0xd8163841)
+        ^ (z as u32).wrapping_mul(0xcb1ab31f);
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x7feb352d);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x846ca68b);
+    hash ^= hash >> 16;
+    return hash;
+}
+
+// The dot product of offset with one of the twelve gradients pointing from
+// the centre of a cube to its edges, picked by hash
+fn gradient(hash: u32, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    let u = if h & 1 == 0 { u } else { -u };
+    let v = if h & 2 == 0 { v } else { -v };
+    return u + v;
+}
+
+// Eases 0 to 1 with zero first and second derivatives at both ends, so the
+// noise has no creases along the cell edges
+fn fade(t: f32) -> f32 {
+    return t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    return a + t * (b - a);
+}
+
+// Perlin's improved gradient noise: smooth, roughly between -1 and 1, zero
+// at every whole-numbered point and varying about once per unit
+pub fn perlin(point: &Point3D) -> f32 {
+    let (x, y, z) = (point.x(), point.y(), point.z());
+    let (cell_x, cell_y, cell_z) = (x.floor(), y.floor(), z.floor());
+    let (x, y, z) = (x - cell_x, y - cell_y, z - cell_z);
+    let (i, j, k) = (cell_x as i32, cell_y as i32, cell_z as i32);
+    let corner = |di: i32, dj: i32, dk: i32| {
+        let hash = hash(i + di, j + dj, k + dk);
+        return gradient(hash, x - di as f32, y - dj as f32, z - dk as f32);
+    };
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    let near = lerp(
+        v,
+        lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+        lerp(u, corner(0, 1, 0), corner(1, 1, 0)),
+    );
+    let far = lerp(
+        v,
+        lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+        lerp(u, corner(0, 1, 1), corner(1, 1, 1)),
+    );
+    return lerp(w, near, far);
+}
+
+// Adds octaves of noise, each twice as fine and half as strong as the last,
+// through octave_noise. Scaled back into the range of a single octave.
+fn octaves(point: &Point3D, octaves: u32, octave_noise: impl Fn(f32) -> f32) -> f32 {
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    let mut amplitudes = 0.0;
+    for _ in 0..octaves.max(1) {
+        total += amplitude * octave_noise(perlin(&(*point * frequency)));
+        amplitudes += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    return total / amplitudes;
+}
+
+// Fractal Brownian motion: noise with detail at every scale, between -1
+// and 1
+pub fn fbm(point: &Point3D, octave_count: u32) -> f32 {
+    return octaves(point, octave_count, |noise| noise);
+}
+
+// Like fbm, but folded at zero into sharp creases, between 0 and 1
+pub fn turbulence(point: &Point3D, octave_count: u32) -> f32 {
+    return octaves(point, octave_count, f32::abs);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raytracer::sampling::Rng;
+
+    #[test]
+    fn lattice_and_range() {
+        assert_eq!(perlin(&Point3D::new([3.0, -2.0, 7.0])), 0.0);
+        let mut rng = Rng::new(9, 0);
+        let (mut lowest, mut highest) = (0.0f32, 0.0f32);
+        for _ in 0..10000 {
+            let point = Point3D::new([
+                rng.next_f32() * 20.0 - 10.0,
+                rng.next_f32() * 20.0 - 10.0,
+                rng.next_f32() * 20.0 - 10.0,
+            ]);
+            let noise = perlin(&point);
+            lowest = lowest.min(noise);
+            highest = highest.max(noise);
+            assert!(fbm(&point, 5).abs() <= 1.1);
+            assert!((0.0..=1.0).contains(&turbulence(&point, 5)));
+        }
+        // The noise uses most of its range, both ways
+        assert!(lowest < -0.6 && highest > 0.6);
+        assert!(lowest >= -1.1 && highest <= 1.1);
+    }
+
+    #[test]
+    fn smooth() {
+        let point = Point3D::new([1.3, 4.7, -0.2]);
+        let step = Point3D::new([1e-3, 1e-3, 1e-3]);
+        assert!((perlin(&point) - perlin(&(point + step))).abs() < 1e-2);
+        // Even across a cell edge
+        let edge = Point3D::new([2.0, 0.5, 0.5]);
+        assert!((perlin(&(edge - step)) - perlin(&(edge + step))).abs() < 1e-2);
+        // A single octave of fbm is plain noise
+        assert_eq!(fbm(&point, 1), perlin(&point));
+    }
+}
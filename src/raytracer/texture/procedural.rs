@@ -0,0 +1,260 @@
+use std::f32::consts::PI;
+
+use crate::image::Color;
+use crate::matrix::vector::Point3D;
+use crate::raytracer::differential::Footprint;
+use crate::raytracer::texture::noise;
+use crate::raytracer::texture::{Texture, TexturePoint};
+
+// How a procedural texture blends between its two colors through space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    // Cubes of alternating color
+    Checker,
+    // A ramp from the first color at start to the second at end
+    Gradient { start: Point3D, end: Point3D },
+    Perlin,
+    Fbm { octaves: u32 },
+    Turbulence { octaves: u32 },
+    // Veins running across x, wobbled by turbulence
+    Marble { octaves: u32 },
+    // Rings around the y axis, growing from the first color to the second
+    Wood,
+}
+
+impl Pattern {
+    // The pattern called name in scene files, with its usual settings
+    pub fn from_name(name: &str) -> Option<Pattern> {
+        return match name {
+            "checker" => Some(Pattern::Checker),
+            "gradient" => Some(Pattern::Gradient {
+                start: Point3D::zero(),
+                end: Point3D::new([0.0, 1.0, 0.0]),
+            }),
+            "perlin" => Some(Pattern::Perlin),
+            "fbm" => Some(Pattern::Fbm { octaves: 6 }),
+            "turbulence" => Some(Pattern::Turbulence { octaves: 6 }),
+            "marble" => Some(Pattern::Marble { octaves: 6 }),
+            "wood" => Some(Pattern::Wood),
+            _ => None,
+        };
+    }
+}
+
+// Surfaces often lie exactly on a checker boundary, like a floor at y = -2.
+// Nudging the cells keeps them from flickering.
+const CHECKER_NUDGE: f32 = 1e-3;
+
+// How much of the way from 0 to x lies in odd cells of a checker
+fn odd_cells_below(x: f32) -> f32 {
+    let pairs = (x / 2.0).floor();
+    return pairs + (x - 2.0 * pairs - 1.0).max(0.0);
+}
+
+// How much of a checker cell row of the given width centred on x is odd.
+// Anything narrower than the nudge, or of no known width, is just the cell
+// x is in.
+fn odd_fraction(x: f32, width: f32) -> f32 {
+    let x = x + CHECKER_NUDGE;
+    if !width.is_finite() || width < CHECKER_NUDGE {
+        return (x.floor() as i64).rem_euclid(2) as f32;
+    }
+    let covered = odd_cells_below(x + width / 2.0) - odd_cells_below(x - width / 2.0);
+    return (covered / width).clamp(0.0, 1.0);
+}
+
+// A solid texture computed from the position in the object's own space,
+// so it needs no uv coordinates. scale is the size of one checker, noise
+// feature, marble vein or wood ring. Checkers are averaged over the
+// footprint and noise loses the octaves finer than it.
+pub struct Procedural {
+    pub pattern: Pattern,
+    pub color1: Color,
+    pub color2: Color,
+    pub scale: f32,
+}
+
+impl Procedural {
+    pub fn new(pattern: Pattern, color1: Color, color2: Color, scale: f32) -> Procedural {
+        return Procedural {
+            pattern,
+            color1,
+            color2,
+            scale,
+        };
+    }
+
+    // How many octaves of noise are coarser than the footprint
+    fn octaves(&self, octaves: u32, footprint: &Footprint) -> u32 {
+        let width = footprint.width() / self.scale;
+        if width.is_nan() || width <= 0.0 {
+            return octaves;
+        }
+        let coarser = (1.0 - width.log2()).floor().max(1.0) as u32;
+        return octaves.min(coarser);
+    }
+
+    // How far from the first color to the second the pattern is on
+    // average over the footprint at position, from 0 to 1
+    fn mix(&self, position: &Point3D, footprint: &Footprint) -> f32 {
+        let point = *position / self.scale;
+        let mix = match self.pattern {
+            Pattern::Checker => {
+                // Each axis is box filtered on its own. A cell is odd when an
+                // odd number of its axes are, so the averages of +1 for even
+                // and -1 for odd multiply.
+                let (dpdx, dpdy) = (footprint.dpdx, footprint.dpdy);
+                let mut product = 1.0;
+                for axis in 0..3 {
+                    let width = dpdx[axis].abs().max(dpdy[axis].abs()) / self.scale;
+                    product *= 1.0 - 2.0 * odd_fraction(point[axis], width);
+                }
+                0.5 - 0.5 * product
+            }
+            Pattern::Gradient { start, end } => {
+                let axis = end - start;
+                let length_squared = axis * axis;
+                if length_squared > 0.0 {
+                    (*position - start) * axis / length_squared
+                } else {
+                    0.0
+                }
+            }
+            Pattern::Perlin => 0.5 + 0.5 * noise::perlin(&point),
+            Pattern::Fbm { octaves } => {
+                0.5 + 0.5 * noise::fbm(&point, self.octaves(octaves, footprint))
+            }
+            Pattern::Turbulence { octaves } => {
+                noise::turbulence(&point, self.octaves(octaves, footprint))
+            }
+            Pattern::Marble { octaves } => {
+                let octaves = self.octaves(octaves, footprint);
+                let phase = PI * point.x() + 5.0 * noise::turbulence(&point, octaves);
+                0.5 + 0.5 * phase.sin()
+            }
+            Pattern::Wood => {
+                let wobble = 0.3 * noise::perlin(&(point * 0.5));
+                let radius = f32::hypot(point.x(), point.z()) + wobble;
+                radius - radius.floor()
+            }
+        };
+        return mix.clamp(0.0, 1.0);
+    }
+}
+
+impl Texture for Procedural {
+    fn color(&self, point: &TexturePoint) -> Color {
+        let mix = self.mix(&point.position, &point.footprint);
+        return self.color1 * (1.0 - mix) + self.color2 * mix;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matrix::vector::{Vector2D, Vector3D};
+
+    fn texture(pattern: Pattern, scale: f32) -> Procedural {
+        return Procedural::new(
+            pattern,
+            Color::new(0, 0, 0, 255),
+            Color::new(255, 255, 255, 255),
+            scale,
+        );
+    }
+
+    fn at(x: f32, y: f32, z: f32) -> TexturePoint {
+        return TexturePoint::new(Vector2D::zero(), Point3D::new([x, y, z]));
+    }
+
+    #[test]
+    fn checker() {
+        let checker = texture(Pattern::Checker, 2.0);
+        assert_eq!(checker.value(&at(0.5, 0.5, 0.5)), 0.0);
+        assert_eq!(checker.value(&at(2.5, 0.5, 0.5)), 1.0);
+        assert_eq!(checker.value(&at(-0.5, 0.5, 0.5)), 1.0);
+        assert_eq!(checker.value(&at(2.5, 2.5, 0.5)), 0.0);
+        // A floor right on a boundary stays one color on either side of it
+        let floor = checker.value(&at(0.5, -2.0, 0.5));
+        assert_eq!(checker.value(&at(0.5, -2.0 + 1e-5, 0.5)), floor);
+        assert_eq!(checker.value(&at(0.5, -2.0 - 1e-5, 0.5)), floor);
+    }
+
+    #[test]
+    fn filtered_checker() {
+        let checker = texture(Pattern::Checker, 2.0);
+        // A footprint inside one cell doesn't change it
+        let mut point = at(0.5, -2.0, 0.5);
+        point.footprint.dpdx = Vector3D::new([0.2, 0.0, 0.0]);
+        point.footprint.dpdy = Vector3D::new([0.0, 0.0, 0.2]);
+        assert_eq!(checker.value(&point), checker.value(&at(0.5, -2.0, 0.5)));
+        // Centred on the edge between two cells it's half of each
+        point.position = Point3D::new([2.0 - 2.0 * CHECKER_NUDGE, -2.0, 0.5]);
+        assert!((checker.value(&point) - 0.5).abs() < 1e-5);
+        // and far away, where a pixel covers many cells, the floor turns
+        // evenly gray instead of aliasing
+        for i in 0..20 {
+            let mut point = at(i as f32 * 1.37, -2.0, 0.5 + i as f32 * 0.71);
+            point.footprint.dpdx = Vector3D::new([23.0, 0.0, 5.0]);
+            point.footprint.dpdy = Vector3D::new([-3.0, 0.0, 41.0]);
+            assert!((checker.value(&point) - 0.5).abs() < 0.05);
+        }
+        // A footprint that can't be measured leaves the cell sharp
+        let mut point = at(0.5, -2.0, 0.5);
+        point.footprint.dpdx = Vector3D::new([f32::NAN, 0.0, 0.0]);
+        assert_eq!(checker.value(&point), checker.value(&at(0.5, -2.0, 0.5)));
+    }
+
+    #[test]
+    fn filtered_noise() {
+        let fbm = texture(Pattern::Fbm { octaves: 6 }, 1.0);
+        let mut footprint = Footprint::none();
+        assert_eq!(fbm.octaves(6, &footprint), 6);
+        // Octaves finer than the footprint are dropped, but never the first
+        footprint.dpdx = Vector3D::new([0.1, 0.0, 0.0]);
+        assert_eq!(fbm.octaves(6, &footprint), 4);
+        footprint.dpdx = Vector3D::new([5.0, 0.0, 0.0]);
+        assert_eq!(fbm.octaves(6, &footprint), 1);
+    }
+
+    #[test]
+    fn gradient() {
+        let pattern = Pattern::Gradient {
+            start: Point3D::new([0.0, -2.0, 0.0]),
+            end: Point3D::new([0.0, 2.0, 0.0]),
+        };
+        let gradient = texture(pattern, 1.0);
+        assert_eq!(gradient.value(&at(5.0, -3.0, 1.0)), 0.0);
+        assert_eq!(gradient.value(&at(-1.0, 0.0, 7.0)), 0.5);
+        assert_eq!(gradient.value(&at(0.0, 4.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn noise_patterns() {
+        let patterns = [
+            Pattern::Perlin,
+            Pattern::Fbm { octaves: 4 },
+            Pattern::Turbulence { octaves: 4 },
+            Pattern::Marble { octaves: 4 },
+            Pattern::Wood,
+        ];
+        for pattern in patterns {
+            let small = texture(pattern, 0.5);
+            let large = texture(pattern, 2.0);
+            let mut values = Vec::new();
+            for i in 0..50 {
+                let x = i as f32 * 0.37;
+                let value = small.value(&at(x, 0.25 * x, 1.25));
+                assert!((0.0..=1.0).contains(&value), "{:?}", pattern);
+                values.push(value);
+                // Scaling the texture scales the pattern with it
+                let scaled = large.value(&at(4.0 * x, x, 5.0));
+                assert_eq!(value, scaled, "{:?}", pattern);
+            }
+            // and the pattern isn't flat
+            let lowest = values.iter().copied().fold(1.0, f32::min);
+            let highest = values.iter().copied().fold(0.0, f32::max);
+            assert!(highest - lowest > 0.2, "{:?}", pattern);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+// The image is split into square tiles so that worker threads can each render
+// a block of pixels on their own. Tiles are handed out along a Hilbert curve,
+// which keeps neighbouring tiles close together in time and looks pretty while
+// the image fills in.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Converts a distance along a Hilbert curve covering a `side` by `side` grid
+// into (x, y) grid coordinates. `side` must be a power of two.
+pub fn hilbert_d2xy(side: u32, d: u32) -> (u32, u32) {
+    let mut x = 0;
+    let mut y = 0;
+    let mut t = d;
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        // Rotate the quadrant so the curve connects up with its neighbours
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    return (x, y);
+}
+
+// Splits a width by height image into tiles of at most tile_size pixels
+// square, ordered along a Hilbert curve.
+pub fn hilbert_tiles(width: u32, height: u32, tile_size: u32) -> Vec<Tile> {
+    let columns = width.div_ceil(tile_size);
+    let rows = height.div_ceil(tile_size);
+    let side = u32::max(columns, rows).max(1).next_power_of_two();
+
+    let mut tiles = Vec::with_capacity((columns * rows) as usize);
+    for d in 0..side * side {
+        let (column, row) = hilbert_d2xy(side, d);
+        // The curve covers a square power of two grid, so skip the cells
+        // that fall outside of the image.
+        if column >= columns || row >= rows {
+            continue;
+        }
+        let x = column * tile_size;
+        let y = row * tile_size;
+        tiles.push(Tile {
+            x,
+            y,
+            width: u32::min(tile_size, width - x),
+            height: u32::min(tile_size, height - y),
+        });
+    }
+    return tiles;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hilbert_steps_are_adjacent() {
+        let side = 16;
+        let mut last = hilbert_d2xy(side, 0);
+        assert_eq!(last, (0, 0));
+        for d in 1..side * side {
+            let next = hilbert_d2xy(side, d);
+            let distance = last.0.abs_diff(next.0) + last.1.abs_diff(next.1);
+            assert_eq!(distance, 1);
+            last = next;
+        }
+    }
+
+    #[test]
+    fn tiles_cover_image() {
+        let (width, height) = (100, 37);
+        let tiles = hilbert_tiles(width, height, 16);
+        assert_eq!(tiles.len(), 7 * 3);
+
+        let mut covered = vec![0; (width * height) as usize];
+        for tile in &tiles {
+            for y in tile.y..tile.y + tile.height {
+                for x in tile.x..tile.x + tile.width {
+                    covered[(x + y * width) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+    }
+}